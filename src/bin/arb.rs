@@ -1,37 +1,72 @@
 use clap::Parser;
 use dotenv::dotenv;
 use ethers::{
-    prelude::{abigen, SignerMiddleware},
-    providers::{Middleware, Provider, PubsubClient, Ws},
+    prelude::SignerMiddleware,
+    providers::{Http, Middleware, Provider, PubsubClient, Ws},
     signers::{LocalWallet, Signer},
     types::{Address, U256},
 };
 use futures_util::StreamExt;
-use log::{debug, error, info};
-use std::{sync::Arc, time::Instant};
+use log::{debug, error, info, warn};
+use std::{
+    fs,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use tsuki::{
     constants::{
-        protocol::{
-            UniswapV2::{self},
-            UNISWAP_V3,
-        },
+        protocol::UniswapV2::{self},
         token::ERC20Token::{self, *},
     },
-    tx_pool::TxPool,
+    execution::{
+        broadcaster::{sign_transaction, Broadcaster, RawTransactionSender},
+        builder::ExecutionBuilder,
+        gas_model::GasModel,
+        ledger::{self, Ledger, LedgerEntry, OpportunityOutcome},
+        nonce_manager::NonceManager,
+        receipt_tracker::{ReceiptTracker, TxStatus},
+        revert::{receipt_revert_reason, RevertError},
+        simulation::simulate,
+        supervisor::{PendingTrade, TradeAction, TradeSupervisor},
+    },
+    tx_pool::{recorder::Recorder, TxPool},
+    utils::gas::compute_next_base_fee,
     world::{Protocol, WorldState},
 };
 
-abigen!(Flashloan, "abis/FlashloanV3.json");
-
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Args {
     /// use ipc (if running on node)
     #[arg(short, long)]
     use_ipc: bool,
+
+    /// warm start from a world state snapshot at this path (written periodically and on exit)
+    #[arg(short, long)]
+    snapshot: Option<PathBuf>,
+
+    /// also broadcast trades through these rpc endpoints (ws or http urls)
+    #[arg(short, long)]
+    broadcast: Vec<String>,
+
+    /// record opportunities, submissions and their outcomes to this JSONL trade ledger
+    #[arg(short, long)]
+    ledger: Option<PathBuf>,
+
+    /// record pending txns, blocks and reserve updates to this file for offline replay,
+    /// alongside a world state snapshot at <path>.world.json
+    #[arg(short, long)]
+    record: Option<PathBuf>,
+
+    /// write mempool metrics to this file every block, in prometheus text format
+    #[arg(short, long)]
+    metrics: Option<PathBuf>,
 }
 
+const SNAPSHOT_PERIOD: Duration = Duration::from_secs(60);
+
 struct Route {
     amount_in: U256,
     token_path: Vec<ERC20Token>,
@@ -50,36 +85,11 @@ fn is_profitable(token: ERC20Token, profit: U256, txn_fees: U256) -> bool {
     profit > txn_fee_usd
 }
 
-fn construct_arb_params(
-    amount_in: U256,
-    token_path: &Vec<ERC20Token>,
-    protocol_route: &Vec<Protocol>,
-) -> ArbParams {
-    let token_path = token_path.iter().map(|x| x.get_address()).collect();
-    let mut protocol_path = Vec::with_capacity(protocol_route.len());
-    let mut protocol_types = Vec::with_capacity(protocol_route.len());
-    let mut fees = Vec::with_capacity(protocol_route.len());
-    for protocol in protocol_route {
-        match protocol {
-            Protocol::UniswapV2(p) => {
-                protocol_path.push(p.get_router_address());
-                protocol_types.push(0);
-                fees.push(0);
-            }
-            Protocol::UniswapV3 { fee } => {
-                protocol_path.push(UNISWAP_V3.router_address);
-                protocol_types.push(1);
-                fees.push(*fee);
-            }
-        };
-    }
-
-    ArbParams {
-        amount_in: amount_in,
-        token_path: token_path,
-        protocol_path: protocol_path,
-        protocol_types: protocol_types,
-        fees: fees,
+fn record(ledger: &Option<Arc<Ledger>>, entry: LedgerEntry) {
+    if let Some(ledger) = ledger {
+        if let Err(e) = ledger.record(&entry) {
+            error!("Failed to write to trade ledger: {:?}", e);
+        }
     }
 }
 
@@ -87,41 +97,262 @@ async fn run_loop<P: PubsubClient + Clone + 'static>(
     provider: Arc<Provider<P>>,
     stream_provider: Provider<P>,
     routes: Vec<Route>,
+    snapshot_path: Option<PathBuf>,
+    broadcast_urls: Vec<String>,
+    ledger_path: Option<PathBuf>,
+    record_path: Option<PathBuf>,
+    metrics_path: Option<PathBuf>,
 ) {
     let tokens_list = vec![USDC, USDT, DAI, WBTC, WMATIC, WETH];
 
-    let txpool = TxPool::init(provider.clone(), 1000);
+    let recorder = record_path
+        .as_ref()
+        .map(|path| Arc::new(Recorder::create(path).expect("Unable to create mempool recording")));
+
+    let mut txpool = TxPool::init(provider.clone(), 1000);
+    if let Some(recorder) = &recorder {
+        txpool = txpool.with_recorder(recorder.clone());
+    }
     let txpool = Arc::new(txpool);
+    match txpool.reconcile().await {
+        Ok(reconciliation) => info!("Seeded mempool with {} txns", reconciliation.added),
+        Err(e) => warn!("Failed to seed mempool: {:?}", e),
+    }
     tokio::spawn(txpool.clone().stream_mempool());
+    tokio::spawn(
+        txpool
+            .clone()
+            .reconcile_periodically(Duration::from_secs(30)),
+    );
+    tokio::spawn(txpool.clone().expire_periodically(Duration::from_secs(10)));
+    tokio::spawn(
+        txpool
+            .clone()
+            .refresh_account_nonces_periodically(Duration::from_secs(60)),
+    );
+
+    let ws = match &snapshot_path {
+        Some(path) => {
+            WorldState::init_from_snapshot(
+                provider.clone(),
+                stream_provider,
+                tokens_list,
+                UniswapV2::get_all_protoccols(),
+                path,
+            )
+            .await
+        }
+        None => {
+            WorldState::init(
+                provider.clone(),
+                stream_provider,
+                tokens_list,
+                UniswapV2::get_all_protoccols(),
+            )
+            .await
+        }
+    };
 
-    let ws = WorldState::init(
-        provider.clone(),
-        stream_provider,
-        tokens_list,
-        UniswapV2::get_all_protoccols(),
-    )
-    .await;
+    let ws = match (&recorder, &record_path) {
+        (Some(recorder), Some(path)) => {
+            let world_path = path.with_extension("world.json");
+            if let Err(e) = ws.snapshot().await.save(&world_path) {
+                error!(
+                    "Failed to write world snapshot to {:?}: {:?}",
+                    world_path, e
+                );
+            }
+            ws.with_recorder(recorder.clone())
+        }
+        _ => ws,
+    };
 
     let ws = Arc::new(ws);
     tokio::spawn(ws.clone().stream_data());
 
+    if let Some(path) = snapshot_path {
+        tokio::spawn(ws.clone().persist_snapshots(path.clone(), SNAPSHOT_PERIOD));
+        let ws = ws.clone();
+        tokio::spawn(async move {
+            tokio::signal::ctrl_c().await.unwrap();
+            info!("Shutting down, writing world snapshot to {:?}", path);
+            if let Err(e) = ws.save_snapshot(&path).await {
+                error!("Failed to write world snapshot: {:?}", e);
+            }
+            std::process::exit(0);
+        });
+    }
+
     let wallet = std::env::var("PRIVATE_KEY")
         .unwrap()
         .parse::<LocalWallet>()
         .unwrap()
         .with_chain_id(137u64);
-    let client = SignerMiddleware::new(provider.clone(), wallet);
-    let arbitrage_contract = Flashloan::new(
+    let client = Arc::new(SignerMiddleware::new(provider.clone(), wallet));
+    let nonce_manager = NonceManager::init(client.clone(), client.address())
+        .await
+        .unwrap();
+    let nonce_manager = Arc::new(nonce_manager);
+    let executor = ExecutionBuilder::new(
         "0x7472bacc648111408497c087826739e7a1e0a6d2"
             .parse::<Address>()
             .unwrap(),
-        Arc::new(client),
+        client.clone(),
     );
+    let executor = Arc::new(executor);
+
+    let mut broadcaster = Broadcaster::new().endpoint("primary", provider.clone());
+    for url in broadcast_urls {
+        let sender: Arc<dyn RawTransactionSender> = if url.starts_with("ws") {
+            Arc::new(Provider::<Ws>::connect(&url).await.unwrap())
+        } else {
+            Arc::new(Provider::<Http>::try_from(url.as_str()).unwrap())
+        };
+        broadcaster = broadcaster.endpoint(url, sender);
+    }
+    let broadcaster = Arc::new(broadcaster);
+
+    let gas_model = Arc::new(GasModel::new());
+    let ledger = ledger_path.map(|path| Arc::new(Ledger::open(path).unwrap()));
+
+    // replaces or cancels sent trades whose opportunity disappears before they are mined
+    let supervisor = TradeSupervisor::new(
+        executor.clone(),
+        nonce_manager.clone(),
+        broadcaster.clone(),
+        is_profitable,
+    );
+    let supervisor = Arc::new(supervisor);
+
+    // resolves sent trades off the hot path, learning gas usage and recording outcomes. The
+    // context is the route traded, None for cancels.
+    let (receipt_tracker, mut resolved_txs) =
+        ReceiptTracker::<_, Option<Vec<Protocol>>>::new(provider.clone(), client.address());
+    let receipt_tracker = Arc::new(receipt_tracker);
+    {
+        let gas_model = gas_model.clone();
+        let ledger = ledger.clone();
+        let account = client.address();
+        let provider = provider.clone();
+        tokio::spawn(async move {
+            while let Some(resolved) = resolved_txs.recv().await {
+                match &resolved.status {
+                    TxStatus::Mined(receipt) | TxStatus::Reverted(receipt) => {
+                        info!(
+                            "  Txn {:?} mined in block {:?}, status: {:?}",
+                            resolved.tx_hash, receipt.block_number, receipt.status
+                        );
+                        match resolved.status {
+                            TxStatus::Mined(_) => {
+                                if let (Some(protocol_route), Some(gas_used)) =
+                                    (&resolved.context, receipt.gas_used)
+                                {
+                                    gas_model.observe(protocol_route, gas_used).await;
+                                }
+                            }
+                            _ => match receipt_revert_reason(provider.as_ref(), receipt).await {
+                                Ok(Some(reason)) => warn!("  Txn reverted: {}", reason),
+                                Ok(None) => warn!("  Txn reverted, reason unknown"),
+                                Err(e) => warn!("  Failed to replay reverted txn: {:?}", e),
+                            },
+                        }
+                        record(&ledger, LedgerEntry::mined(receipt, account));
+                    }
+                    TxStatus::Dropped => {
+                        warn!(
+                            "  Txns at nonce {} dropped or replaced by another sender",
+                            resolved.nonce
+                        )
+                    }
+                }
+            }
+        });
+    }
+
+    {
+        let supervisor = supervisor.clone();
+        let receipt_tracker = receipt_tracker.clone();
+        let ledger = ledger.clone();
+        let txpool = txpool.clone();
+        let ws = ws.clone();
+        let provider = provider.clone();
+        tokio::spawn(async move {
+            let mut block_stream = provider.subscribe_blocks().await.unwrap();
+            while let Some(block) = block_stream.next().await {
+                let next_base_fee = compute_next_base_fee(
+                    block.base_fee_per_gas.unwrap(),
+                    block.gas_used,
+                    block.gas_limit,
+                );
+                let actions = supervisor
+                    .on_block(&ws, block.number.unwrap(), next_base_fee)
+                    .await;
+                // replacements and cancels pay gas too, so they are ledgered like trades
+                for (nonce, action) in actions {
+                    let (tx_hash, fees, protocol_route) = match action {
+                        TradeAction::Replace {
+                            tx_hash,
+                            fees,
+                            protocol_route,
+                        } => (tx_hash, fees, Some(protocol_route)),
+                        TradeAction::Cancel { tx_hash, fees } => (tx_hash, fees, None),
+                        TradeAction::Keep | TradeAction::LetFail => continue,
+                    };
+                    record(
+                        &ledger,
+                        LedgerEntry::Submitted {
+                            timestamp: ledger::now(),
+                            tx_hash: tx_hash,
+                            nonce: nonce,
+                            max_fee_per_gas: fees.max_fee_per_gas,
+                            max_priority_fee_per_gas: fees.max_priority_fee_per_gas,
+                        },
+                    );
+                    receipt_tracker.watch(tx_hash, nonce, protocol_route).await;
+                }
+                receipt_tracker
+                    .on_block(block.number.unwrap().as_u64())
+                    .await;
+                // tips paid in the block feed the gas oracle
+                match provider.get_block_with_txs(block.number.unwrap()).await {
+                    Ok(Some(block)) => {
+                        txpool.on_block(&block).await;
+                        debug!(
+                            "Mempool has {} txns, {:?}",
+                            txpool.size().await,
+                            txpool.stats().await
+                        );
+                        if let Some(path) = &metrics_path {
+                            let exported = txpool.metrics().await.to_prometheus();
+                            // renamed into place so scrapers never read a partial file
+                            let tmp_path = path.with_extension("tmp");
+                            if let Err(e) = fs::write(&tmp_path, exported)
+                                .and_then(|_| fs::rename(&tmp_path, path))
+                            {
+                                warn!("Failed to write mempool metrics: {:?}", e);
+                            }
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(e) => warn!("Failed to fetch block transactions: {:?}", e),
+                }
+            }
+        });
+    }
 
     info!("Setup complete. Detecting arbitrage opportunities...");
     let mut block_stream = provider.subscribe_blocks().await.unwrap();
     while let Some(block) = block_stream.next().await {
+        if !ws.is_healthy() {
+            warn!("World state is resyncing, skipping block");
+            continue;
+        }
         let now = Instant::now();
+        let next_base_fee = compute_next_base_fee(
+            block.base_fee_per_gas.unwrap(),
+            block.gas_used,
+            block.gas_limit,
+        );
 
         let mut futures = Vec::with_capacity(routes.len());
         for route in &routes {
@@ -138,31 +369,141 @@ async fn run_loop<P: PubsubClient + Clone + 'static>(
             let amount_in = routes[i].amount_in;
             if est_amount_out > amount_in {
                 let profit = est_amount_out - amount_in;
+                let protocols: Vec<String> = protocol_route.iter().map(|p| p.to_string()).collect();
+                let opportunity = |outcome, expected_profit, tx_hash| LedgerEntry::Opportunity {
+                    timestamp: ledger::now(),
+                    block_number: block.number.unwrap().as_u64(),
+                    token_path: routes[i].token_path.clone(),
+                    protocols: protocols.clone(),
+                    amount_in: amount_in,
+                    expected_profit: expected_profit,
+                    outcome: outcome,
+                    tx_hash: tx_hash,
+                };
 
-                let params =
-                    construct_arb_params(amount_in, &routes[i].token_path, &protocol_route);
-
-                let est_gas_usage = U256::from(500000);
-                let gas_price = txpool.get_90th_percentile_gas_price().await + U256::from(100);
+                let est_gas_usage = gas_model.estimate(&protocol_route).await;
+                let fees = txpool.suggest_fees(next_base_fee).await;
+                let gas_price = fees.effective_gas_price(next_base_fee);
                 let txn_fees = gas_price.checked_mul(est_gas_usage).unwrap();
                 if !is_profitable(token, profit, txn_fees) {
                     debug!(
                         "  Arb not profitable, fee: {:?}, profit: {:?}",
                         gas_price, profit
                     );
+                    record(
+                        &ledger,
+                        opportunity(OpportunityOutcome::Unprofitable, profit, None),
+                    );
+                    continue;
+                }
+
+                let target_block_number = block.number.unwrap() + 1;
+                let mut contract_call = executor.execute_arbitrage(
+                    amount_in,
+                    &routes[i].token_path,
+                    &protocol_route,
+                    target_block_number,
+                    fees,
+                );
+                contract_call.tx.set_from(nonce_manager.address());
+
+                // dry run against pending state so reverts don't cost gas
+                let simulation =
+                    match simulate(provider.as_ref(), &contract_call.tx, token.get_address()).await
+                    {
+                        Ok(simulation) => {
+                            gas_model
+                                .observe(&protocol_route, simulation.gas_used)
+                                .await;
+                            simulation
+                        }
+                        Err(e) => {
+                            debug!("  Arb simulation failed: {}", e);
+                            record(
+                                &ledger,
+                                opportunity(OpportunityOutcome::SimulationFailed, profit, None),
+                            );
+                            continue;
+                        }
+                    };
+                // nodes that can't trace only tell whether it reverts, keep the estimate then
+                let simulated_profit = simulation.profit.unwrap_or(profit);
+                let txn_fees = gas_price.checked_mul(simulation.gas_used).unwrap();
+                if !is_profitable(token, simulated_profit, txn_fees) {
+                    debug!(
+                        "  Simulated arb not profitable, fee: {:?}, profit: {:?}",
+                        txn_fees, simulated_profit
+                    );
+                    record(
+                        &ledger,
+                        opportunity(
+                            OpportunityOutcome::SimulatedUnprofitable,
+                            simulated_profit,
+                            None,
+                        ),
+                    );
                     continue;
                 }
 
-                let current_block_number = block.number.unwrap();
-                let target_block_number = U256::from(current_block_number.as_u64() + 1);
-                let contract_call =
-                    arbitrage_contract.execute_arbitrage(params, target_block_number);
-                match contract_call.gas_price(gas_price).send().await {
-                    Ok(pending_txn) => {
-                        let _ = pending_txn.confirmations(1).await;
+                let nonce = nonce_manager.next_nonce().await;
+                contract_call.tx.set_nonce(nonce);
+                let sent = match sign_transaction(client.as_ref(), &mut contract_call.tx).await {
+                    Ok(raw_tx) => broadcaster
+                        .broadcast(raw_tx)
+                        .await
+                        .map_err(|e| e.to_string()),
+                    Err(e) => Err(e.to_string()),
+                };
+                match sent {
+                    Ok(broadcast) => {
+                        debug!("  Txn accepted first by {}", broadcast.accepted_by);
+                        record(
+                            &ledger,
+                            opportunity(
+                                OpportunityOutcome::Submitted,
+                                simulated_profit,
+                                Some(broadcast.tx_hash),
+                            ),
+                        );
+                        record(
+                            &ledger,
+                            LedgerEntry::Submitted {
+                                timestamp: ledger::now(),
+                                tx_hash: broadcast.tx_hash,
+                                nonce: nonce,
+                                max_fee_per_gas: fees.max_fee_per_gas,
+                                max_priority_fee_per_gas: fees.max_priority_fee_per_gas,
+                            },
+                        );
+                        nonce_manager
+                            .record_sent(nonce, broadcast.tx_hash, fees)
+                            .await;
+                        supervisor
+                            .track(
+                                nonce,
+                                PendingTrade {
+                                    amount_in: amount_in,
+                                    token_path: routes[i].token_path.clone(),
+                                    protocol_route: protocol_route.clone(),
+                                    tx: contract_call.tx.clone(),
+                                    fees: fees,
+                                },
+                            )
+                            .await;
+                        receipt_tracker
+                            .watch(broadcast.tx_hash, nonce, Some(protocol_route.clone()))
+                            .await;
                         info!("  Txn submitted, curr block: {:?}", block.number.unwrap());
                     }
-                    Err(_) => {
+                    Err(e) => {
+                        record(
+                            &ledger,
+                            opportunity(OpportunityOutcome::SendFailed, simulated_profit, None),
+                        );
+                        nonce_manager.handle_send_error(nonce, &e).await;
+                        if let Some(reason) = RevertError::from_error_message(&e) {
+                            error!("  Txn would revert: {}", reason);
+                        }
                         error!(
                             "  Err received in sending txn. Expected profit: {:?}, Route: {:?}){:?}",
                             profit,
@@ -280,6 +621,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             provider_ipc,
             Provider::connect_ipc("path/to/your/bor.ipc").await?,
             routes,
+            args.snapshot,
+            args.broadcast,
+            args.ledger,
+            args.record,
+            args.metrics,
         )
         .await;
     } else {
@@ -289,6 +635,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             alc_provider_ws.clone(),
             Provider::<Ws>::connect(&rpc_node_ws_url).await?,
             routes,
+            args.snapshot,
+            args.broadcast,
+            args.ledger,
+            args.record,
+            args.metrics,
         )
         .await;
     }
@@ -1,23 +1,23 @@
+use clap::Parser;
 use dotenv::dotenv;
 use ethers::{
     prelude::{k256::ecdsa::SigningKey, SignerMiddleware},
     providers::{JsonRpcClient, Middleware, Provider, ProviderError},
     signers::{LocalWallet, Signer, Wallet},
-    types::{Address, Bytes, Transaction, H160, H256, H64, U256},
+    types::{Address, Bytes, Transaction, H256, H64, U256},
     utils::{self, hex, rlp},
 };
 use futures_util::StreamExt;
 use lazy_static::lazy_static;
-use regex::Regex;
 use std::{
-    collections::{BinaryHeap, HashMap, HashSet},
-    str::FromStr,
+    collections::{HashMap, HashSet},
+    path::PathBuf,
     sync::Arc,
     time::{Duration, Instant},
     vec,
 };
 use tsuki::{
-    tx_pool::TxPool,
+    tx_pool::{recorder::Recorder, TxPool},
     utils::{
         batch::{
             common::{BatchRequest, JsonRpcError},
@@ -26,15 +26,23 @@ use tsuki::{
         },
         block::{Block, Header, PartialHeader},
         block_oracle::BlockOracle,
+        gas::compute_next_base_fee,
         serialize_structs::{Res, TraceConfig, TracerConfig},
         transaction::{build_typed_transaction, EthTransactionRequest, TypedTransaction},
-        txstructs::TxLinkedList,
+        txstructs::filter_mempool,
     },
 };
 
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// record pending txns, blocks and the account nonces each prediction used to this file
+    /// for offline replay
+    #[arg(short, long)]
+    record: Option<PathBuf>,
+}
+
 lazy_static! {
-    static ref ELASTICITY_MULTIPLIER: U256 = U256::from(2);
-    static ref BASE_FEE_CHANGE_DENOMINATOR: U256 = U256::from(8);
     static ref MIN_GAS_LIMIT: U256 = U256::from(500);
     static ref GAS_LIMIT_BOUND_DIVISOR: U256 = U256::from(1024);
     static ref DESIRED_GAS_LIMIT: U256 = U256::from(30_000_000);
@@ -65,106 +73,6 @@ fn compute_next_gas_limit(current_gas_limit: U256) -> U256 {
     return current_gas_limit;
 }
 
-// https://github.com/maticnetwork/bor/blob/ad69ccd0ba6aac4a690e6b4778987242609f4845/consensus/misc/eip1559.go#L99
-fn compute_next_base_fee(current_base_fee: U256, gas_used: U256, gas_limit: U256) -> U256 {
-    let gas_target = gas_limit.checked_div(*ELASTICITY_MULTIPLIER).unwrap();
-    if gas_used == gas_target {
-        return current_base_fee;
-    } else if gas_used > gas_target {
-        let gas_used_delta = gas_used - gas_target;
-        let x = current_base_fee.checked_mul(gas_used_delta).unwrap();
-        let y = x.checked_div(gas_target).unwrap();
-        let base_fee_delta = U256::max(
-            y.checked_div(*BASE_FEE_CHANGE_DENOMINATOR).unwrap(),
-            U256::one(),
-        );
-        return current_base_fee + base_fee_delta;
-    } else {
-        let gas_used_delta = gas_target - gas_used;
-        let x = current_base_fee.checked_mul(gas_used_delta).unwrap();
-        let y = x.checked_div(gas_target).unwrap();
-        let base_fee_delta = y.checked_div(*BASE_FEE_CHANGE_DENOMINATOR).unwrap();
-
-        return current_base_fee - base_fee_delta;
-    }
-}
-
-fn heapify_mempool(mut mempool_txns: Vec<Transaction>) -> BinaryHeap<TxLinkedList> {
-    mempool_txns.sort_by(|a, b| b.gas_price.unwrap().cmp(&a.gas_price.unwrap()));
-    mempool_txns.sort_by(|a, b| a.nonce.cmp(&b.nonce));
-    let mut mapping: HashMap<Address, TxLinkedList> = HashMap::new();
-    for txn in mempool_txns {
-        let sender_address = txn.from;
-        if !mapping.contains_key(&sender_address) {
-            mapping.insert(sender_address, TxLinkedList::new());
-        }
-        mapping
-            .get_mut(&sender_address)
-            .unwrap()
-            .linked_list
-            .push_back(txn);
-    }
-    let mut heap = BinaryHeap::<TxLinkedList>::new();
-
-    for (_, lls) in mapping {
-        heap.push(lls);
-    }
-
-    return heap;
-}
-
-// https://github.com/maticnetwork/bor/blob/ad69ccd0ba6aac4a690e6b4778987242609f4845/miner/worker.go#L942
-// TODO: need to account for https://github.com/maticnetwork/bor/blob/ad69ccd0ba6aac4a690e6b4778987242609f4845/miner/worker.go#L1020
-// where if gas limit is reached it ignores the other transactions from same sender (may impact our block predict algorithm)
-fn filter_mempool(
-    mempool_txns: Vec<Transaction>,
-    mut account_nonces: HashMap<Address, U256>,
-    next_base_fee: U256,
-) -> (Vec<Transaction>, Vec<Transaction>) {
-    let mut heap = heapify_mempool(mempool_txns);
-    let mut rejected_txns: Vec<Transaction> = Vec::new();
-    let mut final_txns: Vec<Transaction> = Vec::new();
-    while heap.len() != 0 {
-        let mut ll = heap.pop().unwrap();
-        let txn = ll.linked_list.pop_front().unwrap();
-        let current_account_nonce = account_nonces.get(&txn.from).unwrap();
-        if txn.nonce < *current_account_nonce {
-            rejected_txns.push(txn);
-            if ll.linked_list.front().is_some() {
-                heap.push(ll);
-            }
-            continue;
-        } else if txn.nonce > *current_account_nonce {
-            continue;
-        } else {
-            account_nonces.insert(txn.from, *current_account_nonce + 1);
-        }
-
-        let gas_fee = match txn.max_fee_per_gas {
-            Some(val) => val,
-            None => txn.gas_price.unwrap(),
-        };
-
-        if gas_fee < next_base_fee || gas_fee < U256::from(22916) {
-            continue;
-        }
-
-        // https://github.com/maticnetwork/bor/blob/ad69ccd0ba6aac4a690e6b4778987242609f4845/core/types/transaction.go#L426
-        if let Some(max_priority_gas_fee) = txn.max_priority_fee_per_gas {
-            let tip = gas_fee - next_base_fee;
-            if max_priority_gas_fee < tip {
-                continue;
-            }
-        }
-
-        if ll.linked_list.front().is_some() {
-            heap.push(ll);
-        }
-        final_txns.push(txn);
-    }
-    (final_txns, rejected_txns)
-}
-
 async fn retrieve_account_nonces(
     batch_provider_ipc: &BatchProvider<Ipc>,
     txns: &Vec<Transaction>,
@@ -244,6 +152,7 @@ async fn debug_traceBlock<M: JsonRpcClient>(
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     dotenv().ok();
+    let args = Args::parse();
     let provider_ipc = Provider::connect_ipc("/home/user/.bor/data/bor.ipc").await?;
     let provider_ipc = Arc::new(provider_ipc);
     let batch_provider_ipc = BatchProvider::connect_ipc("/home/user/.bor/data/bor.ipc").await?;
@@ -255,35 +164,30 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     //     .with_chain_id(137u64);
     // let signer_client = SignerMiddleware::new(provider_ipc.clone(), wallet);
 
-    let txpool = TxPool::init(provider_ipc.clone(), 1000);
+    let recorder = args
+        .record
+        .map(|path| Arc::new(Recorder::create(path).expect("Unable to create mempool recording")));
+    let mut txpool = TxPool::init(provider_ipc.clone(), 1000);
+    if let Some(recorder) = &recorder {
+        txpool = txpool.with_recorder(recorder.clone());
+    }
     let txpool = Arc::new(txpool);
+    let reconciliation = txpool.reconcile().await?;
+    println!("Seeded mempool with {} txns", reconciliation.added);
     tokio::spawn(txpool.clone().stream_mempool());
+    tokio::spawn(
+        txpool
+            .clone()
+            .reconcile_periodically(Duration::from_secs(30)),
+    );
 
     let mut predicted_txns: Vec<TypedTransaction> = Vec::new();
     let mut predicted_txn_hashes: HashSet<H256> = HashSet::new();
     let mut mempool_txns_at_that_time: HashSet<H256> = HashSet::new();
+    let mut last_arrivals: u64 = 0;
     let start_block_number = provider_ipc.get_block_number().await?;
     let mut block_stream = provider_ipc.subscribe_blocks().await.unwrap();
     while let Some(block) = block_stream.next().await {
-        // wait for three blocks to warm up mempool
-        if block.number.unwrap() <= start_block_number + 3 {
-            let block = provider_ipc
-                .get_block(block.number.unwrap())
-                .await?
-                .unwrap();
-            // update local mempool
-            let mut txn_hashes: Vec<H256> = Vec::new();
-            for hash in &block.transactions {
-                txn_hashes.push(*hash);
-            }
-            let num_removed = txpool.remove_transactions(txn_hashes).await;
-            println!(
-                "Num txns removed from mempool while warming up: {}",
-                num_removed
-            );
-            continue;
-        }
-
         /*
         1) predict next block
         2) simulate next block w/ our transactions
@@ -346,13 +250,17 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         // block_oracle.append_block(current_block.clone());
         // block_oracle.display_accuracy();
 
-        // update local mempool
-        let mut txn_hashes: Vec<H256> = Vec::new();
-        for txn in &current_block.transactions {
-            txn_hashes.push(txn.hash());
+        // update local mempool, also releasing quarantined senders and recording the block
+        match provider_ipc
+            .get_block_with_txs(block.number.unwrap())
+            .await?
+        {
+            Some(block) => {
+                let num_removed = txpool.on_block(&block).await;
+                println!("Num txns removed from mempool: {}", num_removed);
+            }
+            None => println!("Block {} not found", block.number.unwrap()),
         }
-        let num_removed = txpool.remove_transactions(txn_hashes).await;
-        println!("Num txns removed from mempool: {}", num_removed);
 
         tokio::time::sleep(Duration::from_secs_f32(0.5)).await;
 
@@ -363,8 +271,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             mempool_txns_at_that_time.insert(txn.hash());
         }
         println!("NUMBER OF TXNS in MEMPOOL: {}", mempool_txns.len());
-        println!("RECIEVED {} TXNS in mempool", txpool.get_count().await);
-        txpool.reset_count().await;
+        let metrics = txpool.metrics().await;
+        println!(
+            "RECIEVED {} TXNS in mempool ({:.1}/s, {} replaced)",
+            metrics.arrivals - last_arrivals,
+            metrics.arrival_rate,
+            metrics.replacements
+        );
+        last_arrivals = metrics.arrivals;
         let account_nonces = retrieve_account_nonces(&batch_provider_ipc, &mempool_txns).await;
         let nonce_now = Instant::now();
 
@@ -373,6 +287,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             current_block.header.gas_used,
             current_block.header.gas_limit,
         );
+        if let Some(recorder) = &recorder {
+            recorder.record_prediction(
+                block.number.unwrap().as_u64() + 1,
+                next_base_fee,
+                &account_nonces,
+                Instant::now(),
+            );
+        }
         let (mempool_txns, rejected_txns) =
             filter_mempool(mempool_txns, account_nonces, next_base_fee);
         let num_removed = txpool
@@ -403,70 +325,20 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             mempool_txns_typed.clone(),
         )
         .await;
-        if result.is_err() {
-            // TODO: remove transactions from mempool causing error
-            // could do by parsing out address and filtering out
-            // transactions with that for address
-            match result.unwrap_err() {
-                ProviderError::JsonRpcClientError(value) => {
-                    // strip the 0x and capture the address in capturing group 1
-                    lazy_static! {
-                        static ref RE: Regex = Regex::new(r"insufficient funds for gas \* price \+ value: address 0x([0-9a-fA-F]+)").unwrap();
-                    }
-                    let msg_str = value.to_string();
-
-                    // get address in capture group 1
-                    match RE.captures(&msg_str) {
-                        Some(caps) => {
-                            let address_str = caps.get(1).map_or("", |m| m.as_str());
-                            println!(
-                                "insufficient funds regex got address string: {:?}",
-                                address_str
-                            );
-                            // try to convert it to address object
-                            match H160::from_str(address_str) {
-                                Ok(address) => {
-                                    println!("insufficient gas error for address: {:?}", address);
-
-                                    // remove that all txs associated with address from the mempool
-                                    let curr_mempool_txs = txpool.get_mempool().await;
-
-                                    // get transaction hashes sent by the offending address
-                                    let troll_tx_hashes: Vec<H256> = curr_mempool_txs
-                                        .into_iter()
-                                        .filter(|tx| tx.from == address)
-                                        .map(|tx| tx.hash)
-                                        .collect();
-
-                                    // remove such txs from the mempool
-                                    txpool.remove_transactions(troll_tx_hashes).await;
-
-                                    println!(
-                                        "Remove transactions from {:?} from the mempool.",
-                                        address
-                                    );
-                                }
-                                Err(e) => {
-                                    println!("{:?}", e);
-                                }
-                            }
-                        }
-                        None => {
-                            println!(
-                                "Unable to match insufficient funds regex. Got message: {:?}",
-                                msg_str
-                            );
-                        }
-                    }
-                }
-                other => {
-                    // some provider error not having to do with JSONRpc was thrown
-                    println!("{:?}", other);
+        let result = match result {
+            Ok(result) => result,
+            Err(e) => {
+                // drop the transactions of whoever broke the simulation for a while
+                if txpool
+                    .quarantine_simulation_failure(&e.to_string())
+                    .await
+                    .is_none()
+                {
+                    println!("{:?}", e);
                 }
+                continue;
             }
-            continue;
-        }
-        let result = result.unwrap();
+        };
 
         // count upto the transaction that fills gas
         predicted_txns.clear();
@@ -5,7 +5,7 @@ use dotenv::dotenv;
 use ethers::prelude::{abigen, SignerMiddleware};
 use ethers::providers::{Http, ProviderError, SubscriptionStream};
 use ethers::signers::{LocalWallet, Signer};
-use ethers::types::{GethTrace, Transaction, U256, U64};
+use ethers::types::{BlockNumber, GethTrace, Transaction, U256, U64};
 use ethers::utils;
 use ethers::{
     abi::{parse_abi, Token},
@@ -16,20 +16,13 @@ use ethers::{
 use futures_util::StreamExt;
 use serde::{Deserialize, Serialize};
 use serde_json::value::RawValue;
+use tsuki::{
+    execution::builder::{with_eip1559_fees, Eip1559Fees},
+    utils::{gas::compute_next_base_fee, serialize_structs::PendingTransactionOptions},
+};
 
 abigen!(Liquidations, "abis/Liquidations.json");
 
-#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Default)]
-#[serde(rename_all = "camelCase")]
-pub struct PendingTransactionOptions {
-    #[serde(default, skip_serializing_if = "Option::is_none")]
-    pub from_address: Option<Vec<String>>,
-    #[serde(default, skip_serializing_if = "Option::is_none")]
-    pub to_address: Option<Vec<String>>,
-    #[serde(default, skip_serializing_if = "Option::is_none")]
-    pub hashes_only: Option<bool>,
-}
-
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Default)]
 #[serde(rename_all = "camelCase")]
 pub struct DebugTraceCallOptions {
@@ -262,8 +255,20 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 if let Some(dodo_pool) = dodo_pool {
                     let uniswap_router = QUICKSWAP.parse::<Address>().unwrap();
 
+                    let latest_block = provider.get_block(BlockNumber::Latest).await?.unwrap();
+                    let next_base_fee = compute_next_base_fee(
+                        latest_block.base_fee_per_gas.unwrap(),
+                        latest_block.gas_used,
+                        latest_block.gas_limit,
+                    );
+                    // double the liquidator's gas price for speedup, of which everything
+                    // above the base fee is tip
+                    let gas_price = gas_fee + gas_fee;
+                    let fees =
+                        Eip1559Fees::new(next_base_fee, gas_price.saturating_sub(next_base_fee));
+
                     // pass args into smart contract and win $$$
-                    match liquidations_contract
+                    let contract_call = liquidations_contract
                         .liquidation(
                             dodo_pool,
                             uniswap_router,
@@ -272,11 +277,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                             user,
                             debt_amount,
                         )
-                        .gas(max_gas)
-                        .gas_price(gas_fee + gas_fee) // double gas price for speedup
-                        .send()
-                        .await
-                    {
+                        .gas(max_gas);
+                    match with_eip1559_fees(contract_call, fees).send().await {
                         Ok(pending_txn) => {
                             println!("  Txn submitted: {}", pending_txn.tx_hash())
                         }
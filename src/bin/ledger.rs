@@ -0,0 +1,39 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+use tsuki::execution::ledger::{daily_pnl, Ledger};
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// trade ledger written by arb
+    path: PathBuf,
+
+    /// price of MATIC in dollars, to net gas out of the stablecoin profit
+    #[arg(short, long, default_value_t = 0.85)]
+    matic_usd: f64,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+    let entries = Ledger::load(&args.path)?;
+
+    println!(
+        "{:<12}{:>8}{:>10}{:>14}{:>12}",
+        "day", "trades", "reverted", "gas (MATIC)", "net ($)"
+    );
+    for day in daily_pnl(&entries) {
+        println!(
+            "{:<12}{:>8}{:>10}{:>14.4}{:>12.2}",
+            day.day,
+            day.trades,
+            day.reverted,
+            day.gas_cost.to_string().parse::<f64>()? / 1e18,
+            day.net_usd(args.matic_usd)
+        );
+        for (token, (received, sent)) in &day.token_deltas {
+            println!("    {:?}: +{} -{}", token, received, sent);
+        }
+    }
+    Ok(())
+}
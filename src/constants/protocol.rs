@@ -1,8 +1,9 @@
 use enum_map::{enum_map, Enum, EnumMap};
 use ethers::types::Address;
 use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
 
-#[derive(PartialEq, Debug, Enum, Clone, Copy)]
+#[derive(PartialEq, Eq, Hash, Debug, Enum, Clone, Copy, Serialize, Deserialize)]
 pub enum UniswapV2 {
     SUSHISWAP,
     QUICKSWAP,
@@ -78,6 +79,10 @@ lazy_static! {
             .parse::<Address>()
             .unwrap()
     };
+    // 1inch AggregationRouterV5
+    pub static ref ONEINCH_ROUTER: Address = "0x1111111254EEB25477B68fb85Ed929f73A960582"
+        .parse::<Address>()
+        .unwrap();
 }
 
 impl UniswapV2 {
@@ -1,8 +1,9 @@
 use enum_map::{enum_map, Enum, EnumMap};
 use ethers::types::Address;
 use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Enum, Clone, Copy, PartialEq)]
+#[derive(Debug, Enum, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum ERC20Token {
     USDC,
     USDT,
@@ -1,10 +1,35 @@
+use std::marker::PhantomData;
+
 use ethers::{
-    providers::{Middleware, Provider, PubsubClient, SubscriptionStream},
-    types::{Address, Log, H256},
-    utils::{self, keccak256},
+    abi::{Event, RawLog},
+    contract::{EthEvent, EthLogDecode, LogMeta},
+    providers::{Middleware, Provider, ProviderError, PubsubClient},
+    types::{Address, Filter, Log, H256},
+    utils,
 };
+use futures_util::{future, stream, Stream, StreamExt};
+use log::{debug, warn};
 use serde::{Deserialize, Serialize};
 
+use crate::{uniswapV2::SyncFilter, uniswapV3::SwapFilter};
+
+/// Max addresses per `eth_subscribe` logs subscription, larger lists are sharded
+pub const SUBSCRIPTION_SHARD_SIZE: usize = 500;
+
+/// Block range of the first `eth_getLogs` request in a backfill, halved whenever the
+/// node rejects a range for returning too many results
+pub const BACKFILL_CHUNK_SIZE: u64 = 2000;
+
+// substrings of the errors geth, bor, erigon and hosted providers return for oversized log queries
+const RANGE_TOO_LARGE_ERRORS: [&str; 6] = [
+    "query returned more than",
+    "too many results",
+    "response size exceeded",
+    "block range is too wide",
+    "exceed maximum block range",
+    "limit exceeded",
+];
+
 #[derive(Serialize, Deserialize)]
 pub struct EthSubscribeLogArgs {
     pub address: Vec<Address>,
@@ -20,39 +45,229 @@ impl EthSubscribeLogArgs {
     }
 }
 
+/// A decoded event along with the log it was emitted in
+#[derive(Debug, Clone)]
+pub struct EventLog<E> {
+    pub event: E,
+    pub meta: LogMeta,
+    /// true if the log was reverted by a chain reorg
+    pub removed: bool,
+}
+
+impl<E: EthLogDecode> EventLog<E> {
+    pub fn decode(log: &Log) -> Result<Self, ethers::abi::Error> {
+        let raw_log = RawLog {
+            topics: log.topics.to_vec(),
+            data: log.data.to_vec(),
+        };
+        Ok(Self {
+            event: E::decode_log(&raw_log)?,
+            meta: LogMeta::from(log),
+            removed: log.removed.unwrap_or(false),
+        })
+    }
+
+    /// `decode`, logging and skipping logs that are not an `E`
+    fn decode_or_skip(log: &Log) -> Option<Self> {
+        match Self::decode(log) {
+            Ok(event_log) => Some(event_log),
+            Err(e) => {
+                warn!("Unable to decode log from {:?}: {:?}", log.address, e);
+                None
+            }
+        }
+    }
+}
+
+/// Builder for `eth_subscribe` log subscriptions over a set of events and contract addresses
+///
+/// ```ignore
+/// let mut stream = EventSubscription::<SyncFilter>::new()
+///     .event::<SyncFilter>()
+///     .addresses(pair_addresses)
+///     .subscribe(&provider)
+///     .await?;
+/// ```
+pub struct EventSubscription<E> {
+    addresses: Vec<Address>,
+    topics: Vec<H256>,
+    shard_size: usize,
+    _event: PhantomData<E>,
+}
+
+impl<E: EthLogDecode> Default for EventSubscription<E> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<E: EthLogDecode> EventSubscription<E> {
+    pub fn new() -> Self {
+        Self {
+            addresses: Vec::new(),
+            topics: Vec::new(),
+            shard_size: SUBSCRIPTION_SHARD_SIZE,
+            _event: PhantomData,
+        }
+    }
+
+    /// Subscribe to an abigen/`EthEvent` event type
+    pub fn event<T: EthEvent>(mut self) -> Self {
+        self.topics.push(T::signature());
+        self
+    }
+
+    /// Subscribe to an event from a parsed ABI
+    pub fn event_abi(mut self, event: &Event) -> Self {
+        self.topics.push(event.signature());
+        self
+    }
+
+    pub fn addresses(mut self, addresses: Vec<Address>) -> Self {
+        self.addresses = addresses;
+        self
+    }
+
+    pub fn shard_size(mut self, shard_size: usize) -> Self {
+        self.shard_size = usize::max(shard_size, 1);
+        self
+    }
+
+    pub fn topics(&self) -> Vec<H256> {
+        self.topics.to_vec()
+    }
+
+    /// Opens one subscription per address shard and merges them into a single stream.
+    /// Logs that fail to decode as `E` are logged and skipped.
+    pub async fn subscribe<'a, P: PubsubClient>(
+        &self,
+        provider: &'a Provider<P>,
+    ) -> Result<impl Stream<Item = EventLog<E>> + Unpin + 'a, ProviderError>
+    where
+        E: 'a,
+    {
+        let command = utils::serialize(&"logs");
+        let mut shards = Vec::new();
+        for addresses in self.addresses.chunks(self.shard_size) {
+            let args = EthSubscribeLogArgs::new(addresses.to_vec(), self.topics.to_vec());
+            let args = utils::serialize(&args);
+            shards.push(
+                provider
+                    .subscribe::<_, Log>([command.clone(), args])
+                    .await?,
+            );
+        }
+
+        let stream = stream::select_all(shards)
+            .filter_map(|log| future::ready(EventLog::<E>::decode_or_skip(&log)));
+        Ok(stream)
+    }
+
+    /// The subscribed events emitted between `from_block` and `to_block` inclusive, decoded
+    /// like the live stream, see `backfill_logs`
+    pub async fn backfill<M: Middleware>(
+        &self,
+        provider: &M,
+        from_block: u64,
+        to_block: u64,
+    ) -> Result<Vec<EventLog<E>>, M::Error> {
+        backfill_logs(
+            provider,
+            self.addresses.to_vec(),
+            self.topics.to_vec(),
+            from_block,
+            to_block,
+        )
+        .await
+    }
+}
+
+pub fn pair_sync_topic() -> H256 {
+    SyncFilter::signature()
+}
+
+fn is_range_too_large(error_msg: &str) -> bool {
+    let error_msg = error_msg.to_lowercase();
+    RANGE_TOO_LARGE_ERRORS
+        .iter()
+        .any(|pattern| error_msg.contains(pattern))
+}
+
+/// Fetches all logs matching `addresses` and any of `topics` (as topic0) between `from_block`
+/// and `to_block` inclusive, in chronological order, decoded as `E`. Requests are split into
+/// block ranges starting at `BACKFILL_CHUNK_SIZE`, halving the range on "too many results"
+/// errors. Logs that fail to decode are logged and skipped, as in `EventSubscription`.
+pub async fn backfill_logs<E: EthLogDecode, M: Middleware>(
+    provider: &M,
+    addresses: Vec<Address>,
+    topics: Vec<H256>,
+    from_block: u64,
+    to_block: u64,
+) -> Result<Vec<EventLog<E>>, M::Error> {
+    let filter = Filter::new().address(addresses).topic0(topics);
+    let mut logs: Vec<EventLog<E>> = Vec::new();
+    let mut chunk_size = BACKFILL_CHUNK_SIZE;
+    let mut start = from_block;
+    while start <= to_block {
+        let end = u64::min(start + chunk_size - 1, to_block);
+        let chunk_filter = filter.clone().from_block(start).to_block(end);
+        match provider.get_logs(&chunk_filter).await {
+            Ok(chunk) => {
+                debug!(
+                    "Backfilled {} logs from blocks {}-{}",
+                    chunk.len(),
+                    start,
+                    end
+                );
+                logs.extend(chunk.iter().filter_map(EventLog::decode_or_skip));
+                start = end + 1;
+                // creep back up after a dense stretch of blocks
+                chunk_size = u64::min(chunk_size * 2, BACKFILL_CHUNK_SIZE);
+            }
+            Err(e) if chunk_size > 1 && is_range_too_large(&e.to_string()) => {
+                chunk_size /= 2;
+                debug!(
+                    "Log range {}-{} too large, retrying with {} blocks",
+                    start, end, chunk_size
+                );
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(logs)
+}
+
 // https://geth.ethereum.org/docs/rpc/pubsub
-pub async fn get_pair_sync_stream<P: PubsubClient>(
-    provider: &Provider<P>,
-    pair_addresses: Vec<Address>,
-) -> SubscriptionStream<P, Log> {
-    let command = "logs";
-    let command = utils::serialize(&command);
-
-    let event_name = "Sync(uint112,uint112)";
-    let topic = H256::from(keccak256(event_name.as_bytes()));
-    let topics = vec![topic];
-
-    let args = EthSubscribeLogArgs::new(pair_addresses, topics);
-    let args = utils::serialize(&args);
-
-    let stream = provider.subscribe::<_, Log>([command, args]).await.unwrap();
-    return stream;
+pub fn pair_sync_subscription(pair_addresses: Vec<Address>) -> EventSubscription<SyncFilter> {
+    EventSubscription::new()
+        .event::<SyncFilter>()
+        .addresses(pair_addresses)
+}
+
+pub fn pool_swap_subscription(pool_addresses: Vec<Address>) -> EventSubscription<SwapFilter> {
+    EventSubscription::new()
+        .event::<SwapFilter>()
+        .addresses(pool_addresses)
 }
 
 #[cfg(test)]
 mod tests {
     use std::str::FromStr;
 
-    use ethers::abi::parse_abi;
+    use ethers::abi::{encode, parse_abi, Token};
+    use ethers::contract::EthEvent;
     use ethers::prelude::BaseContract;
-    use ethers::types::H256;
+    use ethers::types::{Filter, Log, H256, I256, U64};
     use ethers::{
         providers::{Provider, Ws},
         types::{Address, Bytes, U256},
     };
     use futures_util::StreamExt;
 
-    use super::get_pair_sync_stream;
+    use super::{
+        is_range_too_large, pair_sync_subscription, pair_sync_topic, pool_swap_subscription,
+    };
+    use crate::uniswapV3::SwapFilter;
 
     #[test]
     fn test_pair_sync_parsing() {
@@ -70,6 +285,108 @@ mod tests {
         println!("{:?}, {:?}", reserve0, reserve1);
     }
 
+    #[test]
+    fn test_range_too_large_errors() {
+        assert!(is_range_too_large(
+            "(code: -32005, message: query returned more than 10000 results, data: None)"
+        ));
+        assert!(is_range_too_large(
+            "Log response size exceeded. You can make eth_getLogs requests with up to a 2K block range"
+        ));
+        assert!(!is_range_too_large("execution reverted"));
+    }
+
+    #[tokio::test]
+    async fn test_backfill_chunking() {
+        let pair = Address::repeat_byte(1);
+        let sync_log = |block_number: u64, reserve0: u64| Log {
+            address: pair,
+            topics: vec![pair_sync_topic()],
+            data: encode(&[
+                Token::Uint(U256::from(reserve0)),
+                Token::Uint(U256::from(1)),
+            ])
+            .into(),
+            block_number: Some(U64::from(block_number)),
+            block_hash: Some(H256::zero()),
+            transaction_hash: Some(H256::zero()),
+            transaction_index: Some(U64::zero()),
+            log_index: Some(U256::zero()),
+            ..Default::default()
+        };
+
+        // responses are popped from the back. The mock cannot return an rpc error, but a
+        // string where logs are expected fails to deserialize with the string in the error.
+        let (provider, mock) = Provider::mocked();
+        mock.push::<Vec<Log>, _>(vec![sync_log(2500, 3)]).unwrap();
+        mock.push::<Vec<Log>, _>(vec![sync_log(500, 2)]).unwrap();
+        mock.push::<&str, _>("query returned more than 10000 results")
+            .unwrap();
+
+        let logs = pair_sync_subscription(vec![pair])
+            .backfill(&provider, 0, 2999)
+            .await
+            .unwrap();
+        assert_eq!(logs.len(), 2);
+        assert_eq!(logs[0].event.reserve_0, 2);
+        assert_eq!(logs[1].meta.block_number, U64::from(2500));
+
+        // halved after the oversized range, then crept back up to the full chunk size
+        let filter = Filter::new()
+            .address(vec![pair])
+            .topic0(vec![pair_sync_topic()]);
+        for (from, to) in [(0, 1999), (0, 999), (1000, 2999)] {
+            mock.assert_request(
+                "eth_getLogs",
+                [filter.clone().from_block(from).to_block(to)],
+            )
+            .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_backfill_v3_swaps() {
+        let pool = Address::repeat_byte(2);
+        let router = Address::repeat_byte(3);
+        let swap_log = Log {
+            address: pool,
+            topics: vec![
+                SwapFilter::signature(),
+                H256::from(router),
+                H256::from(router),
+            ],
+            data: encode(&[
+                Token::Int(U256::from(1_000_000)),
+                // -500 as int256
+                Token::Int(U256::MAX - 499),
+                Token::Uint(U256::one() << 96),
+                Token::Uint(U256::from(10_000_000)),
+                Token::Int(U256::MAX - 9),
+            ])
+            .into(),
+            block_number: Some(U64::from(10)),
+            block_hash: Some(H256::zero()),
+            transaction_hash: Some(H256::zero()),
+            transaction_index: Some(U64::zero()),
+            log_index: Some(U256::zero()),
+            ..Default::default()
+        };
+
+        let (provider, mock) = Provider::mocked();
+        mock.push::<Vec<Log>, _>(vec![swap_log]).unwrap();
+        let logs = pool_swap_subscription(vec![pool])
+            .backfill(&provider, 0, 100)
+            .await
+            .unwrap();
+        assert_eq!(logs.len(), 1);
+        let swap = &logs[0].event;
+        assert_eq!(swap.recipient, router);
+        assert_eq!(swap.amount_0, I256::from(1_000_000));
+        assert_eq!(swap.amount_1, I256::from(-500));
+        assert_eq!(swap.tick, -10);
+        assert_eq!(logs[0].meta.address, pool);
+    }
+
     #[tokio::test]
     async fn test_pair_sync_stream() {
         dotenv::dotenv().ok();
@@ -77,24 +394,20 @@ mod tests {
 
         let provider_ws = Provider::<Ws>::connect(&rpc_node_ws_url).await.unwrap();
 
-        let mut stream = get_pair_sync_stream(
-            &provider_ws,
-            vec!["0x34965ba0ac2451a34a0471f04cca3f990b8dea27"
+        let subscription =
+            pair_sync_subscription(vec!["0x34965ba0ac2451a34a0471f04cca3f990b8dea27"
                 .parse::<Address>()
-                .unwrap()],
-        )
-        .await;
+                .unwrap()]);
+        let mut stream = subscription.subscribe(&provider_ws).await.unwrap();
 
         while let Some(log) = stream.next().await {
             println!(
-                "block: {:?}, tx: {:?}, pair address: {:?}, log: {:?}",
-                log.block_number,
-                log.transaction_hash,
-                log.address,
-                2 // log.address,
-                  // Address::from(log.topics[1]),
-                  // Address::from(log.topics[2]),
-                  // U256::decode(log.data)
+                "block: {:?}, tx: {:?}, pair address: {:?}, reserves: ({}, {})",
+                log.meta.block_number,
+                log.meta.transaction_hash,
+                log.meta.address,
+                log.event.reserve_0,
+                log.event.reserve_1,
             );
         }
     }
@@ -0,0 +1,251 @@
+use std::{sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use ethers::{
+    middleware::{signer::SignerMiddlewareError, SignerMiddleware},
+    providers::{JsonRpcClient, Middleware, Provider, ProviderError},
+    signers::Signer,
+    types::{transaction::eip2718::TypedTransaction, Bytes, H256},
+    utils::keccak256,
+};
+use futures_util::{stream::FuturesUnordered, StreamExt};
+use log::{debug, warn};
+use thiserror::Error;
+
+use super::nonce_manager::NonceError;
+
+const DEFAULT_BROADCAST_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Anything that accepts signed raw transactions: a node over any transport or a relay
+#[async_trait]
+pub trait RawTransactionSender: Send + Sync {
+    async fn send_raw_transaction(&self, raw_tx: Bytes) -> Result<H256, ProviderError>;
+}
+
+#[async_trait]
+impl<P: JsonRpcClient> RawTransactionSender for Provider<P> {
+    async fn send_raw_transaction(&self, raw_tx: Bytes) -> Result<H256, ProviderError> {
+        self.request("eth_sendRawTransaction", [raw_tx]).await
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum BroadcastError {
+    #[error("no broadcast endpoints configured")]
+    NoEndpoints,
+    #[error("rejected by every endpoint: {0:?}")]
+    Rejected(Vec<(String, String)>),
+}
+
+/// Where a broadcast transaction ended up, as far as was known when the first endpoint
+/// accepted it. Endpoints still answering are logged in the background.
+#[derive(Debug, Clone)]
+pub struct Broadcast {
+    pub tx_hash: H256,
+    /// the first endpoint that took the transaction or already had it
+    pub accepted_by: String,
+    /// endpoints that had already turned the transaction away, best-effort
+    pub rejected: Vec<(String, String)>,
+}
+
+/// Sends the same signed transaction to several endpoints at once, so it reaches the
+/// block producer through whichever path is fastest
+pub struct Broadcaster {
+    endpoints: Vec<(String, Arc<dyn RawTransactionSender>)>,
+    timeout: Duration,
+}
+
+impl Default for Broadcaster {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Broadcaster {
+    pub fn new() -> Self {
+        Self {
+            endpoints: Vec::new(),
+            timeout: DEFAULT_BROADCAST_TIMEOUT,
+        }
+    }
+
+    pub fn endpoint(
+        mut self,
+        name: impl Into<String>,
+        sender: Arc<dyn RawTransactionSender>,
+    ) -> Self {
+        self.endpoints.push((name.into(), sender));
+        self
+    }
+
+    /// How long to wait on each endpoint before counting it as rejected
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sends `raw_tx` to every endpoint concurrently. Returns as soon as an endpoint accepts
+    /// the transaction, an "already known" answer counting as accepted since it means the
+    /// transaction reached that node through another endpoint, or once every endpoint
+    /// rejected it. Endpoints that have not answered by then are awaited in the background.
+    pub async fn broadcast(&self, raw_tx: Bytes) -> Result<Broadcast, BroadcastError> {
+        if self.endpoints.is_empty() {
+            return Err(BroadcastError::NoEndpoints);
+        }
+
+        let tx_hash = H256::from(keccak256(&raw_tx));
+        let mut responses: FuturesUnordered<_> = self
+            .endpoints
+            .iter()
+            .map(|(name, sender)| {
+                let (name, sender) = (name.clone(), sender.clone());
+                let raw_tx = raw_tx.clone();
+                let timeout = self.timeout;
+                async move {
+                    let response =
+                        tokio::time::timeout(timeout, sender.send_raw_transaction(raw_tx)).await;
+                    let accepted = match response {
+                        Ok(Ok(hash)) => {
+                            if hash != tx_hash {
+                                warn!("{} returned hash {:?}, expected {:?}", name, hash, tx_hash);
+                            }
+                            Ok(())
+                        }
+                        Ok(Err(e)) => {
+                            let error_msg = e.to_string();
+                            if NonceError::classify(&error_msg) == Some(NonceError::AlreadyKnown) {
+                                Ok(())
+                            } else {
+                                Err(error_msg)
+                            }
+                        }
+                        Err(_) => Err("timed out".to_string()),
+                    };
+                    (name, accepted)
+                }
+            })
+            .collect();
+
+        let mut rejected = Vec::new();
+        while let Some((name, accepted)) = responses.next().await {
+            match accepted {
+                Ok(()) => {
+                    debug!("{} accepted {:?}", name, tx_hash);
+                    tokio::spawn(async move {
+                        while let Some((name, accepted)) = responses.next().await {
+                            match accepted {
+                                Ok(()) => debug!("{} accepted {:?}", name, tx_hash),
+                                Err(e) => debug!("{} rejected {:?}: {}", name, tx_hash, e),
+                            }
+                        }
+                    });
+                    return Ok(Broadcast {
+                        tx_hash: tx_hash,
+                        accepted_by: name,
+                        rejected: rejected,
+                    });
+                }
+                Err(e) => rejected.push((name, e)),
+            }
+        }
+        Err(BroadcastError::Rejected(rejected))
+    }
+}
+
+/// Fills in nonce, gas and chain id where missing and signs `tx` with the client's wallet,
+/// returning the raw transaction ready for broadcasting
+pub async fn sign_transaction<M: Middleware, S: Signer>(
+    client: &SignerMiddleware<M, S>,
+    tx: &mut TypedTransaction,
+) -> Result<Bytes, SignerMiddlewareError<M, S>> {
+    client.fill_transaction(tx, None).await?;
+    let signature = client
+        .signer()
+        .sign_transaction(tx)
+        .await
+        .map_err(SignerMiddlewareError::SignerError)?;
+    Ok(tx.rlp_signed(&signature))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use async_trait::async_trait;
+    use ethers::{
+        providers::ProviderError,
+        types::{Bytes, H256},
+        utils::keccak256,
+    };
+
+    use super::{BroadcastError, Broadcaster, RawTransactionSender};
+
+    /// Local stand-in for an rpc endpoint, answering after `delay`
+    struct MockEndpoint {
+        delay: Duration,
+        error: Option<&'static str>,
+    }
+
+    #[async_trait]
+    impl RawTransactionSender for MockEndpoint {
+        async fn send_raw_transaction(&self, raw_tx: Bytes) -> Result<H256, ProviderError> {
+            tokio::time::sleep(self.delay).await;
+            match self.error {
+                Some(error) => Err(ProviderError::CustomError(error.to_string())),
+                None => Ok(H256::from(keccak256(&raw_tx))),
+            }
+        }
+    }
+
+    fn mock(delay_ms: u64, error: Option<&'static str>) -> Arc<dyn RawTransactionSender> {
+        Arc::new(MockEndpoint {
+            delay: Duration::from_millis(delay_ms),
+            error: error,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_broadcast() {
+        let raw_tx = Bytes::from(vec![1, 2, 3]);
+        let broadcaster = Broadcaster::new()
+            .endpoint("slow", mock(50, None))
+            .endpoint("fast", mock(0, None))
+            .endpoint("peer", mock(20, Some("already known")))
+            .endpoint("relay", mock(10, Some("nonce too low")))
+            .endpoint("stuck", mock(1000, None))
+            .timeout(Duration::from_millis(200));
+
+        // the first accept returns without waiting on the slower endpoints
+        let started = Instant::now();
+        let broadcast = broadcaster.broadcast(raw_tx.clone()).await.unwrap();
+        assert!(started.elapsed() < Duration::from_millis(50));
+        assert_eq!(broadcast.tx_hash, H256::from(keccak256(&raw_tx)));
+        assert_eq!(broadcast.accepted_by, "fast");
+        assert!(broadcast.rejected.is_empty());
+
+        // an endpoint that already has the transaction counts as accepting it
+        let broadcaster = Broadcaster::new()
+            .endpoint("relay", mock(0, Some("nonce too low")))
+            .endpoint("peer", mock(10, Some("already known")))
+            .endpoint("stuck", mock(1000, None))
+            .timeout(Duration::from_millis(200));
+        let broadcast = broadcaster.broadcast(raw_tx.clone()).await.unwrap();
+        assert_eq!(broadcast.accepted_by, "peer");
+        assert_eq!(broadcast.rejected[0].0, "relay");
+
+        let broadcaster = Broadcaster::new()
+            .endpoint("relay", mock(0, Some("nonce too low")))
+            .endpoint("stuck", mock(1000, None))
+            .timeout(Duration::from_millis(50));
+        match broadcaster.broadcast(raw_tx).await {
+            Err(BroadcastError::Rejected(rejected)) => {
+                assert!(rejected[0].1.contains("nonce too low"));
+                assert_eq!(rejected[1], ("stuck".to_string(), "timed out".to_string()));
+            }
+            other => panic!("expected rejection, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use std::sync::Arc;
+
+use ethers::{
+    prelude::builders::ContractCall,
+    providers::Middleware,
+    types::{
+        transaction::eip2718::TypedTransaction, Address, Eip1559TransactionRequest, U256, U64,
+    },
+};
+use lazy_static::lazy_static;
+
+use super::{construct_arb_params, Flashloan};
+use crate::{constants::token::ERC20Token, world::Protocol};
+
+lazy_static! {
+    // bor rejects transactions tipping less than 30 gwei
+    pub static ref MIN_PRIORITY_FEE: U256 = U256::from(30_000_000_000_u64);
+}
+
+/// Fee caps of a type-2 transaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Eip1559Fees {
+    pub max_fee_per_gas: U256,
+    pub max_priority_fee_per_gas: U256,
+}
+
+impl Eip1559Fees {
+    /// Tip is raised to at least `MIN_PRIORITY_FEE`. The max fee leaves room for the
+    /// base fee to double before the transaction is priced out.
+    pub fn new(next_base_fee: U256, priority_fee: U256) -> Self {
+        let priority_fee = U256::max(priority_fee, *MIN_PRIORITY_FEE);
+        Self {
+            max_fee_per_gas: next_base_fee * 2 + priority_fee,
+            max_priority_fee_per_gas: priority_fee,
+        }
+    }
+
+    /// Smallest fees a node accepts as a same-nonce replacement of `self`, which geth
+    /// requires to raise both the max fee and the tip by at least 10%
+    pub fn bumped(&self) -> Self {
+        Self {
+            max_fee_per_gas: self.max_fee_per_gas * 110 / 100 + 1,
+            max_priority_fee_per_gas: self.max_priority_fee_per_gas * 110 / 100 + 1,
+        }
+    }
+
+    /// Element-wise max, used to keep replacement fees above the bump threshold
+    pub fn max(&self, other: Eip1559Fees) -> Self {
+        Self {
+            max_fee_per_gas: U256::max(self.max_fee_per_gas, other.max_fee_per_gas),
+            max_priority_fee_per_gas: U256::max(
+                self.max_priority_fee_per_gas,
+                other.max_priority_fee_per_gas,
+            ),
+        }
+    }
+
+    /// Price per gas actually paid when included in a block with `base_fee`
+    pub fn effective_gas_price(&self, base_fee: U256) -> U256 {
+        U256::min(
+            self.max_fee_per_gas,
+            base_fee + self.max_priority_fee_per_gas,
+        )
+    }
+}
+
+/// Rewrites the call's transaction as a type-2 transaction paying `fees`
+pub fn with_eip1559_fees<M, D>(
+    mut call: ContractCall<M, D>,
+    fees: Eip1559Fees,
+) -> ContractCall<M, D> {
+    let tx = &call.tx;
+    let mut request = Eip1559TransactionRequest::new()
+        .max_fee_per_gas(fees.max_fee_per_gas)
+        .max_priority_fee_per_gas(fees.max_priority_fee_per_gas);
+    request.from = tx.from().cloned();
+    request.to = tx.to().cloned();
+    request.gas = tx.gas().cloned();
+    request.value = tx.value().cloned();
+    request.data = tx.data().cloned();
+    request.nonce = tx.nonce().cloned();
+    request.chain_id = tx.chain_id();
+    request.access_list = tx.access_list().cloned().unwrap_or_default();
+
+    call.tx = TypedTransaction::Eip1559(request);
+    call
+}
+
+/// Builds type-2 `executeArbitrage` transactions against a deployed Flashloan contract
+pub struct ExecutionBuilder<M> {
+    contract: Flashloan<M>,
+    gas_limit: Option<U256>,
+}
+
+impl<M: Middleware> ExecutionBuilder<M> {
+    pub fn new(contract_address: Address, client: Arc<M>) -> Self {
+        Self {
+            contract: Flashloan::new(contract_address, client),
+            gas_limit: None,
+        }
+    }
+
+    /// Fixed gas limit, otherwise the middleware estimates it when sending
+    pub fn gas_limit(mut self, gas_limit: U256) -> Self {
+        self.gas_limit = Some(gas_limit);
+        self
+    }
+
+    pub fn contract(&self) -> &Flashloan<M> {
+        &self.contract
+    }
+
+    /// Arbitrage along `token_path`/`protocol_route`, reverting if mined after `target_block`
+    pub fn execute_arbitrage(
+        &self,
+        amount_in: U256,
+        token_path: &Vec<ERC20Token>,
+        protocol_route: &Vec<Protocol>,
+        target_block: U64,
+        fees: Eip1559Fees,
+    ) -> ContractCall<M, ()> {
+        let params = construct_arb_params(amount_in, token_path, protocol_route);
+        let mut call = self
+            .contract
+            .execute_arbitrage(params, U256::from(target_block.as_u64()));
+        if let Some(gas_limit) = self.gas_limit {
+            call = call.gas(gas_limit);
+        }
+        with_eip1559_fees(call, fees)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::types::U256;
+
+    use super::{Eip1559Fees, MIN_PRIORITY_FEE};
+
+    #[test]
+    fn test_eip1559_fees() {
+        let gwei = U256::exp10(9);
+        let fees = Eip1559Fees::new(U256::from(100) * gwei, U256::from(40) * gwei);
+        assert_eq!(fees.max_priority_fee_per_gas, U256::from(40) * gwei);
+        assert_eq!(fees.max_fee_per_gas, U256::from(240) * gwei);
+        // base fee falls, only the tip is paid on top
+        assert_eq!(
+            fees.effective_gas_price(U256::from(90) * gwei),
+            U256::from(130) * gwei
+        );
+        // base fee spikes past the cap
+        assert_eq!(
+            fees.effective_gas_price(U256::from(250) * gwei),
+            fees.max_fee_per_gas
+        );
+
+        let fees = Eip1559Fees::new(U256::from(100) * gwei, U256::one());
+        assert_eq!(fees.max_priority_fee_per_gas, *MIN_PRIORITY_FEE);
+
+        let bumped = fees.bumped();
+        assert!(bumped.max_fee_per_gas * 100 > fees.max_fee_per_gas * 110);
+        assert!(bumped.max_priority_fee_per_gas * 100 > fees.max_priority_fee_per_gas * 110);
+    }
+}
@@ -0,0 +1,275 @@
+use async_trait::async_trait;
+use ethers::{
+    signers::Signer,
+    types::{Bytes, H256, U64},
+    utils::keccak256,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use thiserror::Error;
+
+pub const FLASHBOTS_SIGNATURE_HEADER: &str = "X-Flashbots-Signature";
+
+/// Transactions a builder must include in this order, in the same block, or not at all
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Bundle {
+    txs: Vec<Bytes>,
+    block_number: U64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    min_timestamp: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_timestamp: Option<u64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    reverting_tx_hashes: Vec<H256>,
+}
+
+impl Bundle {
+    pub fn new(target_block: U64) -> Self {
+        Self {
+            txs: Vec::new(),
+            block_number: target_block,
+            min_timestamp: None,
+            max_timestamp: None,
+            reverting_tx_hashes: Vec::new(),
+        }
+    }
+
+    /// Appends a signed raw transaction, the bundle is dropped if it reverts
+    pub fn push_transaction(mut self, raw_tx: Bytes) -> Self {
+        self.txs.push(raw_tx);
+        self
+    }
+
+    /// Appends a signed raw transaction that is allowed to revert without the bundle being
+    /// dropped. It must still be included, a missing transaction invalidates the bundle.
+    pub fn push_reverting_transaction(mut self, raw_tx: Bytes) -> Self {
+        self.reverting_tx_hashes
+            .push(H256::from(keccak256(&raw_tx)));
+        self.txs.push(raw_tx);
+        self
+    }
+
+    pub fn min_timestamp(mut self, timestamp: u64) -> Self {
+        self.min_timestamp = Some(timestamp);
+        self
+    }
+
+    pub fn max_timestamp(mut self, timestamp: u64) -> Self {
+        self.max_timestamp = Some(timestamp);
+        self
+    }
+
+    pub fn transactions(&self) -> &Vec<Bytes> {
+        &self.txs
+    }
+
+    pub fn target_block(&self) -> U64 {
+        self.block_number
+    }
+
+    pub fn transaction_hashes(&self) -> Vec<H256> {
+        self.txs
+            .iter()
+            .map(|raw_tx| H256::from(keccak256(raw_tx)))
+            .collect()
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum RelayError {
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+    #[error(transparent)]
+    Serde(#[from] serde_json::Error),
+    #[error("failed to sign relay request: {0}")]
+    Signing(String),
+    #[error("relay rejected bundle: {0}")]
+    Rejected(String),
+}
+
+/// Somewhere bundles can be sent, a builder's relay or a stand-in for tests
+#[async_trait]
+pub trait BundleRelay: Send + Sync {
+    /// Submits `bundle`, returning the relay's bundle hash
+    async fn send_bundle(&self, bundle: &Bundle) -> Result<H256, RelayError>;
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SendBundleResult {
+    bundle_hash: H256,
+}
+
+/// `eth_sendBundle` over http, authenticated by signing each request body with
+/// `signer`, which only identifies the searcher and need not hold funds
+pub struct HttpRelay<S> {
+    client: reqwest::Client,
+    url: String,
+    signer: S,
+}
+
+impl<S: Signer> HttpRelay<S> {
+    pub fn new(url: impl Into<String>, signer: S) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url: url.into(),
+            signer: signer,
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Signer> BundleRelay for HttpRelay<S> {
+    async fn send_bundle(&self, bundle: &Bundle) -> Result<H256, RelayError> {
+        let body = serde_json::to_string(&json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "eth_sendBundle",
+            "params": [bundle],
+        }))?;
+        let signature = sign_request_body(&self.signer, &body).await?;
+
+        let response: serde_json::Value = self
+            .client
+            .post(&self.url)
+            .header(FLASHBOTS_SIGNATURE_HEADER, signature)
+            .header("Content-Type", "application/json")
+            .body(body)
+            .send()
+            .await?
+            .json()
+            .await?;
+        if let Some(error) = response.get("error") {
+            return Err(RelayError::Rejected(error.to_string()));
+        }
+        let result: SendBundleResult = serde_json::from_value(response["result"].clone())?;
+        Ok(result.bundle_hash)
+    }
+}
+
+/// Value of the signature header: `<address>:<signature of the hex keccak of the body>`
+pub async fn sign_request_body<S: Signer>(signer: &S, body: &str) -> Result<String, RelayError> {
+    let body_hash = format!("{:?}", H256::from(keccak256(body.as_bytes())));
+    let signature = signer
+        .sign_message(body_hash)
+        .await
+        .map_err(|e| RelayError::Signing(e.to_string()))?;
+    Ok(format!("{:?}:0x{}", signer.address(), signature))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use ethers::{
+        signers::{LocalWallet, Signer},
+        types::{Address, Bytes, Signature, H256, U64},
+        utils::keccak256,
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::{Bundle, BundleRelay, HttpRelay, FLASHBOTS_SIGNATURE_HEADER};
+
+    /// Local stand-in relay: accepts one request, checks its signature header and answers
+    /// with the bundle hash of the submitted transactions
+    async fn serve_one_bundle(listener: TcpListener) -> (Address, serde_json::Value) {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0u8; 4096];
+        let (headers, body) = loop {
+            let n = socket.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..n]);
+            let text = String::from_utf8_lossy(&request).to_string();
+            if let Some(split) = text.find("\r\n\r\n") {
+                let headers = text[..split].to_string();
+                let content_length: usize = headers
+                    .lines()
+                    .find_map(|line| {
+                        let (name, value) = line.split_once(':')?;
+                        name.eq_ignore_ascii_case("content-length")
+                            .then(|| value.trim().parse().unwrap())
+                    })
+                    .unwrap();
+                if request.len() >= split + 4 + content_length {
+                    break (
+                        headers,
+                        text[split + 4..split + 4 + content_length].to_string(),
+                    );
+                }
+            }
+        };
+
+        let signature_header = headers
+            .lines()
+            .find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                name.eq_ignore_ascii_case(FLASHBOTS_SIGNATURE_HEADER)
+                    .then(|| value.trim().to_string())
+            })
+            .unwrap();
+        let (address, signature) = signature_header.split_once(':').unwrap();
+        let signature = Signature::from_str(signature).unwrap();
+        let body_hash = format!("{:?}", H256::from(keccak256(body.as_bytes())));
+        let signer = signature.recover(body_hash).unwrap();
+        assert_eq!(signer, Address::from_str(address).unwrap());
+
+        let request: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let params = request["params"][0].clone();
+        let response = serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "result": { "bundleHash": H256::from(keccak256(params["txs"].to_string())) },
+        })
+        .to_string();
+        socket
+            .write_all(
+                format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                    response.len(),
+                    response
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        (signer, params)
+    }
+
+    #[tokio::test]
+    async fn test_send_bundle() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let relay_handle = tokio::spawn(serve_one_bundle(listener));
+
+        let signer = "ac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80"
+            .parse::<LocalWallet>()
+            .unwrap();
+        let target_tx = Bytes::from(vec![1, 2, 3]);
+        let arb_tx = Bytes::from(vec![4, 5, 6]);
+        let bundle = Bundle::new(U64::from(100))
+            .push_reverting_transaction(target_tx.clone())
+            .push_transaction(arb_tx)
+            .max_timestamp(1_700_000_000);
+
+        let relay = HttpRelay::new(url, signer.clone());
+        let bundle_hash = relay.send_bundle(&bundle).await.unwrap();
+
+        let (relay_signer, params) = relay_handle.await.unwrap();
+        assert_eq!(relay_signer, signer.address());
+        assert_eq!(params["blockNumber"], "0x64");
+        assert_eq!(params["maxTimestamp"], 1_700_000_000);
+        assert!(params.get("minTimestamp").is_none());
+        assert_eq!(
+            params["revertingTxHashes"][0],
+            serde_json::json!(H256::from(keccak256(&target_tx)))
+        );
+        assert_eq!(
+            bundle_hash,
+            H256::from(keccak256(params["txs"].to_string()))
+        );
+    }
+}
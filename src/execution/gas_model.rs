@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+
+use ethers::types::U256;
+use tokio::sync::RwLock;
+
+use crate::world::Protocol;
+
+// starting points until real observations come in, measured on polygon
+const FLASHLOAN_OVERHEAD_GAS: u64 = 110_000;
+const UNISWAP_V2_HOP_GAS: u64 = 95_000;
+const UNISWAP_V3_HOP_GAS: u64 = 135_000;
+
+// weight of a new observation in the moving averages, out of 100
+const OBSERVATION_WEIGHT: u64 = 30;
+
+/// Gas usage model for `executeArbitrage`, learned from simulation traces and receipts.
+///
+/// Exact estimates are cached per route shape (the sequence of protocols hopped through).
+/// Shapes that have not been seen yet are priced as the balancer flashloan overhead plus a
+/// per-hop cost for each V2 fork / V3 fee tier, which are refined by every observation.
+pub struct GasModel {
+    costs: RwLock<HopCosts>,
+    routes: RwLock<HashMap<Vec<Protocol>, U256>>,
+}
+
+/// Flashloan overhead and per-hop costs, behind one lock so they are always read and
+/// corrected together
+struct HopCosts {
+    flashloan_overhead: U256,
+    hops: HashMap<Protocol, U256>,
+}
+
+impl Default for GasModel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GasModel {
+    pub fn new() -> Self {
+        Self {
+            costs: RwLock::new(HopCosts {
+                flashloan_overhead: U256::from(FLASHLOAN_OVERHEAD_GAS),
+                hops: HashMap::new(),
+            }),
+            routes: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub async fn estimate(&self, protocol_route: &Vec<Protocol>) -> U256 {
+        if let Some(gas) = self.routes.read().await.get(protocol_route) {
+            return *gas;
+        }
+        self.costs.read().await.estimate(protocol_route)
+    }
+
+    /// Records the gas a route actually used, from a trace, `eth_estimateGas` or a receipt
+    pub async fn observe(&self, protocol_route: &Vec<Protocol>, gas_used: U256) {
+        {
+            let mut routes = self.routes.write().await;
+            let gas = routes
+                .get(protocol_route)
+                .map(|gas| moving_average(*gas, gas_used))
+                .unwrap_or(gas_used);
+            routes.insert(protocol_route.clone(), gas);
+        }
+
+        // spread the miss evenly over the flashloan and the hops, so that unseen shapes
+        // sharing them benefit too
+        let mut costs = self.costs.write().await;
+        let predicted = costs.estimate(protocol_route);
+        let parts = U256::from(protocol_route.len() + 1);
+        let correct = |gas: U256| {
+            let target = if gas_used >= predicted {
+                gas + (gas_used - predicted) / parts
+            } else {
+                gas.saturating_sub((predicted - gas_used) / parts)
+            };
+            moving_average(gas, target)
+        };
+
+        costs.flashloan_overhead = correct(costs.flashloan_overhead);
+        for protocol in protocol_route {
+            let hop_gas = costs.hop(protocol);
+            costs.hops.insert(*protocol, correct(hop_gas));
+        }
+    }
+}
+
+impl HopCosts {
+    fn estimate(&self, protocol_route: &Vec<Protocol>) -> U256 {
+        protocol_route
+            .iter()
+            .fold(self.flashloan_overhead, |gas, protocol| {
+                gas + self.hop(protocol)
+            })
+    }
+
+    fn hop(&self, protocol: &Protocol) -> U256 {
+        match self.hops.get(protocol) {
+            Some(gas) => *gas,
+            None => match protocol {
+                Protocol::UniswapV2(_) => U256::from(UNISWAP_V2_HOP_GAS),
+                Protocol::UniswapV3 { .. } => U256::from(UNISWAP_V3_HOP_GAS),
+            },
+        }
+    }
+}
+
+fn moving_average(current: U256, observed: U256) -> U256 {
+    (current * (100 - OBSERVATION_WEIGHT) + observed * OBSERVATION_WEIGHT) / 100
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::types::U256;
+
+    use super::{GasModel, FLASHLOAN_OVERHEAD_GAS, UNISWAP_V2_HOP_GAS, UNISWAP_V3_HOP_GAS};
+    use crate::{constants::protocol::UniswapV2, world::Protocol};
+
+    #[tokio::test]
+    async fn test_gas_model() {
+        let gas_model = GasModel::new();
+        let route = vec![
+            Protocol::UniswapV2(UniswapV2::SUSHISWAP),
+            Protocol::UniswapV3 { fee: 500 },
+        ];
+        assert_eq!(
+            gas_model.estimate(&route).await,
+            U256::from(FLASHLOAN_OVERHEAD_GAS + UNISWAP_V2_HOP_GAS + UNISWAP_V3_HOP_GAS)
+        );
+
+        // observed shapes are answered from the cache
+        gas_model.observe(&route, U256::from(400_000)).await;
+        assert_eq!(gas_model.estimate(&route).await, U256::from(400_000));
+
+        // and the miss is pushed into the hops for shapes not seen yet
+        let reversed = vec![
+            Protocol::UniswapV3 { fee: 500 },
+            Protocol::UniswapV2(UniswapV2::SUSHISWAP),
+        ];
+        let estimate = gas_model.estimate(&reversed).await;
+        assert!(
+            estimate > U256::from(FLASHLOAN_OVERHEAD_GAS + UNISWAP_V2_HOP_GAS + UNISWAP_V3_HOP_GAS)
+        );
+        assert!(estimate < U256::from(400_000));
+    }
+}
@@ -0,0 +1,358 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::Path,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use ethers::types::{Address, TransactionReceipt, H256, U256, U64};
+use serde::{Deserialize, Serialize};
+
+use super::simulation::TRANSFER_TOPIC;
+use crate::constants::token::ERC20Token::{self, *};
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+// valued at $1 when netting daily pnl
+const STABLECOINS: [ERC20Token; 3] = [USDC, USDT, DAI];
+
+/// Net movement of one token in or out of our account in a mined transaction
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenDelta {
+    pub token: Address,
+    pub received: U256,
+    pub sent: U256,
+}
+
+/// How far a detected opportunity got
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OpportunityOutcome {
+    /// the estimated profit did not cover the estimated gas
+    Unprofitable,
+    /// the dry run reverted or could not be run
+    SimulationFailed,
+    /// the dry run's profit did not cover the gas it used
+    SimulatedUnprofitable,
+    /// no endpoint accepted the transaction
+    SendFailed,
+    Submitted,
+}
+
+/// One line of the ledger. Entries for the same trade share its transaction hash.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum LedgerEntry {
+    /// every opportunity detected, whether or not it was traded
+    Opportunity {
+        timestamp: u64,
+        block_number: u64,
+        token_path: Vec<ERC20Token>,
+        protocols: Vec<String>,
+        amount_in: U256,
+        /// simulated profit once the trade was dry run, estimated profit before that
+        expected_profit: U256,
+        outcome: OpportunityOutcome,
+        /// set once submitted
+        tx_hash: Option<H256>,
+    },
+    Submitted {
+        timestamp: u64,
+        tx_hash: H256,
+        nonce: U256,
+        max_fee_per_gas: U256,
+        max_priority_fee_per_gas: U256,
+    },
+    Mined {
+        timestamp: u64,
+        tx_hash: H256,
+        block_number: u64,
+        success: bool,
+        gas_used: U256,
+        effective_gas_price: U256,
+        token_deltas: Vec<TokenDelta>,
+    },
+}
+
+impl LedgerEntry {
+    pub fn timestamp(&self) -> u64 {
+        match self {
+            LedgerEntry::Opportunity { timestamp, .. }
+            | LedgerEntry::Submitted { timestamp, .. }
+            | LedgerEntry::Mined { timestamp, .. } => *timestamp,
+        }
+    }
+
+    /// Outcome of a trade from its receipt, with token deltas decoded from the `Transfer`
+    /// logs moving tokens in or out of `account`
+    pub fn mined(receipt: &TransactionReceipt, account: Address) -> Self {
+        let mut deltas: BTreeMap<Address, (U256, U256)> = BTreeMap::new();
+        for log in &receipt.logs {
+            if log.topics.len() != 3 || log.topics[0] != *TRANSFER_TOPIC {
+                continue;
+            }
+            let amount = U256::from_big_endian(&log.data);
+            let (from, to) = (Address::from(log.topics[1]), Address::from(log.topics[2]));
+            if to == account {
+                deltas.entry(log.address).or_default().0 += amount;
+            }
+            if from == account {
+                deltas.entry(log.address).or_default().1 += amount;
+            }
+        }
+
+        LedgerEntry::Mined {
+            timestamp: now(),
+            tx_hash: receipt.transaction_hash,
+            block_number: receipt.block_number.unwrap_or_default().as_u64(),
+            success: receipt.status == Some(U64::one()),
+            gas_used: receipt.gas_used.unwrap_or_default(),
+            effective_gas_price: receipt.effective_gas_price.unwrap_or_default(),
+            token_deltas: deltas
+                .into_iter()
+                .map(|(token, (received, sent))| TokenDelta {
+                    token: token,
+                    received: received,
+                    sent: sent,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Realized results of the trades mined on one UTC day
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct DailyPnl {
+    /// YYYY-MM-DD
+    pub day: String,
+    pub trades: usize,
+    pub reverted: usize,
+    /// in wei of the native token
+    pub gas_cost: U256,
+    /// token -> (received, sent)
+    pub token_deltas: BTreeMap<Address, (U256, U256)>,
+}
+
+impl DailyPnl {
+    /// Stablecoin profit in dollars less gas paid, with the native token at `native_usd`.
+    /// Deltas in other tokens are not included.
+    pub fn net_usd(&self, native_usd: f64) -> f64 {
+        let mut net = 0.0;
+        for token in STABLECOINS {
+            if let Some((received, sent)) = self.token_deltas.get(&token.get_address()) {
+                let scale = 10f64.powi(token.get_decimals() as i32);
+                net += (to_f64(*received) - to_f64(*sent)) / scale;
+            }
+        }
+        net - to_f64(self.gas_cost) / 1e18 * native_usd
+    }
+}
+
+/// Append-only JSONL record of opportunities, submissions and their on-chain outcome
+pub struct Ledger {
+    file: Mutex<File>,
+}
+
+impl Ledger {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn record(&self, entry: &LedgerEntry) -> io::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        let mut file = self.file.lock().unwrap();
+        file.write_all(&line)?;
+        file.flush()
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Vec<LedgerEntry>> {
+        let reader = BufReader::new(File::open(path)?);
+        let mut entries = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            entries.push(serde_json::from_str(&line)?);
+        }
+        Ok(entries)
+    }
+}
+
+/// Groups mined trades by the UTC day they were recorded on, oldest first
+pub fn daily_pnl(entries: &Vec<LedgerEntry>) -> Vec<DailyPnl> {
+    let mut days: HashMap<u64, DailyPnl> = HashMap::new();
+    for entry in entries {
+        if let LedgerEntry::Mined {
+            timestamp,
+            success,
+            gas_used,
+            effective_gas_price,
+            token_deltas,
+            ..
+        } = entry
+        {
+            let day = days
+                .entry(timestamp / SECONDS_PER_DAY)
+                .or_insert_with(|| DailyPnl {
+                    day: format_day(timestamp / SECONDS_PER_DAY),
+                    ..Default::default()
+                });
+            day.trades += 1;
+            if !success {
+                day.reverted += 1;
+            }
+            day.gas_cost += gas_used * effective_gas_price;
+            for delta in token_deltas {
+                let (received, sent) = day.token_deltas.entry(delta.token).or_default();
+                *received += delta.received;
+                *sent += delta.sent;
+            }
+        }
+    }
+
+    let mut days: Vec<(u64, DailyPnl)> = days.into_iter().collect();
+    days.sort_by_key(|(day, _)| *day);
+    days.into_iter().map(|(_, pnl)| pnl).collect()
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+fn to_f64(amount: U256) -> f64 {
+    amount.to_string().parse().unwrap()
+}
+
+/// Days since the unix epoch as a YYYY-MM-DD civil date
+fn format_day(days: u64) -> String {
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::{
+        abi::{self, Token},
+        types::{Address, Log, TransactionReceipt, H256, U256, U64},
+    };
+
+    use super::{
+        daily_pnl, format_day, Ledger, LedgerEntry, OpportunityOutcome, TokenDelta, TRANSFER_TOPIC,
+    };
+    use crate::constants::token::ERC20Token::*;
+
+    fn transfer_log(token: Address, from: Address, to: Address, amount: u64) -> Log {
+        Log {
+            address: token,
+            topics: vec![*TRANSFER_TOPIC, H256::from(from), H256::from(to)],
+            data: abi::encode(&[Token::Uint(U256::from(amount))]).into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_format_day() {
+        assert_eq!(format_day(0), "1970-01-01");
+        assert_eq!(format_day(19_358), "2023-01-01");
+        assert_eq!(format_day(19_417), "2023-03-01");
+    }
+
+    #[test]
+    fn test_mined_token_deltas() {
+        let account = Address::from_low_u64_be(1);
+        let contract = Address::from_low_u64_be(2);
+        let receipt = TransactionReceipt {
+            status: Some(U64::one()),
+            logs: vec![
+                transfer_log(USDC.get_address(), contract, account, 2_000_000),
+                // the flashloan repayment doesn't touch our account
+                transfer_log(USDC.get_address(), contract, contract, 1_000_000_000),
+            ],
+            ..Default::default()
+        };
+        match LedgerEntry::mined(&receipt, account) {
+            LedgerEntry::Mined {
+                success,
+                token_deltas,
+                ..
+            } => {
+                assert!(success);
+                assert_eq!(token_deltas.len(), 1);
+                assert_eq!(token_deltas[0].token, USDC.get_address());
+                assert_eq!(token_deltas[0].received, U256::from(2_000_000));
+                assert_eq!(token_deltas[0].sent, U256::zero());
+            }
+            other => panic!("expected mined entry, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_daily_pnl() {
+        let mined = |timestamp: u64, profit: u64| LedgerEntry::Mined {
+            timestamp: timestamp,
+            tx_hash: H256::zero(),
+            block_number: 0,
+            success: profit > 0,
+            gas_used: U256::from(300_000),
+            effective_gas_price: U256::from(100_000_000_000_u64),
+            token_deltas: vec![TokenDelta {
+                token: USDC.get_address(),
+                received: U256::from(profit),
+                sent: U256::zero(),
+            }],
+        };
+        let entries = vec![
+            // skipped opportunities are kept, and do not count towards pnl
+            LedgerEntry::Opportunity {
+                timestamp: 19_358 * 86_400,
+                block_number: 0,
+                token_path: vec![USDC, WETH, USDC],
+                protocols: vec![],
+                amount_in: U256::from(1_000_000_000),
+                expected_profit: U256::from(10_000),
+                outcome: OpportunityOutcome::Unprofitable,
+                tx_hash: None,
+            },
+            mined(19_358 * 86_400 + 5, 2_000_000),
+            mined(19_358 * 86_400 + 50, 0),
+            mined(19_359 * 86_400, 2_000_000),
+        ];
+
+        let path = std::env::temp_dir().join(format!("tsuki_ledger_{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let ledger = Ledger::open(&path).unwrap();
+        for entry in &entries {
+            ledger.record(entry).unwrap();
+        }
+        let loaded = Ledger::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, entries);
+
+        let days = daily_pnl(&loaded);
+        assert_eq!(days.len(), 2);
+        assert_eq!(days[0].day, "2023-01-01");
+        assert_eq!(days[0].trades, 2);
+        assert_eq!(days[0].reverted, 1);
+        // $2 profit less two trades of 0.03 MATIC gas, at $1
+        assert!((days[0].net_usd(1.0) - 1.94).abs() < 1e-9);
+        assert!((days[1].net_usd(1.0) - 1.97).abs() < 1e-9);
+    }
+}
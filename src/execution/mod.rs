@@ -0,0 +1,52 @@
+use ethers::{prelude::abigen, types::U256};
+
+use crate::{
+    constants::{protocol::UNISWAP_V3, token::ERC20Token},
+    world::Protocol,
+};
+
+pub mod broadcaster;
+pub mod builder;
+pub mod bundle;
+pub mod gas_model;
+pub mod ledger;
+pub mod nonce_manager;
+pub mod receipt_tracker;
+pub mod revert;
+pub mod simulation;
+pub mod supervisor;
+
+abigen!(Flashloan, "abis/FlashloanV3.json");
+
+pub fn construct_arb_params(
+    amount_in: U256,
+    token_path: &Vec<ERC20Token>,
+    protocol_route: &Vec<Protocol>,
+) -> ArbParams {
+    let token_path = token_path.iter().map(|x| x.get_address()).collect();
+    let mut protocol_path = Vec::with_capacity(protocol_route.len());
+    let mut protocol_types = Vec::with_capacity(protocol_route.len());
+    let mut fees = Vec::with_capacity(protocol_route.len());
+    for protocol in protocol_route {
+        match protocol {
+            Protocol::UniswapV2(p) => {
+                protocol_path.push(p.get_router_address());
+                protocol_types.push(0);
+                fees.push(0);
+            }
+            Protocol::UniswapV3 { fee } => {
+                protocol_path.push(UNISWAP_V3.router_address);
+                protocol_types.push(1);
+                fees.push(*fee);
+            }
+        };
+    }
+
+    ArbParams {
+        amount_in: amount_in,
+        token_path: token_path,
+        protocol_path: protocol_path,
+        protocol_types: protocol_types,
+        fees: fees,
+    }
+}
@@ -0,0 +1,283 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use ethers::{
+    providers::Middleware,
+    types::{Address, BlockId, BlockNumber, H256, U256},
+};
+use log::{debug, warn};
+use tokio::sync::Mutex;
+
+use super::builder::Eip1559Fees;
+
+/// Send errors that mean our view of the account nonce is wrong, or that a replacement
+/// was rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NonceError {
+    TooLow,
+    TooHigh,
+    ReplacementUnderpriced,
+    AlreadyKnown,
+}
+
+impl NonceError {
+    pub fn classify(error_msg: &str) -> Option<Self> {
+        let error_msg = error_msg.to_lowercase();
+        if error_msg.contains("nonce too low") {
+            Some(Self::TooLow)
+        } else if error_msg.contains("nonce too high") || error_msg.contains("nonce gap") {
+            Some(Self::TooHigh)
+        } else if error_msg.contains("replacement transaction underpriced") {
+            Some(Self::ReplacementUnderpriced)
+        } else if error_msg.contains("already known") {
+            Some(Self::AlreadyKnown)
+        } else {
+            None
+        }
+    }
+}
+
+/// A transaction we sent that has not been seen mined yet
+#[derive(Debug, Clone, Copy)]
+pub struct InFlightTx {
+    pub tx_hash: H256,
+    pub fees: Eip1559Fees,
+}
+
+struct NonceState {
+    next_nonce: U256,
+    in_flight: BTreeMap<U256, InFlightTx>,
+}
+
+/// Hands out nonces locally so several transactions can be in flight at once, instead of
+/// asking the node for the count on every send
+pub struct NonceManager<M> {
+    provider: Arc<M>,
+    address: Address,
+    state: Mutex<NonceState>,
+}
+
+impl<M: Middleware> NonceManager<M> {
+    pub async fn init(provider: Arc<M>, address: Address) -> Result<Self, M::Error> {
+        let next_nonce = pending_transaction_count(&provider, address).await?;
+        debug!("Nonce manager for {:?} starting at {}", address, next_nonce);
+        Ok(Self {
+            provider: provider,
+            address: address,
+            state: Mutex::new(NonceState {
+                next_nonce: next_nonce,
+                in_flight: BTreeMap::new(),
+            }),
+        })
+    }
+
+    pub fn address(&self) -> Address {
+        self.address
+    }
+
+    /// Reserves the next unused nonce
+    pub async fn next_nonce(&self) -> U256 {
+        let mut state = self.state.lock().await;
+        let nonce = state.next_nonce;
+        state.next_nonce += U256::one();
+        nonce
+    }
+
+    pub async fn record_sent(&self, nonce: U256, tx_hash: H256, fees: Eip1559Fees) {
+        self.state.lock().await.in_flight.insert(
+            nonce,
+            InFlightTx {
+                tx_hash: tx_hash,
+                fees: fees,
+            },
+        );
+    }
+
+    pub async fn in_flight(&self) -> Vec<(U256, InFlightTx)> {
+        let state = self.state.lock().await;
+        state
+            .in_flight
+            .iter()
+            .map(|(nonce, tx)| (*nonce, *tx))
+            .collect()
+    }
+
+    /// Fees to use for a same-nonce replacement of the transaction in flight at `nonce`:
+    /// `fees`, raised to the minimum bump over the one being replaced. None if nothing is in
+    /// flight at that nonce.
+    pub async fn replacement_fees(&self, nonce: U256, fees: Eip1559Fees) -> Option<Eip1559Fees> {
+        let state = self.state.lock().await;
+        state
+            .in_flight
+            .get(&nonce)
+            .map(|in_flight| fees.max(in_flight.fees.bumped()))
+    }
+
+    /// Gives back a reserved nonce whose transaction never reached the mempool. Only the most
+    /// recently reserved nonce can be handed out again, returns false if `nonce` was not it
+    /// and is left as a gap for `reconcile` to repair.
+    pub async fn release(&self, nonce: U256) -> bool {
+        let mut state = self.state.lock().await;
+        if nonce + 1 == state.next_nonce && !state.in_flight.contains_key(&nonce) {
+            state.next_nonce = nonce;
+            return true;
+        }
+        false
+    }
+
+    /// Forgets transactions below the account's mined nonce
+    pub async fn confirmed(&self, account_nonce: U256) {
+        let mut state = self.state.lock().await;
+        state.in_flight = state.in_flight.split_off(&account_nonce);
+        if state.next_nonce < account_nonce {
+            state.next_nonce = account_nonce;
+        }
+    }
+
+    /// Forgets transactions the node reports as mined, returning the mined nonce
+    pub async fn sync_mined(&self) -> Result<U256, M::Error> {
+        let account_nonce = self
+            .provider
+            .get_transaction_count(self.address, Some(BlockId::Number(BlockNumber::Latest)))
+            .await?;
+        self.confirmed(account_nonce).await;
+        Ok(account_nonce)
+    }
+
+    /// Resets to the node's pending transaction count, dropping anything it no longer knows
+    pub async fn reconcile(&self) -> Result<U256, M::Error> {
+        let pending_count = pending_transaction_count(&self.provider, self.address).await?;
+        let mut state = self.state.lock().await;
+        if state.next_nonce != pending_count {
+            warn!(
+                "Local nonce {} out of sync with node, resetting to {}",
+                state.next_nonce, pending_count
+            );
+        }
+        state.next_nonce = pending_count;
+        // nonces at or above the pending count were dropped by the node
+        state.in_flight.retain(|nonce, _| *nonce < pending_count);
+        Ok(pending_count)
+    }
+
+    /// Updates local state after sending with `nonce` failed with `error_msg`
+    pub async fn handle_send_error(&self, nonce: U256, error_msg: &str) -> Option<NonceError> {
+        let nonce_error = NonceError::classify(error_msg);
+        match nonce_error {
+            Some(NonceError::TooLow) | Some(NonceError::TooHigh) => self.try_reconcile().await,
+            // the original transaction at this nonce is still in flight
+            Some(NonceError::ReplacementUnderpriced) | Some(NonceError::AlreadyKnown) => {}
+            None => {
+                // later nonces wait in the node's queued pool behind the gap without any
+                // error being raised, so it has to be repaired here
+                if !self.release(nonce).await {
+                    self.try_reconcile().await;
+                }
+            }
+        }
+        nonce_error
+    }
+
+    async fn try_reconcile(&self) {
+        if let Err(e) = self.reconcile().await {
+            warn!("Failed to reconcile nonce: {:?}", e);
+        }
+    }
+}
+
+async fn pending_transaction_count<M: Middleware>(
+    provider: &Arc<M>,
+    address: Address,
+) -> Result<U256, M::Error> {
+    provider
+        .get_transaction_count(address, Some(BlockId::Number(BlockNumber::Pending)))
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use ethers::{
+        providers::Provider,
+        types::{Address, H256, U256},
+    };
+
+    use super::{NonceError, NonceManager};
+    use crate::execution::builder::Eip1559Fees;
+
+    #[test]
+    fn test_classify_nonce_errors() {
+        assert_eq!(
+            NonceError::classify("(code: -32000, message: nonce too low, data: None)"),
+            Some(NonceError::TooLow)
+        );
+        assert_eq!(
+            NonceError::classify("replacement transaction underpriced"),
+            Some(NonceError::ReplacementUnderpriced)
+        );
+        assert_eq!(NonceError::classify("execution reverted: a"), None);
+    }
+
+    #[tokio::test]
+    async fn test_nonce_manager() {
+        let (provider, mock) = Provider::mocked();
+        mock.push(U256::from(7)).unwrap();
+        let nonce_manager = NonceManager::init(Arc::new(provider), Address::zero())
+            .await
+            .unwrap();
+
+        assert_eq!(nonce_manager.next_nonce().await, U256::from(7));
+        let nonce = nonce_manager.next_nonce().await;
+        assert_eq!(nonce, U256::from(8));
+
+        // failed before reaching the mempool, reused by the next send
+        nonce_manager.release(nonce).await;
+        let nonce = nonce_manager.next_nonce().await;
+        assert_eq!(nonce, U256::from(8));
+
+        let fees = Eip1559Fees::new(U256::from(100), U256::from(50_000_000_000_u64));
+        nonce_manager.record_sent(nonce, H256::zero(), fees).await;
+        let replacement = nonce_manager.replacement_fees(nonce, fees).await.unwrap();
+        assert_eq!(replacement, fees.bumped());
+        assert!(nonce_manager
+            .replacement_fees(U256::from(9), fees)
+            .await
+            .is_none());
+
+        // a send raced past us, the node already has 9 pending
+        mock.push(U256::from(10)).unwrap();
+        nonce_manager
+            .handle_send_error(U256::from(9), "nonce too low")
+            .await;
+        assert_eq!(nonce_manager.in_flight().await.len(), 1);
+        assert_eq!(nonce_manager.next_nonce().await, U256::from(10));
+
+        nonce_manager.confirmed(U256::from(9)).await;
+        assert!(nonce_manager.in_flight().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_send_error_behind_later_nonce() {
+        let (provider, mock) = Provider::mocked();
+        mock.push(U256::from(7)).unwrap();
+        let nonce_manager = NonceManager::init(Arc::new(provider), Address::zero())
+            .await
+            .unwrap();
+
+        let first = nonce_manager.next_nonce().await;
+        let second = nonce_manager.next_nonce().await;
+        let fees = Eip1559Fees::new(U256::from(100), U256::from(50_000_000_000_u64));
+        nonce_manager.record_sent(second, H256::zero(), fees).await;
+
+        // the first send timed out after the second was reserved, the node queues the
+        // second behind the gap and still counts 7 pending
+        mock.push(U256::from(7)).unwrap();
+        assert_eq!(
+            nonce_manager
+                .handle_send_error(first, "request timed out")
+                .await,
+            None
+        );
+        assert_eq!(nonce_manager.next_nonce().await, first);
+    }
+}
@@ -0,0 +1,261 @@
+use std::{collections::HashMap, sync::Arc};
+
+use ethers::{
+    providers::Middleware,
+    types::{Address, BlockId, BlockNumber, TransactionReceipt, H256, U256, U64},
+};
+use log::{debug, warn};
+use tokio::sync::{
+    mpsc::{self, UnboundedReceiver, UnboundedSender},
+    Mutex,
+};
+
+// blocks without a receipt before a transaction the node no longer knows is given up on
+const DROP_AFTER_BLOCKS: u64 = 20;
+
+#[derive(Debug, Clone)]
+pub enum TxStatus {
+    Mined(TransactionReceipt),
+    Reverted(TransactionReceipt),
+    /// replaced by another transaction with the same nonce, or evicted from the mempool
+    Dropped,
+}
+
+/// A watched nonce once its fate is known. `tx_hash` and `context` are those of the
+/// transaction that was mined at the nonce, or of the latest one sent if none of ours was.
+#[derive(Debug, Clone)]
+pub struct ResolvedTx<T> {
+    pub tx_hash: H256,
+    pub nonce: U256,
+    pub context: T,
+    pub status: TxStatus,
+}
+
+/// Every transaction sent at one nonce, in the order they were sent
+struct WatchedNonce<T> {
+    sends: Vec<(H256, T)>,
+    sent_block: Option<u64>,
+}
+
+/// Resolves sent nonces to mined / reverted / dropped in the background, once per block,
+/// and reports them on a channel so the sender never waits on confirmations. Replacements
+/// and cancels are watched at the nonce of the transaction they replace, so the nonce
+/// resolves to whichever of them is mined.
+pub struct ReceiptTracker<M, T> {
+    provider: Arc<M>,
+    address: Address,
+    watched: Mutex<HashMap<U256, WatchedNonce<T>>>,
+    resolved: UnboundedSender<ResolvedTx<T>>,
+}
+
+impl<M: Middleware, T: Send> ReceiptTracker<M, T> {
+    pub fn new(provider: Arc<M>, address: Address) -> (Self, UnboundedReceiver<ResolvedTx<T>>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let tracker = Self {
+            provider: provider,
+            address: address,
+            watched: Mutex::new(HashMap::new()),
+            resolved: sender,
+        };
+        (tracker, receiver)
+    }
+
+    /// Watches a transaction sent at `nonce`, alongside any sent at that nonce before it
+    pub async fn watch(&self, tx_hash: H256, nonce: U256, context: T) {
+        let mut watched = self.watched.lock().await;
+        let watched_nonce = watched.entry(nonce).or_insert_with(|| WatchedNonce {
+            sends: Vec::new(),
+            sent_block: None,
+        });
+        watched_nonce.sends.push((tx_hash, context));
+        // the latest send gets the full wait before the nonce is given up on
+        watched_nonce.sent_block = None;
+    }
+
+    pub async fn watching(&self) -> usize {
+        self.watched.lock().await.len()
+    }
+
+    /// Checks every watched nonce against the chain at `block_number`
+    pub async fn on_block(&self, block_number: u64) {
+        let account_nonce = match self
+            .provider
+            .get_transaction_count(self.address, Some(BlockId::Number(BlockNumber::Latest)))
+            .await
+        {
+            Ok(account_nonce) => account_nonce,
+            Err(e) => {
+                warn!("Failed to fetch account nonce: {:?}", e);
+                return;
+            }
+        };
+
+        // check without holding the lock, so sends can keep registering transactions
+        let pending: Vec<(U256, Vec<H256>, Option<u64>)> = self
+            .watched
+            .lock()
+            .await
+            .iter()
+            .map(|(nonce, watched)| {
+                let tx_hashes = watched.sends.iter().map(|(tx_hash, _)| *tx_hash).collect();
+                (*nonce, tx_hashes, watched.sent_block)
+            })
+            .collect();
+        for (nonce, tx_hashes, sent_block) in pending {
+            let resolved = self
+                .check(&tx_hashes, nonce, sent_block, account_nonce, block_number)
+                .await;
+            let mut watched = self.watched.lock().await;
+            let (tx_hash, status) = match resolved {
+                Ok(Some(resolved)) => resolved,
+                Ok(None) => {
+                    if let Some(watched_nonce) = watched.get_mut(&nonce) {
+                        watched_nonce.sent_block.get_or_insert(block_number);
+                    }
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Failed to check transactions at nonce {}: {:?}", nonce, e);
+                    continue;
+                }
+            };
+
+            if let Some(watched_nonce) = watched.remove(&nonce) {
+                debug!("Nonce {} resolved by {:?}: {:?}", nonce, tx_hash, status);
+                let context = watched_nonce
+                    .sends
+                    .into_iter()
+                    .find(|(sent_hash, _)| *sent_hash == tx_hash)
+                    .map(|(_, context)| context);
+                if let Some(context) = context {
+                    let _ = self.resolved.send(ResolvedTx {
+                        tx_hash: tx_hash,
+                        nonce: nonce,
+                        context: context,
+                        status: status,
+                    });
+                }
+            }
+        }
+    }
+
+    /// The transaction mined at `nonce` out of `tx_hashes` and its status, or the latest one
+    /// sent as dropped once the nonce was used by something else or the node forgot them all
+    async fn check(
+        &self,
+        tx_hashes: &[H256],
+        nonce: U256,
+        sent_block: Option<u64>,
+        account_nonce: U256,
+        block_number: u64,
+    ) -> Result<Option<(H256, TxStatus)>, M::Error> {
+        for tx_hash in tx_hashes {
+            if let Some(receipt) = self.provider.get_transaction_receipt(*tx_hash).await? {
+                if receipt.status == Some(U64::one()) {
+                    return Ok(Some((*tx_hash, TxStatus::Mined(receipt))));
+                }
+                return Ok(Some((*tx_hash, TxStatus::Reverted(receipt))));
+            }
+        }
+
+        let latest = match tx_hashes.last() {
+            Some(latest) => *latest,
+            None => return Ok(None),
+        };
+        // the nonce was used by something else
+        if nonce < account_nonce {
+            return Ok(Some((latest, TxStatus::Dropped)));
+        }
+        let waited = block_number.saturating_sub(sent_block.unwrap_or(block_number));
+        if waited < DROP_AFTER_BLOCKS {
+            return Ok(None);
+        }
+        for tx_hash in tx_hashes {
+            if self.provider.get_transaction(*tx_hash).await?.is_some() {
+                return Ok(None);
+            }
+        }
+        Ok(Some((latest, TxStatus::Dropped)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use ethers::{
+        providers::Provider,
+        types::{Address, Transaction, TransactionReceipt, H256, U256, U64},
+    };
+
+    use super::{ReceiptTracker, TxStatus, DROP_AFTER_BLOCKS};
+
+    #[tokio::test]
+    async fn test_receipt_tracker() {
+        let (provider, mock) = Provider::mocked();
+        let (tracker, mut resolved) = ReceiptTracker::new(Arc::new(provider), Address::zero());
+        let tx_hash = H256::from_low_u64_be(1);
+        tracker.watch(tx_hash, U256::from(5), "route").await;
+
+        // responses are popped from the back: nonce count first, then the receipt
+        mock.push::<Option<TransactionReceipt>, _>(None).unwrap();
+        mock.push(U256::from(5)).unwrap();
+        tracker.on_block(100).await;
+        assert_eq!(tracker.watching().await, 1);
+        assert!(resolved.try_recv().is_err());
+
+        let receipt = TransactionReceipt {
+            transaction_hash: tx_hash,
+            status: Some(U64::zero()),
+            ..Default::default()
+        };
+        mock.push(Some(receipt)).unwrap();
+        mock.push(U256::from(6)).unwrap();
+        tracker.on_block(101).await;
+        let resolved_tx = resolved.try_recv().unwrap();
+        assert_eq!(resolved_tx.context, "route");
+        assert!(matches!(resolved_tx.status, TxStatus::Reverted(_)));
+        assert_eq!(tracker.watching().await, 0);
+
+        // never mined and forgotten by the node
+        tracker.watch(tx_hash, U256::from(6), "route").await;
+        mock.push::<Option<TransactionReceipt>, _>(None).unwrap();
+        mock.push(U256::from(6)).unwrap();
+        tracker.on_block(200).await;
+        mock.push::<Option<Transaction>, _>(None).unwrap();
+        mock.push::<Option<TransactionReceipt>, _>(None).unwrap();
+        mock.push(U256::from(6)).unwrap();
+        tracker.on_block(200 + DROP_AFTER_BLOCKS).await;
+        assert!(matches!(
+            resolved.try_recv().unwrap().status,
+            TxStatus::Dropped
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_replacement_resolves_nonce() {
+        let (provider, mock) = Provider::mocked();
+        let (tracker, mut resolved) = ReceiptTracker::new(Arc::new(provider), Address::zero());
+        let (original, cancel) = (H256::from_low_u64_be(1), H256::from_low_u64_be(2));
+        tracker.watch(original, U256::from(7), "route").await;
+        tracker.watch(cancel, U256::from(7), "cancel").await;
+        assert_eq!(tracker.watching().await, 1);
+
+        // nonce count, then the receipts in the order the transactions were sent
+        let receipt = TransactionReceipt {
+            transaction_hash: cancel,
+            status: Some(U64::one()),
+            ..Default::default()
+        };
+        mock.push(Some(receipt)).unwrap();
+        mock.push::<Option<TransactionReceipt>, _>(None).unwrap();
+        mock.push(U256::from(8)).unwrap();
+        tracker.on_block(100).await;
+
+        let resolved_tx = resolved.try_recv().unwrap();
+        assert_eq!(resolved_tx.tx_hash, cancel);
+        assert_eq!(resolved_tx.context, "cancel");
+        assert!(matches!(resolved_tx.status, TxStatus::Mined(_)));
+        assert_eq!(tracker.watching().await, 0);
+    }
+}
@@ -0,0 +1,282 @@
+use ethers::{
+    abi::{self, ParamType, Token},
+    providers::Middleware,
+    types::{
+        transaction::eip2718::TypedTransaction, BlockId, BlockNumber, TransactionReceipt,
+        TransactionRequest, U256,
+    },
+    utils::hex,
+};
+use thiserror::Error;
+
+/// Selector of solidity's `Error(string)`, which `require(cond, "msg")` reverts with
+const ERROR_SELECTOR: [u8; 4] = [0x08, 0xc3, 0x79, 0xa0];
+/// Selector of solidity's `Panic(uint256)`, raised on overflows, bad array indexes etc
+const PANIC_SELECTOR: [u8; 4] = [0x4e, 0x48, 0x7b, 0x71];
+
+/// Why a call into the Flashloan or Liquidations contracts reverted, including the errors
+/// of the Balancer vault and Uniswap routers they call into
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum RevertError {
+    /// Flashloan "a": the route returned less than was borrowed
+    #[error("unprofitable after execution")]
+    Unprofitable,
+    /// Flashloan "b": mined after the block the trade was priced for
+    #[error("target block expired")]
+    TargetBlockExpired,
+    /// Flashloan "c": a router could not be approved to spend the tokens
+    #[error("token approval failed")]
+    ApproveFailed,
+    /// Liquidations: the flashloan callback was not initiated by us
+    #[error("flashloan callback denied")]
+    FlashLoanDenied,
+    /// Balancer's `BAL#` error codes
+    #[error("balancer error BAL#{code:03}{}", balancer_error_name(*.code).map(|name| format!(" ({name})")).unwrap_or_default())]
+    Balancer { code: u16 },
+    /// A Uniswap TransferHelper or ERC20 transfer failed, usually for lack of balance
+    #[error("token transfer failed: {0}")]
+    TransferFailed(String),
+    /// The router's minimum output check failed
+    #[error("slippage exceeded: {0}")]
+    SlippageExceeded(String),
+    #[error("router deadline passed: {0}")]
+    DeadlinePassed(String),
+    #[error("panic code {0:#x}")]
+    Panic(U256),
+    #[error("reverted without a reason")]
+    Empty,
+    #[error("reverted: {0}")]
+    Other(String),
+}
+
+impl RevertError {
+    /// Maps the message of an `Error(string)` revert
+    pub fn from_reason(reason: &str) -> Self {
+        let reason = reason.trim();
+        match reason {
+            "a" => return RevertError::Unprofitable,
+            "b" => return RevertError::TargetBlockExpired,
+            "c" => return RevertError::ApproveFailed,
+            "HANDLE_FLASH_DENIED" => return RevertError::FlashLoanDenied,
+            "" => return RevertError::Empty,
+            // uniswap v3 TransferHelper
+            "STF" | "ST" | "SA" | "STE" => return RevertError::TransferFailed(reason.to_string()),
+            "Too little received" | "Too much requested" => {
+                return RevertError::SlippageExceeded(reason.to_string())
+            }
+            "Transaction too old" => return RevertError::DeadlinePassed(reason.to_string()),
+            _ => {}
+        }
+
+        if let Some(code) = reason.strip_prefix("BAL#") {
+            if let Ok(code) = code.parse() {
+                return RevertError::Balancer { code: code };
+            }
+        }
+        if reason.contains("INSUFFICIENT_OUTPUT_AMOUNT") {
+            return RevertError::SlippageExceeded(reason.to_string());
+        }
+        if reason.ends_with("EXPIRED") {
+            return RevertError::DeadlinePassed(reason.to_string());
+        }
+        if reason.contains("TRANSFER_FAILED")
+            || reason.contains("TRANSFER_FROM_FAILED")
+            || reason.contains("transfer amount exceeds")
+        {
+            return RevertError::TransferFailed(reason.to_string());
+        }
+        RevertError::Other(reason.to_string())
+    }
+
+    /// Decodes raw revert data, as returned by `eth_call` or a trace's output
+    pub fn decode(output: &[u8]) -> Self {
+        if output.is_empty() {
+            return RevertError::Empty;
+        }
+        decode_builtin(output)
+            .unwrap_or_else(|| RevertError::Other(format!("0x{}", hex::encode(output))))
+    }
+
+    /// Picks the revert out of a node's error message, e.g. a failed `eth_estimateGas` or
+    /// `eth_call`. None if the error was not a revert.
+    pub fn from_error_message(error_msg: &str) -> Option<Self> {
+        // revert data is the most precise, when the node includes it
+        if let Some(start) = error_msg
+            .find("0x08c379a0")
+            .or(error_msg.find("0x4e487b71"))
+        {
+            let data: String = error_msg[start + 2..]
+                .chars()
+                .take_while(|c| c.is_ascii_hexdigit())
+                .collect();
+            // nodes sometimes cut the data short
+            if let Some(error) = hex::decode(&data).ok().as_deref().and_then(decode_builtin) {
+                return Some(error);
+            }
+        }
+
+        let start = error_msg.find("execution reverted")?;
+        let rest = &error_msg[start + "execution reverted".len()..];
+        match rest.strip_prefix(':') {
+            Some(reason) => {
+                let end = reason.find(", data:").unwrap_or(reason.len());
+                Some(RevertError::from_reason(
+                    reason[..end].trim_end_matches(')'),
+                ))
+            }
+            None => Some(RevertError::Empty),
+        }
+    }
+}
+
+/// Recovers why a mined transaction reverted by replaying it with `eth_call` on top of
+/// its parent block. This misses state changes made earlier in the same block, so the
+/// replay can succeed even though the transaction failed, in which case this returns None.
+pub async fn receipt_revert_reason<M: Middleware>(
+    provider: &M,
+    receipt: &TransactionReceipt,
+) -> Result<Option<RevertError>, M::Error> {
+    let tx = match provider.get_transaction(receipt.transaction_hash).await? {
+        Some(tx) => tx,
+        None => return Ok(None),
+    };
+    let block_number = match receipt.block_number {
+        Some(block_number) => block_number.as_u64().saturating_sub(1),
+        None => return Ok(None),
+    };
+    let mut request = TransactionRequest::new()
+        .from(tx.from)
+        .data(tx.input)
+        .value(tx.value)
+        .gas(tx.gas);
+    request.to = tx.to.map(Into::into);
+    let call: TypedTransaction = request.into();
+
+    let block = BlockId::Number(BlockNumber::Number(block_number.into()));
+    match provider.call(&call, Some(block)).await {
+        Ok(_) => Ok(None),
+        Err(e) => Ok(RevertError::from_error_message(&e.to_string())),
+    }
+}
+
+/// Decodes solidity's `Error(string)` and `Panic(uint256)` reverts
+fn decode_builtin(output: &[u8]) -> Option<RevertError> {
+    if output.len() < 4 {
+        return None;
+    }
+    if output[..4] == ERROR_SELECTOR {
+        if let Token::String(reason) = abi::decode(&[ParamType::String], &output[4..])
+            .ok()?
+            .pop()?
+        {
+            return Some(RevertError::from_reason(&reason));
+        }
+    }
+    if output[..4] == PANIC_SELECTOR {
+        if let Token::Uint(code) = abi::decode(&[ParamType::Uint(256)], &output[4..])
+            .ok()?
+            .pop()?
+        {
+            return Some(RevertError::Panic(code));
+        }
+    }
+    None
+}
+
+/// Name of the balancer errors our flashloans can run into
+fn balancer_error_name(code: u16) -> Option<&'static str> {
+    match code {
+        515 => Some("INVALID_POST_LOAN_BALANCE"),
+        528 => Some("INSUFFICIENT_FLASH_LOAN_BALANCE"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::{
+        abi::{self, Token},
+        types::U256,
+    };
+
+    use super::{RevertError, ERROR_SELECTOR, PANIC_SELECTOR};
+
+    fn error_data(reason: &str) -> Vec<u8> {
+        [
+            &ERROR_SELECTOR[..],
+            &abi::encode(&[Token::String(reason.to_string())]),
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn test_decode_revert_data() {
+        assert_eq!(
+            RevertError::decode(&error_data("a")),
+            RevertError::Unprofitable
+        );
+        assert_eq!(
+            RevertError::decode(&error_data("b")),
+            RevertError::TargetBlockExpired
+        );
+        assert_eq!(
+            RevertError::decode(&error_data("BAL#528")),
+            RevertError::Balancer { code: 528 }
+        );
+        assert_eq!(
+            RevertError::decode(&error_data("STF")),
+            RevertError::TransferFailed("STF".to_string())
+        );
+        assert_eq!(
+            RevertError::decode(&error_data("UniswapV2Router: INSUFFICIENT_OUTPUT_AMOUNT")),
+            RevertError::SlippageExceeded(
+                "UniswapV2Router: INSUFFICIENT_OUTPUT_AMOUNT".to_string()
+            )
+        );
+        let panic = [
+            &PANIC_SELECTOR[..],
+            &abi::encode(&[Token::Uint(U256::from(0x11))]),
+        ]
+        .concat();
+        assert_eq!(
+            RevertError::decode(&panic),
+            RevertError::Panic(U256::from(0x11))
+        );
+        assert_eq!(RevertError::decode(&[]), RevertError::Empty);
+
+        assert_eq!(
+            RevertError::Balancer { code: 528 }.to_string(),
+            "balancer error BAL#528 (INSUFFICIENT_FLASH_LOAN_BALANCE)"
+        );
+        assert_eq!(
+            RevertError::Unprofitable.to_string(),
+            "unprofitable after execution"
+        );
+    }
+
+    #[test]
+    fn test_revert_from_error_message() {
+        assert_eq!(
+            RevertError::from_error_message(
+                "(code: 3, message: execution reverted: b, data: Some(String(\"0x08c379a0\")))"
+            ),
+            Some(RevertError::TargetBlockExpired)
+        );
+        let data: String = error_data("c")
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        assert_eq!(
+            RevertError::from_error_message(&format!(
+                "(code: 3, message: execution reverted, data: Some(String(\"0x{}\")))",
+                data
+            )),
+            Some(RevertError::ApproveFailed)
+        );
+        assert_eq!(
+            RevertError::from_error_message("execution reverted"),
+            Some(RevertError::Empty)
+        );
+        assert_eq!(RevertError::from_error_message("nonce too low"), None);
+    }
+}
@@ -0,0 +1,303 @@
+use ethers::{
+    providers::{Middleware, ProviderError},
+    types::{transaction::eip2718::TypedTransaction, Address, BlockNumber, H256, U256},
+    utils::{self, keccak256},
+};
+use lazy_static::lazy_static;
+use thiserror::Error;
+
+use super::revert::RevertError;
+use crate::utils::serialize_structs::{BlockTraceResult, TraceConfig, TracerConfig};
+
+lazy_static! {
+    pub(crate) static ref TRANSFER_TOPIC: H256 =
+        H256::from(keccak256("Transfer(address,address,uint256)"));
+}
+
+/// Outcome of running a transaction against pending state without sending it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Simulation {
+    pub gas_used: U256,
+    /// amount of the profit token transferred from the contract to the sender, None when
+    /// the node can't trace and only whether the transaction reverts is known
+    pub profit: Option<U256>,
+}
+
+#[derive(Debug, Error)]
+pub enum SimulationError {
+    #[error("simulation reverted: {reason}")]
+    Reverted { reason: RevertError, gas_used: U256 },
+    #[error(transparent)]
+    Provider(#[from] ProviderError),
+}
+
+/// Traces `tx` (which must have `from` set) with `debug_traceCall` on top of the pending
+/// block. The profit is read from the `profit_token` transfers the called contract makes
+/// back to the sender. Nodes without the `debug` namespace get an `eth_call` instead, see
+/// `call_pending`.
+pub async fn simulate<M: Middleware>(
+    client: &M,
+    tx: &TypedTransaction,
+    profit_token: Address,
+) -> Result<Simulation, SimulationError> {
+    let config = TraceConfig {
+        disable_storage: true,
+        disable_stack: true,
+        enable_memory: false,
+        enable_return_data: true,
+        tracer: "callTracer".to_string(),
+        tracer_config: Some(TracerConfig {
+            only_top_call: false,
+            with_log: true,
+        }),
+    };
+    let trace: Result<BlockTraceResult, ProviderError> = client
+        .provider()
+        .request(
+            "debug_traceCall",
+            [
+                utils::serialize(tx),
+                utils::serialize(&"pending"),
+                utils::serialize(&config),
+            ],
+        )
+        .await;
+    match trace {
+        Ok(trace) => parse_trace(&trace, profit_token),
+        Err(e) if is_unsupported_method(&e.to_string()) => call_pending(client, tx).await,
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Runs `tx` with `eth_call` on the pending block, then estimates its gas. The profit is
+/// unknown, and so is the gas of a revert, which is reported as the transaction's limit.
+async fn call_pending<M: Middleware>(
+    client: &M,
+    tx: &TypedTransaction,
+) -> Result<Simulation, SimulationError> {
+    let pending = Some(BlockNumber::Pending.into());
+    let revert = |e: ProviderError| match RevertError::from_error_message(&e.to_string()) {
+        Some(reason) => SimulationError::Reverted {
+            reason: reason,
+            gas_used: tx.gas().copied().unwrap_or_default(),
+        },
+        None => SimulationError::Provider(e),
+    };
+    client.provider().call(tx, pending).await.map_err(revert)?;
+    let gas_used = client
+        .provider()
+        .estimate_gas(tx, pending)
+        .await
+        .map_err(revert)?;
+    Ok(Simulation {
+        gas_used: gas_used,
+        profit: None,
+    })
+}
+
+/// Whether a node turned a request down because it does not serve that method at all
+fn is_unsupported_method(error_msg: &str) -> bool {
+    let error_msg = error_msg.to_lowercase();
+    [
+        "method not found",
+        "does not exist",
+        "not available",
+        "unsupported method",
+    ]
+    .iter()
+    .any(|pattern| error_msg.contains(pattern))
+}
+
+fn parse_trace(
+    trace: &BlockTraceResult,
+    profit_token: Address,
+) -> Result<Simulation, SimulationError> {
+    if let Some(error) = &trace.error {
+        let reason = match (&trace.revert_reason, &trace.output) {
+            (Some(reason), _) => RevertError::from_reason(reason),
+            (None, Some(output)) if !output.is_empty() => RevertError::decode(output),
+            _ if error == "execution reverted" => RevertError::Empty,
+            // out of gas, invalid opcode etc
+            _ => RevertError::Other(error.clone()),
+        };
+        return Err(SimulationError::Reverted {
+            reason: reason,
+            gas_used: trace.gas_used,
+        });
+    }
+
+    let mut profit = U256::zero();
+    sum_transfers(trace, profit_token, trace.to, trace.from, &mut profit);
+    Ok(Simulation {
+        gas_used: trace.gas_used,
+        profit: Some(profit),
+    })
+}
+
+/// Adds up `token` transfers from `from` to `to` emitted by calls that did not revert
+fn sum_transfers(
+    frame: &BlockTraceResult,
+    token: Address,
+    from: Address,
+    to: Address,
+    total: &mut U256,
+) {
+    if frame.error.is_some() {
+        return;
+    }
+    for log in frame.logs.iter().flatten() {
+        if log.address == token
+            && log.topics.len() == 3
+            && log.topics[0] == *TRANSFER_TOPIC
+            && log.topics[1] == H256::from(from)
+            && log.topics[2] == H256::from(to)
+        {
+            *total += U256::from_big_endian(&log.data);
+        }
+    }
+    for call in frame.calls.iter().flatten() {
+        sum_transfers(call, token, from, to, total);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::Debug;
+
+    use async_trait::async_trait;
+    use ethers::{
+        abi::{self, Token},
+        providers::{JsonRpcClient, Provider, ProviderError},
+        types::{
+            transaction::eip2718::TypedTransaction, Address, Bytes, Eip1559TransactionRequest,
+            H256, U256,
+        },
+    };
+    use serde::{de::DeserializeOwned, Serialize};
+
+    use super::{parse_trace, simulate, SimulationError, TRANSFER_TOPIC};
+    use crate::execution::revert::RevertError;
+    use crate::utils::serialize_structs::{BlockTraceResult, CallLog};
+
+    fn transfer_log(token: Address, from: Address, to: Address, amount: u64) -> CallLog {
+        CallLog {
+            address: token,
+            topics: vec![*TRANSFER_TOPIC, H256::from(from), H256::from(to)],
+            data: Bytes::from(abi::encode(&[Token::Uint(U256::from(amount))])),
+        }
+    }
+
+    #[test]
+    fn test_parse_trace() {
+        let owner = Address::from_low_u64_be(1);
+        let contract = Address::from_low_u64_be(2);
+        let vault = Address::from_low_u64_be(3);
+        let token = Address::from_low_u64_be(4);
+
+        let receive_flash_loan = BlockTraceResult {
+            from: vault,
+            to: contract,
+            calls: Some(vec![
+                BlockTraceResult {
+                    from: contract,
+                    to: token,
+                    logs: Some(vec![transfer_log(token, contract, owner, 25)]),
+                    ..Default::default()
+                },
+                BlockTraceResult {
+                    from: contract,
+                    to: token,
+                    logs: Some(vec![transfer_log(token, contract, vault, 1000)]),
+                    ..Default::default()
+                },
+            ]),
+            ..Default::default()
+        };
+        let trace = BlockTraceResult {
+            from: owner,
+            to: contract,
+            gas_used: U256::from(250_000),
+            calls: Some(vec![receive_flash_loan]),
+            ..Default::default()
+        };
+
+        let simulation = parse_trace(&trace, token).unwrap();
+        assert_eq!(simulation.profit, Some(U256::from(25)));
+        assert_eq!(simulation.gas_used, U256::from(250_000));
+
+        let reverted = BlockTraceResult {
+            error: Some("execution reverted".to_string()),
+            output: Some(Bytes::from(
+                [
+                    &[0x08, 0xc3, 0x79, 0xa0][..],
+                    &abi::encode(&[Token::String("a".to_string())]),
+                ]
+                .concat(),
+            )),
+            ..trace
+        };
+        match parse_trace(&reverted, token) {
+            Err(SimulationError::Reverted { reason, gas_used }) => {
+                assert_eq!(reason, RevertError::Unprofitable);
+                assert_eq!(gas_used, U256::from(250_000));
+            }
+            other => panic!("expected revert, got {:?}", other),
+        }
+    }
+
+    /// A node without the `debug` namespace, answering `eth_call` with `call`
+    #[derive(Debug)]
+    struct NoDebugNode {
+        call: Result<&'static str, &'static str>,
+    }
+
+    #[async_trait]
+    impl JsonRpcClient for NoDebugNode {
+        type Error = ProviderError;
+
+        async fn request<T, R>(&self, method: &str, _params: T) -> Result<R, ProviderError>
+        where
+            T: Debug + Serialize + Send + Sync,
+            R: DeserializeOwned,
+        {
+            let response = match method {
+                "debug_traceCall" => {
+                    Err("the method debug_traceCall does not exist/is not available")
+                }
+                "eth_call" => self.call,
+                "eth_estimateGas" => Ok("0x3d090"),
+                _ => Err("unexpected method"),
+            };
+            match response {
+                Ok(result) => Ok(serde_json::from_value(serde_json::json!(result)).unwrap()),
+                Err(error_msg) => Err(ProviderError::CustomError(error_msg.to_string())),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_simulate_without_debug_namespace() {
+        let tx: TypedTransaction = Eip1559TransactionRequest::new()
+            .from(Address::from_low_u64_be(1))
+            .to(Address::from_low_u64_be(2))
+            .gas(500_000)
+            .into();
+        let token = Address::from_low_u64_be(4);
+
+        let provider = Provider::new(NoDebugNode { call: Ok("0x") });
+        let simulation = simulate(&provider, &tx, token).await.unwrap();
+        assert_eq!(simulation.gas_used, U256::from(250_000));
+        assert_eq!(simulation.profit, None);
+
+        let provider = Provider::new(NoDebugNode {
+            call: Err("execution reverted: a"),
+        });
+        match simulate(&provider, &tx, token).await {
+            Err(SimulationError::Reverted { reason, gas_used }) => {
+                assert_eq!(reason, RevertError::Unprofitable);
+                assert_eq!(gas_used, U256::from(500_000));
+            }
+            other => panic!("expected revert, got {:?}", other),
+        }
+    }
+}
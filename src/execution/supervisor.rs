@@ -0,0 +1,325 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use ethers::{
+    middleware::SignerMiddleware,
+    prelude::builders::ContractCall,
+    providers::{Middleware, PubsubClient},
+    signers::Signer,
+    types::{transaction::eip2718::TypedTransaction, Eip1559TransactionRequest, H256, U256, U64},
+};
+use log::{debug, info, warn};
+use tokio::sync::Mutex;
+
+use super::{
+    broadcaster::{sign_transaction, Broadcaster},
+    builder::{Eip1559Fees, ExecutionBuilder},
+    nonce_manager::NonceManager,
+    simulation::{simulate, SimulationError},
+};
+use crate::{
+    constants::token::ERC20Token,
+    world::{Protocol, WorldState},
+};
+
+// a zero value transfer to ourselves
+const CANCEL_GAS: u64 = 21_000;
+
+/// An arbitrage transaction that was sent and has not been mined yet
+#[derive(Debug, Clone)]
+pub struct PendingTrade {
+    pub amount_in: U256,
+    pub token_path: Vec<ERC20Token>,
+    pub protocol_route: Vec<Protocol>,
+    pub tx: TypedTransaction,
+    pub fees: Eip1559Fees,
+}
+
+/// What the supervisor did with an in-flight trade. Replacements and cancels carry what
+/// was sent, so they can be watched alongside the transaction they replace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TradeAction {
+    /// still profitable as sent
+    Keep,
+    /// replaced with a repriced trade at the same nonce
+    Replace {
+        tx_hash: H256,
+        fees: Eip1559Fees,
+        protocol_route: Vec<Protocol>,
+    },
+    /// replaced with a zero value self transfer at the same nonce
+    Cancel { tx_hash: H256, fees: Eip1559Fees },
+    /// no longer profitable, but cancelling costs more than the revert
+    LetFail,
+}
+
+/// Re-scores in-flight trades after every block, replacing or cancelling the ones that
+/// would revert or lose money if they were mined as sent. Replacements and cancels are
+/// broadcast the same way as the trades they replace.
+pub struct TradeSupervisor<M> {
+    executor: Arc<ExecutionBuilder<M>>,
+    nonce_manager: Arc<NonceManager<M>>,
+    broadcaster: Arc<Broadcaster>,
+    /// (profit token, profit, transaction fees in wei) -> worth sending
+    is_profitable: fn(ERC20Token, U256, U256) -> bool,
+    trades: Mutex<BTreeMap<U256, PendingTrade>>,
+}
+
+impl<M: Middleware + 'static, S: Signer + 'static> TradeSupervisor<SignerMiddleware<M, S>> {
+    pub fn new(
+        executor: Arc<ExecutionBuilder<SignerMiddleware<M, S>>>,
+        nonce_manager: Arc<NonceManager<SignerMiddleware<M, S>>>,
+        broadcaster: Arc<Broadcaster>,
+        is_profitable: fn(ERC20Token, U256, U256) -> bool,
+    ) -> Self {
+        Self {
+            executor: executor,
+            nonce_manager: nonce_manager,
+            broadcaster: broadcaster,
+            is_profitable: is_profitable,
+            trades: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub async fn track(&self, nonce: U256, trade: PendingTrade) {
+        self.trades.lock().await.insert(nonce, trade);
+    }
+
+    pub async fn pending_trades(&self) -> usize {
+        self.trades.lock().await.len()
+    }
+
+    /// Re-scores every in-flight trade against the state after `block_number`, returning
+    /// what was done with each by nonce
+    pub async fn on_block<W: Middleware + Clone, P: PubsubClient>(
+        &self,
+        ws: &Arc<WorldState<W, P>>,
+        block_number: U64,
+        next_base_fee: U256,
+    ) -> Vec<(U256, TradeAction)> {
+        match self.nonce_manager.sync_mined().await {
+            Ok(account_nonce) => {
+                let mut trades = self.trades.lock().await;
+                *trades = trades.split_off(&account_nonce);
+            }
+            Err(e) => {
+                warn!("Failed to fetch account nonce: {:?}", e);
+                return Vec::new();
+            }
+        }
+
+        let trades: Vec<(U256, PendingTrade)> = self
+            .trades
+            .lock()
+            .await
+            .iter()
+            .map(|(nonce, trade)| (*nonce, trade.clone()))
+            .collect();
+        let mut actions = Vec::with_capacity(trades.len());
+        for (nonce, trade) in trades {
+            let action = self
+                .supervise(ws, nonce, trade, block_number, next_base_fee)
+                .await;
+            debug!("Pending trade with nonce {}: {:?}", nonce, action);
+            actions.push((nonce, action));
+        }
+        actions
+    }
+
+    async fn supervise<W: Middleware + Clone, P: PubsubClient>(
+        &self,
+        ws: &Arc<WorldState<W, P>>,
+        nonce: U256,
+        trade: PendingTrade,
+        block_number: U64,
+        next_base_fee: U256,
+    ) -> TradeAction {
+        let client = self.executor.contract().client();
+        let token = trade.token_path[0];
+        let failure_gas = match simulate(client.as_ref(), &trade.tx, token.get_address()).await {
+            Ok(simulation) => {
+                let txn_fees = simulation.gas_used * trade.fees.effective_gas_price(next_base_fee);
+                // without a trace, not reverting is all that is known
+                let profitable = match simulation.profit {
+                    Some(profit) => (self.is_profitable)(token, profit, txn_fees),
+                    None => true,
+                };
+                if profitable {
+                    return TradeAction::Keep;
+                }
+                simulation.gas_used
+            }
+            Err(SimulationError::Reverted { gas_used, .. }) => gas_used,
+            Err(e) => {
+                warn!("Failed to re-simulate pending trade: {}", e);
+                return TradeAction::Keep;
+            }
+        };
+
+        let fees = Eip1559Fees::new(next_base_fee, trade.fees.max_priority_fee_per_gas);
+        let fees = match self.nonce_manager.replacement_fees(nonce, fees).await {
+            Some(fees) => fees,
+            // already mined or dropped
+            None => return TradeAction::Keep,
+        };
+
+        if let Some((mut call, protocol_route)) = self
+            .reprice(ws, &trade, block_number, next_base_fee, fees)
+            .await
+        {
+            call.tx.set_nonce(nonce);
+            match self.send(&mut call.tx).await {
+                Ok(tx_hash) => {
+                    info!("Replaced pending trade with nonce {}", nonce);
+                    self.nonce_manager.record_sent(nonce, tx_hash, fees).await;
+                    self.track(
+                        nonce,
+                        PendingTrade {
+                            protocol_route: protocol_route.clone(),
+                            tx: call.tx.clone(),
+                            fees: fees,
+                            ..trade
+                        },
+                    )
+                    .await;
+                    return TradeAction::Replace {
+                        tx_hash: tx_hash,
+                        fees: fees,
+                        protocol_route: protocol_route,
+                    };
+                }
+                Err(e) => {
+                    self.nonce_manager.handle_send_error(nonce, &e).await;
+                    warn!("Failed to send replacement trade: {}", e);
+                    // a cancel at the same fees would be turned away for the same reason
+                    return TradeAction::LetFail;
+                }
+            }
+        }
+
+        if !cancel_is_cheaper(failure_gas, trade.fees, fees, next_base_fee) {
+            return TradeAction::LetFail;
+        }
+        let address = self.nonce_manager.address();
+        let mut cancel: TypedTransaction = Eip1559TransactionRequest::new()
+            .from(address)
+            .to(address)
+            .value(0)
+            .nonce(nonce)
+            .gas(CANCEL_GAS)
+            .max_fee_per_gas(fees.max_fee_per_gas)
+            .max_priority_fee_per_gas(fees.max_priority_fee_per_gas)
+            .into();
+        match self.send(&mut cancel).await {
+            Ok(tx_hash) => {
+                info!("Cancelled pending trade with nonce {}", nonce);
+                self.nonce_manager.record_sent(nonce, tx_hash, fees).await;
+                self.trades.lock().await.remove(&nonce);
+                TradeAction::Cancel {
+                    tx_hash: tx_hash,
+                    fees: fees,
+                }
+            }
+            Err(e) => {
+                self.nonce_manager.handle_send_error(nonce, &e).await;
+                warn!("Failed to cancel pending trade: {}", e);
+                TradeAction::LetFail
+            }
+        }
+    }
+
+    /// Signs `tx` and broadcasts it, returning its hash or why no endpoint took it
+    async fn send(&self, tx: &mut TypedTransaction) -> Result<H256, String> {
+        let client = self.executor.contract().client();
+        let raw_tx = sign_transaction(client.as_ref(), tx)
+            .await
+            .map_err(|e| e.to_string())?;
+        let broadcast = self
+            .broadcaster
+            .broadcast(raw_tx)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(broadcast.tx_hash)
+    }
+
+    /// The trade's token path at its size along today's best route, if it still pays for
+    /// itself at the replacement `fees`
+    async fn reprice<W: Middleware + Clone, P: PubsubClient>(
+        &self,
+        ws: &Arc<WorldState<W, P>>,
+        trade: &PendingTrade,
+        block_number: U64,
+        next_base_fee: U256,
+        fees: Eip1559Fees,
+    ) -> Option<(ContractCall<SignerMiddleware<M, S>, ()>, Vec<Protocol>)> {
+        let (amount_out, protocol_route) = ws
+            .clone()
+            .compute_best_route(trade.token_path.clone(), trade.amount_in)
+            .await;
+        if amount_out <= trade.amount_in {
+            return None;
+        }
+
+        let token = trade.token_path[0];
+        let mut call = self.executor.execute_arbitrage(
+            trade.amount_in,
+            &trade.token_path,
+            &protocol_route,
+            block_number + 1,
+            fees,
+        );
+        call.tx.set_from(self.nonce_manager.address());
+        let client = self.executor.contract().client();
+        let simulation = simulate(client.as_ref(), &call.tx, token.get_address())
+            .await
+            .ok()?;
+        let txn_fees = simulation.gas_used * fees.effective_gas_price(next_base_fee);
+        let profit = simulation.profit.unwrap_or(amount_out - trade.amount_in);
+        if (self.is_profitable)(token, profit, txn_fees) {
+            Some((call, protocol_route))
+        } else {
+            None
+        }
+    }
+}
+
+/// Whether a same-nonce cancel at `cancel_fees` costs less than letting a trade paying
+/// `fees` burn `failure_gas`
+fn cancel_is_cheaper(
+    failure_gas: U256,
+    fees: Eip1559Fees,
+    cancel_fees: Eip1559Fees,
+    base_fee: U256,
+) -> bool {
+    U256::from(CANCEL_GAS) * cancel_fees.effective_gas_price(base_fee)
+        < failure_gas * fees.effective_gas_price(base_fee)
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::types::U256;
+
+    use super::cancel_is_cheaper;
+    use crate::execution::builder::Eip1559Fees;
+
+    #[test]
+    fn test_cancel_is_cheaper() {
+        let base_fee = U256::from(100_000_000_000_u64);
+        let fees = Eip1559Fees::new(base_fee, U256::from(40_000_000_000_u64));
+        let cancel_fees = fees.bumped();
+
+        // reverting at the end of the route burns most of the gas
+        assert!(cancel_is_cheaper(
+            U256::from(300_000),
+            fees,
+            cancel_fees,
+            base_fee
+        ));
+        // reverting on the block check costs less than a cancel with bumped fees
+        assert!(!cancel_is_cheaper(
+            U256::from(21_500),
+            fees,
+            cancel_fees,
+            base_fee
+        ));
+    }
+}
@@ -1,6 +1,8 @@
 pub mod balancer;
 pub mod constants;
 pub mod event_monitor;
+pub mod execution;
+pub mod pending_state;
 pub mod tx_pool;
 pub mod uniswapV2;
 pub mod uniswapV3;
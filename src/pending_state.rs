@@ -0,0 +1,350 @@
+use std::{collections::HashMap, sync::Arc};
+
+use ethers::{
+    providers::{Middleware, PubsubClient},
+    types::{H256, I256, U256},
+};
+
+use crate::{
+    constants::token::ERC20Token,
+    tx_pool::swaps::{SwapAmounts, SwapIntent},
+    uniswapV2::UniswapV2Pair,
+    world::{market_index, MarketIndex, Protocol, RouteQuote, WorldState},
+};
+
+/// What applying a pending swap did to the overlay
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PendingSwapOutcome {
+    Applied {
+        amount_in: U256,
+        amount_out: U256,
+    },
+    /// the swap would revert on the overlay's reserves, e.g. on slippage, so nothing moved
+    Reverts,
+    /// the swap touches a uniswap v3 pool, an aggregator route or an untracked token
+    Unsupported,
+}
+
+/// Arbitrage on a route right after a pending swap lands
+#[derive(Debug, Clone)]
+pub struct Backrun {
+    pub tx_hash: H256,
+    /// index of the route in the routes searched
+    pub route: usize,
+    pub quote: RouteQuote,
+}
+
+/// Copy-on-write view of the world state's uniswap v2 reserves with pending swaps applied
+/// on top. A pair is copied out of the world state the first time a swap touches it, every
+/// other market is read through. Uniswap v3 pools are still quoted on-chain, so they never
+/// reflect the pending swaps.
+pub struct PendingState<M, P> {
+    world: Arc<WorldState<M, P>>,
+    pairs: HashMap<MarketIndex, UniswapV2Pair>,
+}
+
+impl<M: Middleware + Clone, P: PubsubClient> PendingState<M, P> {
+    pub fn new(world: Arc<WorldState<M, P>>) -> Self {
+        Self {
+            world: world,
+            pairs: HashMap::new(),
+        }
+    }
+
+    /// Number of pairs moved by the applied swaps
+    pub fn touched(&self) -> usize {
+        self.pairs.len()
+    }
+
+    /// Moves the reserves `swap` trades against, if it goes through on the current overlay
+    pub async fn apply_swap(&mut self, swap: &SwapIntent) -> PendingSwapOutcome {
+        if swap.protocols.is_empty() || swap.protocols.len() + 1 != swap.token_path.len() {
+            return PendingSwapOutcome::Unsupported;
+        }
+        let tokens: Option<Vec<ERC20Token>> = swap
+            .token_path
+            .iter()
+            .map(|address| self.world.token(*address))
+            .collect();
+        let tokens = match tokens {
+            Some(tokens) => tokens,
+            None => return PendingSwapOutcome::Unsupported,
+        };
+
+        let mut indexes = Vec::with_capacity(swap.protocols.len());
+        for (protocol, hop) in swap.protocols.iter().zip(tokens.windows(2)) {
+            match protocol {
+                Protocol::UniswapV2(protocol) => {
+                    indexes.push(market_index(*protocol, hop[0], hop[1]))
+                }
+                Protocol::UniswapV3 { .. } => return PendingSwapOutcome::Unsupported,
+            }
+        }
+
+        let mut pairs = HashMap::new();
+        for index in &indexes {
+            if !pairs.contains_key(index) {
+                let pair = match self.pairs.get(index) {
+                    Some(pair) => *pair,
+                    None => self.world.uniswapV2_pair(*index).await,
+                };
+                pairs.insert(*index, pair);
+            }
+        }
+
+        let outcome = apply_hops(&mut pairs, &indexes, &tokens, &swap.amounts);
+        if matches!(outcome, PendingSwapOutcome::Applied { .. }) {
+            self.pairs.extend(pairs);
+        }
+        outcome
+    }
+
+    /// `WorldState::compute_best_route` on the overlay's reserves
+    pub async fn compute_best_route(
+        &self,
+        token_path: Vec<ERC20Token>,
+        amount_in: U256,
+    ) -> (U256, Vec<Protocol>) {
+        let quote = self
+            .sweep_route(token_path, vec![amount_in])
+            .await
+            .remove(0);
+        (quote.amount_out, quote.protocols)
+    }
+
+    /// `WorldState::sweep_route` on the overlay's reserves
+    pub async fn sweep_route(
+        &self,
+        token_path: Vec<ERC20Token>,
+        amounts_in: Vec<U256>,
+    ) -> Vec<RouteQuote> {
+        self.world
+            .sweep_route_with(&self.pairs, token_path, amounts_in)
+            .await
+    }
+}
+
+/// Applies `swaps` in order on one overlay and quotes every route in `routes`, given as
+/// (token path, amount in), right after each swap that goes through. Only profitable quotes
+/// are returned, profit is before gas.
+pub async fn find_backruns<M: Middleware + Clone, P: PubsubClient>(
+    world: Arc<WorldState<M, P>>,
+    swaps: &[SwapIntent],
+    routes: &[(Vec<ERC20Token>, U256)],
+) -> Vec<Backrun> {
+    let mut state = PendingState::new(world);
+    let mut backruns = Vec::new();
+    for swap in swaps {
+        if !matches!(
+            state.apply_swap(swap).await,
+            PendingSwapOutcome::Applied { .. }
+        ) {
+            continue;
+        }
+        for (i, (token_path, amount_in)) in routes.iter().enumerate() {
+            let quote = state
+                .sweep_route(token_path.to_vec(), vec![*amount_in])
+                .await
+                .remove(0);
+            if quote.profit > I256::zero() {
+                backruns.push(Backrun {
+                    tx_hash: swap.tx_hash,
+                    route: i,
+                    quote: quote,
+                });
+            }
+        }
+    }
+    backruns
+}
+
+/// Runs a swap through the pairs at `indexes`, one per hop of `tokens`. `pairs` is only
+/// left updated if the swap would go through.
+fn apply_hops(
+    pairs: &mut HashMap<MarketIndex, UniswapV2Pair>,
+    indexes: &[MarketIndex],
+    tokens: &[ERC20Token],
+    amounts: &SwapAmounts,
+) -> PendingSwapOutcome {
+    let mut updated = pairs.clone();
+    let (amount_in, amount_out) = match amounts {
+        SwapAmounts::ExactIn {
+            amount_in,
+            min_amount_out,
+        } => {
+            let mut amount = *amount_in;
+            for (index, token_in) in indexes.iter().zip(tokens) {
+                amount = match updated.get_mut(index).unwrap().swap(amount, *token_in) {
+                    Some(amount) => amount,
+                    // amounts no real balance could cover
+                    None => return PendingSwapOutcome::Reverts,
+                };
+            }
+            if amount.is_zero() || amount < *min_amount_out {
+                return PendingSwapOutcome::Reverts;
+            }
+            (*amount_in, amount)
+        }
+        SwapAmounts::ExactOut {
+            amount_out,
+            max_amount_in,
+        } => {
+            // the router prices the hops backwards from the output against the starting reserves
+            let mut amounts = vec![*amount_out; tokens.len()];
+            for i in (0..indexes.len()).rev() {
+                let pair = updated[&indexes[i]];
+                amounts[i] = match pair.get_amounts_in(amounts[i + 1], tokens[i]) {
+                    Some(amount) => amount,
+                    None => return PendingSwapOutcome::Reverts,
+                };
+            }
+            if amounts[0] > *max_amount_in {
+                return PendingSwapOutcome::Reverts;
+            }
+            for (i, (index, token_in)) in indexes.iter().zip(tokens).enumerate() {
+                let pair = updated.get_mut(index).unwrap();
+                if pair.settle(amounts[i], amounts[i + 1], *token_in).is_none() {
+                    return PendingSwapOutcome::Reverts;
+                }
+            }
+            (amounts[0], *amount_out)
+        }
+    };
+    *pairs = updated;
+    PendingSwapOutcome::Applied {
+        amount_in: amount_in,
+        amount_out: amount_out,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use ethers::types::U256;
+
+    use super::{apply_hops, PendingSwapOutcome};
+    use crate::{
+        constants::{protocol::UniswapV2::*, token::ERC20Token::*},
+        tx_pool::swaps::SwapAmounts,
+        uniswapV2::UniswapV2Pair,
+        world::market_index,
+    };
+
+    #[test]
+    fn test_apply_hops() {
+        let mut usdc_weth = UniswapV2Pair::default();
+        usdc_weth.update_metadata(QUICKSWAP, USDC, WETH, U256::zero());
+        usdc_weth.update_reserves(U256::from(2_000_000_000), U256::from(1_000_000));
+        let mut weth_wmatic = UniswapV2Pair::default();
+        weth_wmatic.update_metadata(QUICKSWAP, WMATIC, WETH, U256::zero());
+        weth_wmatic.update_reserves(U256::from(3_000_000_000u64), U256::from(1_000_000));
+
+        let indexes = vec![
+            market_index(QUICKSWAP, USDC, WETH),
+            market_index(QUICKSWAP, WETH, WMATIC),
+        ];
+        let tokens = vec![USDC, WETH, WMATIC];
+        let pairs = HashMap::from([(indexes[0], usdc_weth), (indexes[1], weth_wmatic)]);
+
+        let weth_out = usdc_weth.get_amounts_out(U256::from(20_000_000), USDC);
+        let wmatic_out = weth_wmatic.get_amounts_out(weth_out, WETH);
+        let mut applied = pairs.clone();
+        let outcome = apply_hops(
+            &mut applied,
+            &indexes,
+            &tokens,
+            &SwapAmounts::ExactIn {
+                amount_in: U256::from(20_000_000),
+                min_amount_out: wmatic_out,
+            },
+        );
+        assert_eq!(
+            outcome,
+            PendingSwapOutcome::Applied {
+                amount_in: U256::from(20_000_000),
+                amount_out: wmatic_out,
+            }
+        );
+        // the same trade again gets a worse price
+        assert!(applied[&indexes[0]].get_amounts_out(U256::from(20_000_000), USDC) < weth_out);
+
+        // slippage check fails and leaves the reserves alone
+        let mut reverted = pairs.clone();
+        let outcome = apply_hops(
+            &mut reverted,
+            &indexes,
+            &tokens,
+            &SwapAmounts::ExactIn {
+                amount_in: U256::from(20_000_000),
+                min_amount_out: wmatic_out + 1,
+            },
+        );
+        assert_eq!(outcome, PendingSwapOutcome::Reverts);
+        assert_eq!(
+            reverted[&indexes[0]].get_amounts_out(U256::from(20_000_000), USDC),
+            weth_out
+        );
+
+        // buying exactly what the exact input swap bought costs at most the same input
+        let mut applied = pairs.clone();
+        let outcome = apply_hops(
+            &mut applied,
+            &indexes,
+            &tokens,
+            &SwapAmounts::ExactOut {
+                amount_out: wmatic_out,
+                max_amount_in: U256::from(20_000_000),
+            },
+        );
+        match outcome {
+            PendingSwapOutcome::Applied {
+                amount_in,
+                amount_out,
+            } => {
+                assert!(amount_in <= U256::from(20_000_000));
+                assert_eq!(amount_out, wmatic_out);
+            }
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        }
+        // the last pool paid out exactly what was asked for
+        let weth_in = weth_wmatic.get_amounts_in(wmatic_out, WETH).unwrap();
+        let mut settled = weth_wmatic;
+        settled.settle(weth_in, wmatic_out, WETH).unwrap();
+        assert_eq!(
+            applied[&indexes[1]].get_amounts_out(weth_out, WETH),
+            settled.get_amounts_out(weth_out, WETH)
+        );
+
+        // more than the pool holds
+        let outcome = apply_hops(
+            &mut applied,
+            &indexes,
+            &tokens,
+            &SwapAmounts::ExactOut {
+                amount_out: U256::from(3_000_000_000u64),
+                max_amount_in: U256::MAX,
+            },
+        );
+        assert_eq!(outcome, PendingSwapOutcome::Reverts);
+
+        // calldata amounts overflowing the pricing math or the pair's uint112 reserves
+        for amount_in in [U256::MAX / 2, U256::one() << 120] {
+            let mut reverted = pairs.clone();
+            let outcome = apply_hops(
+                &mut reverted,
+                &indexes,
+                &tokens,
+                &SwapAmounts::ExactIn {
+                    amount_in: amount_in,
+                    min_amount_out: U256::zero(),
+                },
+            );
+            assert_eq!(outcome, PendingSwapOutcome::Reverts);
+            assert_eq!(
+                reverted[&indexes[0]].get_amounts_out(U256::from(20_000_000), USDC),
+                weth_out
+            );
+        }
+    }
+}
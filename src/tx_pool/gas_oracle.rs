@@ -0,0 +1,244 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use ethers::types::{Block, Transaction, U256};
+
+use super::mempool::{fee_caps, PoolTx};
+use crate::execution::builder::{Eip1559Fees, MIN_PRIORITY_FEE};
+
+#[derive(Debug, Clone)]
+pub struct GasOracleConfig {
+    /// percentile of the tip distribution to pay, 0-100
+    pub percentile: f64,
+    /// age at which a sample counts half as much as a fresh one
+    pub half_life: Duration,
+    /// recent blocks whose included transactions are sampled
+    pub block_history: usize,
+    /// full blocks of base fee growth the max fee survives, 6 is about double
+    pub base_fee_headroom_blocks: u32,
+}
+
+impl Default for GasOracleConfig {
+    fn default() -> Self {
+        Self {
+            percentile: 90.0,
+            half_life: Duration::from_secs(30),
+            block_history: 20,
+            base_fee_headroom_blocks: 6,
+        }
+    }
+}
+
+struct BlockTips {
+    seen: Instant,
+    tips: Vec<U256>,
+}
+
+/// Suggests EIP-1559 fees from the effective tips of pending transactions and of those
+/// included in recent blocks, weighting samples down as they age
+pub struct GasOracle {
+    config: GasOracleConfig,
+    blocks: VecDeque<BlockTips>,
+}
+
+impl GasOracle {
+    pub fn new(config: GasOracleConfig) -> Self {
+        Self {
+            config: config,
+            blocks: VecDeque::new(),
+        }
+    }
+
+    /// Samples the tips paid in `block`, which must include full transactions
+    pub fn on_block(&mut self, block: &Block<Transaction>, now: Instant) {
+        let base_fee = match block.base_fee_per_gas {
+            Some(base_fee) => base_fee,
+            None => return,
+        };
+        let tips = block
+            .transactions
+            .iter()
+            .map(|tx| effective_tip(tx, base_fee))
+            .collect();
+        self.blocks.push_back(BlockTips {
+            seen: now,
+            tips: tips,
+        });
+        while self.blocks.len() > self.config.block_history {
+            self.blocks.pop_front();
+        }
+    }
+
+    /// Tips at each of `percentiles` (0-100) for inclusion in a block with `next_base_fee`.
+    /// Empty without any samples.
+    pub fn tip_percentiles<'a>(
+        &self,
+        mempool: impl Iterator<Item = &'a PoolTx>,
+        next_base_fee: U256,
+        percentiles: &[f64],
+        now: Instant,
+    ) -> Vec<U256> {
+        let mut samples: Vec<(U256, f64)> = Vec::new();
+        for pool_tx in mempool {
+            // priced out of the next block, it says nothing about what inclusion costs
+            if fee_caps(&pool_tx.tx).0 < next_base_fee {
+                continue;
+            }
+            samples.push((
+                effective_tip(&pool_tx.tx, next_base_fee),
+                self.weight(pool_tx.first_seen, now),
+            ));
+        }
+        for block in &self.blocks {
+            let weight = self.weight(block.seen, now);
+            samples.extend(block.tips.iter().map(|tip| (*tip, weight)));
+        }
+        weighted_percentiles(samples, percentiles)
+    }
+
+    /// Fees tipping at the configured percentile, with a max fee covering
+    /// `base_fee_headroom_blocks` of base fee growth
+    pub fn suggest<'a>(
+        &self,
+        mempool: impl Iterator<Item = &'a PoolTx>,
+        next_base_fee: U256,
+        now: Instant,
+    ) -> Eip1559Fees {
+        let tip = self
+            .tip_percentiles(mempool, next_base_fee, &[self.config.percentile], now)
+            .pop()
+            .unwrap_or_default();
+        let tip = U256::max(tip, *MIN_PRIORITY_FEE);
+        Eip1559Fees {
+            max_fee_per_gas: max_base_fee(next_base_fee, self.config.base_fee_headroom_blocks)
+                + tip,
+            max_priority_fee_per_gas: tip,
+        }
+    }
+
+    fn weight(&self, seen: Instant, now: Instant) -> f64 {
+        let age = now.saturating_duration_since(seen).as_secs_f64();
+        0.5f64.powf(age / self.config.half_life.as_secs_f64())
+    }
+}
+
+/// Tip the block producer receives from `tx` in a block with `base_fee`
+pub fn effective_tip(tx: &Transaction, base_fee: U256) -> U256 {
+    let (max_fee, priority_fee) = fee_caps(tx);
+    U256::min(priority_fee, max_fee.saturating_sub(base_fee))
+}
+
+/// Base fee after `blocks` full blocks, each raising it by an eighth
+pub fn max_base_fee(next_base_fee: U256, blocks: u32) -> U256 {
+    let mut base_fee = next_base_fee;
+    for _ in 0..blocks {
+        base_fee += U256::max(base_fee / 8, U256::one());
+    }
+    base_fee
+}
+
+fn weighted_percentiles(mut samples: Vec<(U256, f64)>, percentiles: &[f64]) -> Vec<U256> {
+    let total: f64 = samples.iter().map(|(_, weight)| weight).sum();
+    if samples.is_empty() || total <= 0.0 {
+        return Vec::new();
+    }
+    samples.sort_by_key(|(tip, _)| *tip);
+
+    percentiles
+        .iter()
+        .map(|percentile| {
+            let target = total * percentile.clamp(0.0, 100.0) / 100.0;
+            let mut cumulative = 0.0;
+            for (tip, weight) in &samples {
+                cumulative += weight;
+                if cumulative >= target {
+                    return *tip;
+                }
+            }
+            samples[samples.len() - 1].0
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use ethers::types::{Block, Transaction, U256};
+
+    use super::{effective_tip, max_base_fee, GasOracle, GasOracleConfig};
+    use crate::{execution::builder::MIN_PRIORITY_FEE, tx_pool::mempool::PoolTx};
+
+    const GWEI: u64 = 1_000_000_000;
+
+    fn tx(max_fee: u64, priority_fee: u64) -> Transaction {
+        Transaction {
+            max_fee_per_gas: Some(U256::from(max_fee * GWEI)),
+            max_priority_fee_per_gas: Some(U256::from(priority_fee * GWEI)),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_effective_tip() {
+        let base_fee = U256::from(100 * GWEI);
+        assert_eq!(effective_tip(&tx(200, 40), base_fee), U256::from(40 * GWEI));
+        // capped by the max fee
+        assert_eq!(effective_tip(&tx(120, 40), base_fee), U256::from(20 * GWEI));
+        let legacy = Transaction {
+            gas_price: Some(U256::from(150 * GWEI)),
+            ..Default::default()
+        };
+        assert_eq!(effective_tip(&legacy, base_fee), U256::from(50 * GWEI));
+
+        assert_eq!(max_base_fee(U256::from(800), 1), U256::from(900));
+        assert_eq!(max_base_fee(U256::from(800), 0), U256::from(800));
+    }
+
+    #[test]
+    fn test_suggest() {
+        let now = Instant::now();
+        let base_fee = U256::from(100 * GWEI);
+        let mut oracle = GasOracle::new(GasOracleConfig {
+            percentile: 50.0,
+            half_life: Duration::from_secs(10),
+            block_history: 1,
+            base_fee_headroom_blocks: 0,
+        });
+
+        // nothing sampled yet
+        let fees = oracle.suggest([].iter(), base_fee, now);
+        assert_eq!(fees.max_priority_fee_per_gas, *MIN_PRIORITY_FEE);
+        assert_eq!(fees.max_fee_per_gas, base_fee + *MIN_PRIORITY_FEE);
+
+        // an old block of cheap tips is outweighed by fresh pending transactions
+        let block = Block {
+            base_fee_per_gas: Some(base_fee),
+            transactions: vec![tx(300, 35), tx(300, 35), tx(300, 35)],
+            ..Default::default()
+        };
+        oracle.on_block(&block, now - Duration::from_secs(60));
+        let pending: Vec<PoolTx> = [tx(300, 80), tx(300, 90), tx(90, 500)]
+            .into_iter()
+            .map(|tx| PoolTx {
+                tx: tx,
+                first_seen: now,
+                expires_at: now + Duration::from_secs(60),
+            })
+            .collect();
+        let percentiles = oracle.tip_percentiles(pending.iter(), base_fee, &[0.0, 50.0], now);
+        assert_eq!(
+            percentiles,
+            vec![U256::from(35 * GWEI), U256::from(80 * GWEI)]
+        );
+        let fees = oracle.suggest(pending.iter(), base_fee, now);
+        assert_eq!(fees.max_priority_fee_per_gas, U256::from(80 * GWEI));
+        assert_eq!(fees.max_fee_per_gas, U256::from(180 * GWEI));
+
+        // only the latest block is kept
+        oracle.on_block(&block, now);
+        assert_eq!(oracle.blocks.len(), 1);
+    }
+}
@@ -0,0 +1,591 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+use ethers::types::{Address, Transaction, H256, U256};
+
+use crate::utils::serialize_structs::TxpoolContent;
+
+/// Minimum fee increase, in percent, for a transaction to replace another with the same nonce
+pub const PRICE_BUMP_PERCENT: u64 = 10;
+/// How long a transaction is kept without being mined. Ones still unmined after a few
+/// minutes are usually underpriced or waiting on a nonce gap that never fills.
+pub const DEFAULT_TX_TTL: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone)]
+pub struct PoolTx {
+    pub tx: Transaction,
+    pub first_seen: Instant,
+    pub expires_at: Instant,
+}
+
+/// Running totals of why transactions left the pool
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MempoolStats {
+    /// outlived their TTL
+    pub expired: u64,
+    /// mined, or their sender's on-chain nonce moved past them
+    pub nonce_passed: u64,
+    /// replaced by a same-nonce transaction paying more
+    pub replaced: u64,
+    /// evicted to stay within capacity
+    pub evicted: u64,
+    /// no longer in the node's pool when reconciling
+    pub dropped: u64,
+    /// sent by a sender quarantined for breaking block simulation
+    pub quarantined: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InsertOutcome {
+    /// executable once the sender's earlier pending transactions are mined
+    Pending,
+    /// waiting on a nonce gap to fill
+    Queued,
+    /// took the nonce of the transaction with this hash
+    Replaced(H256),
+    AlreadyKnown,
+    /// same nonce as a known transaction without the required fee bump
+    Underpriced,
+    /// the sender's account nonce is already past it
+    NonceTooLow,
+    /// the sender is excluded for breaking block simulation
+    Quarantined,
+}
+
+impl InsertOutcome {
+    pub fn is_inserted(&self) -> bool {
+        matches!(
+            self,
+            InsertOutcome::Pending | InsertOutcome::Queued | InsertOutcome::Replaced(_)
+        )
+    }
+}
+
+/// Changes made to match a snapshot of the node's pool
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Reconciliation {
+    /// transactions we missed notifications for
+    pub added: usize,
+    /// transactions the node no longer has
+    pub removed: usize,
+}
+
+/// A sender's transactions split by whether they can execute yet
+#[derive(Debug, Default)]
+struct SenderLanes {
+    /// next nonce of the account on-chain, if known
+    account_nonce: Option<U256>,
+    /// gapless run of nonces from the account nonce
+    pending: BTreeMap<U256, PoolTx>,
+    /// nonces after a gap
+    queued: BTreeMap<U256, PoolTx>,
+}
+
+impl SenderLanes {
+    fn get(&self, nonce: &U256) -> Option<&PoolTx> {
+        self.pending.get(nonce).or_else(|| self.queued.get(nonce))
+    }
+
+    fn remove(&mut self, nonce: &U256) -> Option<PoolTx> {
+        self.pending
+            .remove(nonce)
+            .or_else(|| self.queued.remove(nonce))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pending.is_empty() && self.queued.is_empty()
+    }
+
+    /// Promotes queued transactions whose gap filled and demotes pending ones behind a
+    /// new gap. Without a known account nonce the lowest nonce seen is assumed next.
+    fn rebalance(&mut self) {
+        let mut txs = std::mem::take(&mut self.pending);
+        txs.append(&mut self.queued);
+        let mut next_nonce = match self.account_nonce.or(txs.keys().next().copied()) {
+            Some(nonce) => nonce,
+            None => return,
+        };
+        for (nonce, tx) in txs {
+            if nonce == next_nonce {
+                self.pending.insert(nonce, tx);
+                next_nonce += U256::one();
+            } else {
+                self.queued.insert(nonce, tx);
+            }
+        }
+    }
+}
+
+/// Model of the node's transaction pool: per-sender pending and queued lanes ordered by
+/// nonce, with replace-by-fee. Oldest queued transactions are evicted first when full.
+pub struct Mempool {
+    capacity: usize,
+    ttl: Duration,
+    stats: MempoolStats,
+    senders: HashMap<Address, SenderLanes>,
+    by_hash: HashMap<H256, (Address, U256)>,
+}
+
+impl Mempool {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity,
+            ttl: DEFAULT_TX_TTL,
+            stats: MempoolStats::default(),
+            senders: HashMap::new(),
+            by_hash: HashMap::new(),
+        }
+    }
+
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn stats(&self) -> MempoolStats {
+        self.stats
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_hash.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_hash.is_empty()
+    }
+
+    pub fn contains(&self, tx_hash: &H256) -> bool {
+        self.by_hash.contains_key(tx_hash)
+    }
+
+    pub fn get(&self, tx_hash: &H256) -> Option<&PoolTx> {
+        let (sender, nonce) = self.by_hash.get(tx_hash)?;
+        self.senders.get(sender)?.get(nonce)
+    }
+
+    pub fn insert(&mut self, tx: Transaction, now: Instant) -> InsertOutcome {
+        if self.by_hash.contains_key(&tx.hash) {
+            return InsertOutcome::AlreadyKnown;
+        }
+        let (sender, nonce, tx_hash) = (tx.from, tx.nonce, tx.hash);
+        let lanes = self.senders.entry(sender).or_default();
+        if matches!(lanes.account_nonce, Some(account_nonce) if nonce < account_nonce) {
+            return InsertOutcome::NonceTooLow;
+        }
+
+        let mut replaced = None;
+        if let Some(existing) = lanes.get(&nonce) {
+            if !is_replacement(&existing.tx, &tx) {
+                return InsertOutcome::Underpriced;
+            }
+            replaced = Some(existing.tx.hash);
+            lanes.remove(&nonce);
+        }
+        lanes.queued.insert(
+            nonce,
+            PoolTx {
+                tx: tx,
+                first_seen: now,
+                expires_at: now + self.ttl,
+            },
+        );
+        lanes.rebalance();
+        let outcome = match replaced {
+            Some(replaced) => InsertOutcome::Replaced(replaced),
+            None if lanes.pending.contains_key(&nonce) => InsertOutcome::Pending,
+            None => InsertOutcome::Queued,
+        };
+
+        if let Some(replaced) = replaced {
+            self.by_hash.remove(&replaced);
+            self.stats.replaced += 1;
+        }
+        self.by_hash.insert(tx_hash, (sender, nonce));
+        self.enforce_capacity();
+        outcome
+    }
+
+    /// Drops a transaction without assuming it was mined, e.g. the node evicted it
+    pub fn remove(&mut self, tx_hash: &H256) -> Option<PoolTx> {
+        let (sender, nonce) = self.by_hash.remove(tx_hash)?;
+        let lanes = self.senders.get_mut(&sender)?;
+        let removed = lanes.remove(&nonce);
+        lanes.rebalance();
+        if lanes.is_empty() {
+            self.senders.remove(&sender);
+        }
+        removed
+    }
+
+    /// Handles a transaction being mined: its sender's nonce moves past it, which also
+    /// evicts any competing transactions with that nonce or lower. Returns the evicted hashes.
+    pub fn mined(&mut self, tx_hash: &H256) -> Vec<H256> {
+        match self.by_hash.get(tx_hash) {
+            Some((sender, nonce)) => self.advance_nonce(*sender, nonce + 1),
+            None => Vec::new(),
+        }
+    }
+
+    /// Sets the next on-chain nonce of `sender`, evicting the transactions it makes stale
+    pub fn advance_nonce(&mut self, sender: Address, account_nonce: U256) -> Vec<H256> {
+        let lanes = match self.senders.get_mut(&sender) {
+            Some(lanes) => lanes,
+            None => return Vec::new(),
+        };
+        if matches!(lanes.account_nonce, Some(known) if known >= account_nonce) {
+            return Vec::new();
+        }
+        lanes.account_nonce = Some(account_nonce);
+
+        let mut evicted = Vec::new();
+        for lane in [&mut lanes.pending, &mut lanes.queued] {
+            let keep = lane.split_off(&account_nonce);
+            evicted.extend(lane.values().map(|pool_tx| pool_tx.tx.hash));
+            *lane = keep;
+        }
+        lanes.rebalance();
+        if lanes.is_empty() {
+            self.senders.remove(&sender);
+        }
+        for tx_hash in &evicted {
+            self.by_hash.remove(tx_hash);
+        }
+        self.stats.nonce_passed += evicted.len() as u64;
+        evicted
+    }
+
+    /// Drops every transaction of a quarantined `sender`, returns their hashes
+    pub fn quarantine(&mut self, sender: &Address) -> Vec<H256> {
+        let lanes = match self.senders.remove(sender) {
+            Some(lanes) => lanes,
+            None => return Vec::new(),
+        };
+        let removed: Vec<H256> = lanes
+            .pending
+            .values()
+            .chain(lanes.queued.values())
+            .map(|pool_tx| pool_tx.tx.hash)
+            .collect();
+        for tx_hash in &removed {
+            self.by_hash.remove(tx_hash);
+        }
+        self.stats.quarantined += removed.len() as u64;
+        removed
+    }
+
+    /// Evicts transactions that outlived their TTL
+    pub fn expire(&mut self, now: Instant) -> Vec<H256> {
+        let expired: Vec<H256> = self
+            .iter()
+            .filter(|pool_tx| pool_tx.expires_at <= now)
+            .map(|pool_tx| pool_tx.tx.hash)
+            .collect();
+        for tx_hash in &expired {
+            self.remove(tx_hash);
+        }
+        self.stats.expired += expired.len() as u64;
+        expired
+    }
+
+    /// Next nonce of every sender with transactions in the pool: the on-chain nonce when
+    /// known, otherwise its lowest pooled nonce
+    pub fn account_nonces(&self) -> HashMap<Address, U256> {
+        self.senders
+            .iter()
+            .filter_map(|(sender, lanes)| {
+                let lowest = lanes.pending.keys().chain(lanes.queued.keys()).min();
+                Some((*sender, lanes.account_nonce.or(lowest.copied())?))
+            })
+            .collect()
+    }
+
+    /// Senders with transactions in the pool
+    pub fn senders(&self) -> impl Iterator<Item = &Address> {
+        self.senders.keys()
+    }
+
+    /// Brings the pool in line with `content`, a snapshot of the node's pool taken at
+    /// `taken_at`: adds what we missed and drops what the node no longer has. Transactions
+    /// first seen after the snapshot are kept. The lowest pending nonce of each sender in
+    /// the snapshot is its account nonce.
+    pub fn reconcile(
+        &mut self,
+        content: TxpoolContent,
+        taken_at: Instant,
+        now: Instant,
+    ) -> Reconciliation {
+        let mut reconciliation = Reconciliation::default();
+        let mut known = HashSet::new();
+        let mut account_nonces = Vec::new();
+        for (sender, txs) in content.pending {
+            if let Some(nonce) = txs.values().map(|tx| tx.nonce).min() {
+                account_nonces.push((sender, nonce));
+            }
+            for tx in txs.into_values() {
+                known.insert(tx.hash);
+                if self.insert(tx, now).is_inserted() {
+                    reconciliation.added += 1;
+                }
+            }
+        }
+        for tx in content
+            .queued
+            .into_values()
+            .flat_map(|txs| txs.into_values())
+        {
+            known.insert(tx.hash);
+            if self.insert(tx, now).is_inserted() {
+                reconciliation.added += 1;
+            }
+        }
+
+        let dropped: Vec<H256> = self
+            .iter()
+            .filter(|pool_tx| pool_tx.first_seen < taken_at && !known.contains(&pool_tx.tx.hash))
+            .map(|pool_tx| pool_tx.tx.hash)
+            .collect();
+        for tx_hash in &dropped {
+            self.remove(tx_hash);
+        }
+        self.stats.dropped += dropped.len() as u64;
+        reconciliation.removed = dropped.len();
+        for (sender, account_nonce) in account_nonces {
+            reconciliation.removed += self.advance_nonce(sender, account_nonce).len();
+        }
+        reconciliation
+    }
+
+    /// Transactions executable in nonce order per sender
+    pub fn pending(&self) -> impl Iterator<Item = &PoolTx> {
+        self.senders
+            .values()
+            .flat_map(|lanes| lanes.pending.values())
+    }
+
+    pub fn queued(&self) -> impl Iterator<Item = &PoolTx> {
+        self.senders
+            .values()
+            .flat_map(|lanes| lanes.queued.values())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &PoolTx> {
+        self.pending().chain(self.queued())
+    }
+
+    /// All transactions of `sender` ordered by nonce
+    pub fn sender_txs(&self, sender: &Address) -> Vec<&PoolTx> {
+        match self.senders.get(sender) {
+            Some(lanes) => {
+                let mut txs: Vec<&PoolTx> = lanes
+                    .pending
+                    .values()
+                    .chain(lanes.queued.values())
+                    .collect();
+                txs.sort_by_key(|pool_tx| pool_tx.tx.nonce);
+                txs
+            }
+            None => Vec::new(),
+        }
+    }
+
+    fn enforce_capacity(&mut self) {
+        while self.by_hash.len() > self.capacity {
+            // the last transaction of a lane can go without opening a gap, prefer queued ones
+            let victim = self
+                .senders
+                .values()
+                .filter_map(|lanes| match lanes.queued.values().next_back() {
+                    Some(pool_tx) => Some((false, pool_tx)),
+                    None => lanes
+                        .pending
+                        .values()
+                        .next_back()
+                        .map(|pool_tx| (true, pool_tx)),
+                })
+                .min_by_key(|(is_pending, pool_tx)| (*is_pending, pool_tx.first_seen))
+                .map(|(_, pool_tx)| pool_tx.tx.hash);
+            match victim {
+                Some(tx_hash) => {
+                    self.remove(&tx_hash);
+                    self.stats.evicted += 1;
+                }
+                None => return,
+            }
+        }
+    }
+}
+
+/// Max fee and priority fee of a transaction, both the gas price for legacy ones
+pub fn fee_caps(tx: &Transaction) -> (U256, U256) {
+    let max_fee = tx.max_fee_per_gas.or(tx.gas_price).unwrap_or_default();
+    let priority_fee = tx
+        .max_priority_fee_per_gas
+        .or(tx.gas_price)
+        .unwrap_or_default();
+    (max_fee, priority_fee)
+}
+
+/// Whether `new` bumps both fee caps of `old` by at least `PRICE_BUMP_PERCENT`
+fn is_replacement(old: &Transaction, new: &Transaction) -> bool {
+    let bumped = |fee: U256| fee * (100 + PRICE_BUMP_PERCENT) / 100;
+    let (old_max_fee, old_priority_fee) = fee_caps(old);
+    let (new_max_fee, new_priority_fee) = fee_caps(new);
+    new_max_fee >= bumped(old_max_fee) && new_priority_fee >= bumped(old_priority_fee)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use ethers::types::{Address, Transaction, H256, U256};
+
+    use super::{InsertOutcome, Mempool, MempoolStats, Reconciliation};
+    use crate::utils::serialize_structs::TxpoolContent;
+
+    fn tx(id: u64, sender: u64, nonce: u64, max_fee: u64) -> Transaction {
+        Transaction {
+            hash: H256::from_low_u64_be(id),
+            from: Address::from_low_u64_be(sender),
+            nonce: U256::from(nonce),
+            max_fee_per_gas: Some(U256::from(max_fee)),
+            max_priority_fee_per_gas: Some(U256::from(max_fee / 2)),
+            ..Default::default()
+        }
+    }
+
+    fn pending_hashes(mempool: &Mempool) -> Vec<H256> {
+        let mut hashes: Vec<H256> = mempool.pending().map(|pool_tx| pool_tx.tx.hash).collect();
+        hashes.sort();
+        hashes
+    }
+
+    #[test]
+    fn test_lanes_and_promotion() {
+        let now = Instant::now();
+        let mut mempool = Mempool::new(100);
+        assert_eq!(
+            mempool.insert(tx(1, 1, 5, 100), now),
+            InsertOutcome::Pending
+        );
+        assert_eq!(mempool.insert(tx(2, 1, 7, 100), now), InsertOutcome::Queued);
+        let evicted = mempool.advance_nonce(Address::from_low_u64_be(1), U256::from(5));
+        assert!(evicted.is_empty());
+        assert_eq!(
+            mempool.insert(tx(3, 1, 4, 100), now),
+            InsertOutcome::NonceTooLow
+        );
+        assert_eq!(mempool.queued().count(), 1);
+
+        // filling the gap promotes nonce 7
+        assert_eq!(
+            mempool.insert(tx(4, 1, 6, 100), now),
+            InsertOutcome::Pending
+        );
+        assert_eq!(mempool.queued().count(), 0);
+        assert_eq!(pending_hashes(&mempool).len(), 3);
+
+        // mining nonce 5 and 6 leaves 7 pending
+        assert_eq!(mempool.mined(&H256::from_low_u64_be(4)).len(), 2);
+        assert_eq!(pending_hashes(&mempool), vec![H256::from_low_u64_be(2)]);
+        assert_eq!(mempool.len(), 1);
+        assert_eq!(mempool.stats().nonce_passed, 2);
+    }
+
+    #[test]
+    fn test_replace_by_fee() {
+        let now = Instant::now();
+        let mut mempool = Mempool::new(100);
+        assert_eq!(
+            mempool.insert(tx(1, 1, 0, 100), now),
+            InsertOutcome::Pending
+        );
+        assert_eq!(
+            mempool.insert(tx(1, 1, 0, 100), now),
+            InsertOutcome::AlreadyKnown
+        );
+        assert_eq!(
+            mempool.insert(tx(2, 1, 0, 109), now),
+            InsertOutcome::Underpriced
+        );
+        assert_eq!(
+            mempool.insert(tx(3, 1, 0, 110), now),
+            InsertOutcome::Replaced(H256::from_low_u64_be(1))
+        );
+        assert!(!mempool.contains(&H256::from_low_u64_be(1)));
+        assert_eq!(mempool.len(), 1);
+        assert_eq!(pending_hashes(&mempool), vec![H256::from_low_u64_be(3)]);
+    }
+
+    #[test]
+    fn test_eviction() {
+        let start = Instant::now();
+        let mut mempool = Mempool::new(3).with_ttl(Duration::from_secs(10));
+        mempool.insert(tx(1, 1, 0, 100), start);
+        mempool.insert(tx(2, 1, 2, 100), start);
+        mempool.insert(tx(3, 2, 0, 100), start + Duration::from_secs(1));
+        // full: the queued transaction goes before older pending ones
+        mempool.insert(tx(4, 3, 0, 100), start + Duration::from_secs(2));
+        assert!(!mempool.contains(&H256::from_low_u64_be(2)));
+        assert_eq!(mempool.len(), 3);
+
+        let expired = mempool.expire(start + Duration::from_secs(11));
+        assert_eq!(expired.len(), 2);
+        assert_eq!(pending_hashes(&mempool), vec![H256::from_low_u64_be(4)]);
+        assert_eq!(
+            mempool.stats(),
+            MempoolStats {
+                expired: 2,
+                evicted: 1,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_reconcile() {
+        let start = Instant::now();
+        let mut mempool = Mempool::new(100);
+        // mined while we weren't looking
+        mempool.insert(tx(1, 1, 4, 100), start);
+        mempool.insert(tx(2, 1, 5, 100), start);
+        // dropped by the node
+        mempool.insert(tx(3, 2, 0, 100), start);
+        // arrived after the snapshot was taken
+        mempool.insert(tx(4, 3, 0, 100), start + Duration::from_secs(2));
+
+        let mut content = TxpoolContent::default();
+        let sender = content
+            .pending
+            .entry(Address::from_low_u64_be(1))
+            .or_default();
+        sender.insert("5".to_string(), tx(2, 1, 5, 100));
+        sender.insert("6".to_string(), tx(5, 1, 6, 100));
+        content
+            .queued
+            .entry(Address::from_low_u64_be(4))
+            .or_default()
+            .insert("3".to_string(), tx(6, 4, 3, 100));
+
+        let reconciliation = mempool.reconcile(
+            content,
+            start + Duration::from_secs(1),
+            start + Duration::from_secs(3),
+        );
+        assert_eq!(
+            reconciliation,
+            Reconciliation {
+                added: 2,
+                removed: 2
+            }
+        );
+        let mut hashes: Vec<H256> = mempool.iter().map(|pool_tx| pool_tx.tx.hash).collect();
+        hashes.sort();
+        assert_eq!(hashes, [2, 4, 5, 6].map(H256::from_low_u64_be).to_vec());
+    }
+}
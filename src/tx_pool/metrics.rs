@@ -0,0 +1,351 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Write,
+    time::{Duration, Instant},
+};
+
+use ethers::types::{Address, H256};
+use serde::Serialize;
+
+use super::mempool::InsertOutcome;
+
+/// Span arrival rates are averaged over
+pub const DEFAULT_RATE_WINDOW: Duration = Duration::from_secs(60);
+/// Inclusion latencies kept for percentiles
+const LATENCY_SAMPLES: usize = 2048;
+/// Transaction hashes remembered as seen, a few minutes of mempool traffic on polygon
+const FIRST_SEEN_CAPACITY: usize = 100_000;
+/// Senders listed in a snapshot, by arrival rate
+const TOP_SENDERS: usize = 10;
+
+/// Tracks how transactions flow through the pool: how fast they arrive, how long they wait
+/// to be mined, how many mined ones we never saw, and how often they are replaced
+pub struct MempoolMetrics {
+    window: Duration,
+    /// arrivals within the window, in arrival order
+    recent: VecDeque<(Instant, Address)>,
+    recent_by_sender: HashMap<Address, usize>,
+    arrivals: u64,
+    replacements: u64,
+    latencies: VecDeque<Duration>,
+    /// when each transaction was first seen, whether or not it stayed in the pool
+    first_seen: HashMap<H256, Instant>,
+    /// `first_seen` in insertion order, to forget the oldest once full
+    first_seen_order: VecDeque<H256>,
+    blocks: u64,
+    block_txs: u64,
+    unseen_block_txs: u64,
+    last_block: Option<BlockInclusion>,
+}
+
+/// How much of a block our pool saw coming
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct BlockInclusion {
+    pub number: u64,
+    pub txs: usize,
+    /// mined without ever being seen
+    pub unseen: usize,
+}
+
+/// Point in time copy of the metrics, serializable for dashboards
+#[derive(Debug, Clone, Serialize)]
+pub struct MetricsSnapshot {
+    /// new transactions since startup, excluding duplicate notifications
+    pub arrivals: u64,
+    /// per second, over the rate window
+    pub arrival_rate: f64,
+    /// busiest senders over the rate window, per second
+    pub top_senders: Vec<(Address, f64)>,
+    pub replacements: u64,
+    /// share of arrivals that replaced a transaction with the same nonce
+    pub replacement_share: f64,
+    /// first seen to inclusion, over the most recent samples
+    pub inclusion_latency_p50: Option<Duration>,
+    pub inclusion_latency_p90: Option<Duration>,
+    pub inclusion_latency_p99: Option<Duration>,
+    pub blocks: u64,
+    /// share of mined transactions never seen, since startup. With a node side filter the
+    /// transactions it filtered out count as unseen.
+    pub unseen_share: f64,
+    pub last_block: Option<BlockInclusion>,
+}
+
+impl MempoolMetrics {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window: window,
+            recent: VecDeque::new(),
+            recent_by_sender: HashMap::new(),
+            arrivals: 0,
+            replacements: 0,
+            latencies: VecDeque::with_capacity(LATENCY_SAMPLES),
+            first_seen: HashMap::new(),
+            first_seen_order: VecDeque::new(),
+            blocks: 0,
+            block_txs: 0,
+            unseen_block_txs: 0,
+            last_block: None,
+        }
+    }
+
+    /// Remembers when a transaction was first seen, including ones the pool turns away or
+    /// later evicts, so their inclusion is not counted as unseen
+    pub fn on_seen(&mut self, tx_hash: H256, now: Instant) {
+        if self.first_seen.contains_key(&tx_hash) {
+            return;
+        }
+        self.first_seen.insert(tx_hash, now);
+        self.first_seen_order.push_back(tx_hash);
+        while self.first_seen_order.len() > FIRST_SEEN_CAPACITY {
+            if let Some(oldest) = self.first_seen_order.pop_front() {
+                self.first_seen.remove(&oldest);
+            }
+        }
+    }
+
+    pub fn on_insert(
+        &mut self,
+        tx_hash: H256,
+        sender: Address,
+        outcome: InsertOutcome,
+        now: Instant,
+    ) {
+        self.on_seen(tx_hash, now);
+        if outcome == InsertOutcome::AlreadyKnown {
+            return;
+        }
+        self.arrivals += 1;
+        if matches!(outcome, InsertOutcome::Replaced(_)) {
+            self.replacements += 1;
+        }
+        self.recent.push_back((now, sender));
+        *self.recent_by_sender.entry(sender).or_default() += 1;
+        self.trim(now);
+    }
+
+    /// Samples first seen to inclusion times of the transactions in a block that arrived at
+    /// `now`, counting the ones never seen
+    pub fn on_block(
+        &mut self,
+        number: u64,
+        tx_hashes: impl IntoIterator<Item = H256>,
+        now: Instant,
+    ) {
+        let mut txs = 0;
+        let mut latencies = Vec::new();
+        for tx_hash in tx_hashes {
+            txs += 1;
+            if let Some(first_seen) = self.first_seen.remove(&tx_hash) {
+                latencies.push(now.saturating_duration_since(first_seen));
+            }
+        }
+        let unseen = txs - latencies.len();
+        self.blocks += 1;
+        self.block_txs += txs as u64;
+        self.unseen_block_txs += unseen as u64;
+        self.last_block = Some(BlockInclusion {
+            number: number,
+            txs: txs,
+            unseen: unseen,
+        });
+        for latency in latencies {
+            if self.latencies.len() == LATENCY_SAMPLES {
+                self.latencies.pop_front();
+            }
+            self.latencies.push_back(latency);
+        }
+    }
+
+    pub fn snapshot(&mut self, now: Instant) -> MetricsSnapshot {
+        self.trim(now);
+        let window = self.window.as_secs_f64();
+        let mut top_senders: Vec<(Address, f64)> = self
+            .recent_by_sender
+            .iter()
+            .map(|(sender, count)| (*sender, *count as f64 / window))
+            .collect();
+        top_senders.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        top_senders.truncate(TOP_SENDERS);
+
+        let mut latencies: Vec<Duration> = self.latencies.iter().copied().collect();
+        latencies.sort();
+        let percentile = |p: usize| {
+            if latencies.is_empty() {
+                return None;
+            }
+            Some(latencies[(latencies.len() - 1) * p / 100])
+        };
+
+        MetricsSnapshot {
+            arrivals: self.arrivals,
+            arrival_rate: self.recent.len() as f64 / window,
+            top_senders: top_senders,
+            replacements: self.replacements,
+            replacement_share: ratio(self.replacements, self.arrivals),
+            inclusion_latency_p50: percentile(50),
+            inclusion_latency_p90: percentile(90),
+            inclusion_latency_p99: percentile(99),
+            blocks: self.blocks,
+            unseen_share: ratio(self.unseen_block_txs, self.block_txs),
+            last_block: self.last_block,
+        }
+    }
+
+    /// Drops arrivals that fell out of the rate window
+    fn trim(&mut self, now: Instant) {
+        while let Some((seen, sender)) = self.recent.front().copied() {
+            if now.saturating_duration_since(seen) <= self.window {
+                break;
+            }
+            self.recent.pop_front();
+            if let Some(count) = self.recent_by_sender.get_mut(&sender) {
+                *count -= 1;
+                if *count == 0 {
+                    self.recent_by_sender.remove(&sender);
+                }
+            }
+        }
+    }
+}
+
+impl MetricsSnapshot {
+    /// Prometheus text exposition format
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: f64| {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} {kind}");
+            let _ = writeln!(out, "{name} {value}");
+        };
+        metric(
+            "mempool_arrivals_total",
+            "counter",
+            "New pending transactions seen",
+            self.arrivals as f64,
+        );
+        metric(
+            "mempool_arrival_rate",
+            "gauge",
+            "Pending transactions per second",
+            self.arrival_rate,
+        );
+        metric(
+            "mempool_replacements_total",
+            "counter",
+            "Pending transactions replaced by a same-nonce one",
+            self.replacements as f64,
+        );
+        metric(
+            "mempool_blocks_total",
+            "counter",
+            "Blocks observed",
+            self.blocks as f64,
+        );
+        metric(
+            "mempool_unseen_share",
+            "gauge",
+            "Share of mined transactions never seen in the mempool",
+            self.unseen_share,
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP mempool_inclusion_latency_seconds First seen to inclusion"
+        );
+        let _ = writeln!(out, "# TYPE mempool_inclusion_latency_seconds summary");
+        for (quantile, latency) in [
+            ("0.5", self.inclusion_latency_p50),
+            ("0.9", self.inclusion_latency_p90),
+            ("0.99", self.inclusion_latency_p99),
+        ] {
+            if let Some(latency) = latency {
+                let _ = writeln!(
+                    out,
+                    "mempool_inclusion_latency_seconds{{quantile=\"{quantile}\"}} {}",
+                    latency.as_secs_f64()
+                );
+            }
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP mempool_sender_arrival_rate Pending transactions per second of the busiest senders"
+        );
+        let _ = writeln!(out, "# TYPE mempool_sender_arrival_rate gauge");
+        for (sender, rate) in &self.top_senders {
+            let _ = writeln!(
+                out,
+                "mempool_sender_arrival_rate{{sender=\"{:?}\"}} {}",
+                sender, rate
+            );
+        }
+        out
+    }
+}
+
+fn ratio(numerator: u64, denominator: u64) -> f64 {
+    if denominator == 0 {
+        return 0.0;
+    }
+    numerator as f64 / denominator as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use ethers::types::{Address, H256};
+
+    use super::MempoolMetrics;
+    use crate::tx_pool::mempool::InsertOutcome;
+
+    #[test]
+    fn test_metrics() {
+        let now = Instant::now();
+        let mut metrics = MempoolMetrics::new(Duration::from_secs(10));
+        let (busy, quiet) = (Address::repeat_byte(1), Address::repeat_byte(2));
+        let hash = H256::from_low_u64_be;
+        metrics.on_insert(hash(100), quiet, InsertOutcome::Pending, now);
+        metrics.on_insert(hash(100), quiet, InsertOutcome::AlreadyKnown, now);
+        for i in 0..10 {
+            metrics.on_insert(
+                hash(i),
+                busy,
+                InsertOutcome::Pending,
+                now + Duration::from_secs(i),
+            );
+        }
+        metrics.on_insert(
+            hash(101),
+            quiet,
+            InsertOutcome::Replaced(hash(100)),
+            now + Duration::from_secs(9),
+        );
+
+        let snapshot = metrics.snapshot(now + Duration::from_secs(9));
+        assert_eq!(snapshot.arrivals, 12);
+        assert_eq!(snapshot.replacements, 1);
+        assert_eq!(snapshot.arrival_rate, 1.2);
+        assert_eq!(snapshot.top_senders, vec![(busy, 1.0), (quiet, 0.2)]);
+
+        // the first arrivals leave the window
+        let snapshot = metrics.snapshot(now + Duration::from_secs(15));
+        assert_eq!(snapshot.top_senders, vec![(busy, 0.5), (quiet, 0.1)]);
+
+        // a replaced transaction is still seen, and so is one the pool never kept
+        metrics.on_seen(hash(200), now + Duration::from_secs(7));
+        let mined_at = now + Duration::from_secs(10);
+        metrics.on_block(1, vec![hash(1), hash(100), hash(200), hash(999)], mined_at);
+        metrics.on_block(2, vec![hash(8), hash(998), hash(997), hash(996)], mined_at);
+        let snapshot = metrics.snapshot(now + Duration::from_secs(15));
+        assert_eq!(snapshot.unseen_share, 0.5);
+        assert_eq!(snapshot.last_block.unwrap().unseen, 3);
+        assert_eq!(snapshot.inclusion_latency_p50, Some(Duration::from_secs(3)));
+        assert_eq!(snapshot.inclusion_latency_p99, Some(Duration::from_secs(9)));
+
+        let exported = snapshot.to_prometheus();
+        assert!(exported.contains("mempool_arrivals_total 12\n"));
+        assert!(exported.contains("mempool_unseen_share 0.5\n"));
+        assert!(exported.contains("mempool_inclusion_latency_seconds{quantile=\"0.5\"} 3\n"));
+    }
+}
@@ -0,0 +1,510 @@
+pub mod gas_oracle;
+pub mod mempool;
+pub mod metrics;
+pub mod quarantine;
+pub mod recorder;
+pub mod subscription;
+pub mod swaps;
+
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use ethers::{
+    providers::{Middleware, ProviderError, PubsubClient, SubscriptionStream},
+    types::{Address, Block, Transaction, H256, U256},
+};
+use futures_util::StreamExt;
+use log::{debug, info, warn};
+use serde_json::value::RawValue;
+use tokio::sync::RwLock;
+
+use self::{
+    gas_oracle::{GasOracle, GasOracleConfig},
+    mempool::{InsertOutcome, Mempool, MempoolStats, Reconciliation},
+    metrics::{MempoolMetrics, MetricsSnapshot, DEFAULT_RATE_WINDOW},
+    quarantine::{Quarantine, QuarantinedSender, SimulationError, DEFAULT_QUARANTINE_BLOCKS},
+    recorder::Recorder,
+    subscription::{PendingTxFilter, PendingTxTransport},
+    swaps::{decode_swaps, SwapIntent},
+};
+use crate::{execution::builder::Eip1559Fees, utils::serialize_structs::TxpoolContent};
+
+pub struct TxPool<M> {
+    provider: Arc<M>,
+    mempool: RwLock<Mempool>,
+    gas_oracle: RwLock<GasOracle>,
+    metrics: RwLock<MempoolMetrics>,
+    quarantine: RwLock<Quarantine>,
+    filter: PendingTxFilter,
+    transport: Option<PendingTxTransport>,
+    recorder: Option<Arc<Recorder>>,
+}
+
+impl<M: Middleware + Clone> TxPool<M> {
+    pub fn init(provider: Arc<M>, capacity: usize) -> Self {
+        TxPool {
+            provider: provider.clone(),
+            mempool: RwLock::new(Mempool::new(capacity)),
+            gas_oracle: RwLock::new(GasOracle::new(GasOracleConfig::default())),
+            metrics: RwLock::new(MempoolMetrics::new(DEFAULT_RATE_WINDOW)),
+            quarantine: RwLock::new(Quarantine::new(DEFAULT_QUARANTINE_BLOCKS)),
+            filter: PendingTxFilter::default(),
+            transport: None,
+            recorder: None,
+        }
+    }
+
+    /// How long transactions are kept without being mined, see `DEFAULT_TX_TTL`
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        let capacity = self.mempool.get_mut().capacity();
+        self.mempool = RwLock::new(Mempool::new(capacity).with_ttl(ttl));
+        self
+    }
+
+    pub fn with_gas_oracle_config(mut self, config: GasOracleConfig) -> Self {
+        self.gas_oracle = RwLock::new(GasOracle::new(config));
+        self
+    }
+
+    /// Only keeps pending transactions matching `filter`, see `PendingTxFilter`
+    pub fn with_filter(mut self, filter: PendingTxFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Span arrival rates are averaged over, see `DEFAULT_RATE_WINDOW`
+    pub fn with_rate_window(mut self, window: Duration) -> Self {
+        self.metrics = RwLock::new(MempoolMetrics::new(window));
+        self
+    }
+
+    /// Blocks a sender is excluded for after breaking block simulation, see
+    /// `DEFAULT_QUARANTINE_BLOCKS`
+    pub fn with_quarantine_blocks(mut self, blocks: u64) -> Self {
+        self.quarantine = RwLock::new(Quarantine::new(blocks));
+        self
+    }
+
+    /// Streams with `transport` instead of the cheapest one the node supports
+    pub fn with_transport(mut self, transport: PendingTxTransport) -> Self {
+        self.transport = Some(transport);
+        self
+    }
+
+    /// Records every transaction inserted and every block, to be replayed with `Replay`
+    pub fn with_recorder(mut self, recorder: Arc<Recorder>) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// Pending and queued transactions
+    pub async fn get_mempool(&self) -> Vec<Transaction> {
+        let mempool = self.mempool.read().await;
+        mempool.iter().map(|pool_tx| pool_tx.tx.clone()).collect()
+    }
+
+    /// Transactions that can execute in the next block, in nonce order per sender
+    pub async fn get_pending(&self) -> Vec<Transaction> {
+        let mempool = self.mempool.read().await;
+        mempool
+            .pending()
+            .map(|pool_tx| pool_tx.tx.clone())
+            .collect()
+    }
+
+    /// Router swaps of the pending transactions
+    pub async fn get_pending_swaps(&self) -> Vec<SwapIntent> {
+        let mempool = self.mempool.read().await;
+        mempool
+            .pending()
+            .flat_map(|pool_tx| decode_swaps(&pool_tx.tx))
+            .collect()
+    }
+
+    pub async fn size(&self) -> usize {
+        self.mempool.read().await.len()
+    }
+
+    /// Next nonce of every sender in the pool, see `Mempool::account_nonces`
+    pub async fn account_nonces(&self) -> HashMap<Address, U256> {
+        self.mempool.read().await.account_nonces()
+    }
+
+    pub async fn insert(&self, txn: Transaction) -> InsertOutcome {
+        self.insert_at(txn, Instant::now()).await
+    }
+
+    /// `insert`, with `now` as the arrival time
+    pub async fn insert_at(&self, txn: Transaction, now: Instant) -> InsertOutcome {
+        if let Some(recorder) = &self.recorder {
+            recorder.record_pending(&txn, now);
+        }
+        let (tx_hash, sender) = (txn.hash, txn.from);
+        let outcome = if self.quarantine.read().await.contains(&sender) {
+            InsertOutcome::Quarantined
+        } else {
+            self.mempool.write().await.insert(txn, now)
+        };
+        self.metrics
+            .write()
+            .await
+            .on_insert(tx_hash, sender, outcome, now);
+        outcome
+    }
+
+    /// Fees to be included in a block with `next_base_fee`, see `GasOracle::suggest`
+    pub async fn suggest_fees(&self, next_base_fee: U256) -> Eip1559Fees {
+        let mempool = self.mempool.read().await;
+        let gas_oracle = self.gas_oracle.read().await;
+        gas_oracle.suggest(mempool.pending(), next_base_fee, Instant::now())
+    }
+
+    /// Effective tips of pending and recently included transactions at `percentiles`
+    pub async fn tip_percentiles(&self, next_base_fee: U256, percentiles: &[f64]) -> Vec<U256> {
+        let mempool = self.mempool.read().await;
+        let gas_oracle = self.gas_oracle.read().await;
+        gas_oracle.tip_percentiles(
+            mempool.pending(),
+            next_base_fee,
+            percentiles,
+            Instant::now(),
+        )
+    }
+
+    /// Samples the tips paid in a new block and removes its transactions, along with the
+    /// ones their senders' nonces moved past. `block` must include full transactions.
+    pub async fn on_block(&self, block: &Block<Transaction>) -> usize {
+        self.on_block_at(block, Instant::now()).await
+    }
+
+    /// `on_block`, with `now` as the time the block arrived
+    pub async fn on_block_at(&self, block: &Block<Transaction>, now: Instant) -> usize {
+        if let Some(recorder) = &self.recorder {
+            recorder.record_block(block, now);
+        }
+        self.gas_oracle.write().await.on_block(block, now);
+        let block_number = block.number.unwrap_or_default().as_u64();
+        for released in self.quarantine.write().await.on_block(block_number) {
+            debug!(
+                "Released {:?} from quarantine ({})",
+                released.sender, released.reason
+            );
+        }
+        self.metrics.write().await.on_block(
+            block_number,
+            block.transactions.iter().map(|txn| txn.hash),
+            now,
+        );
+        let mut num_removed: usize = 0;
+        let mut mempool = self.mempool.write().await;
+        for txn in &block.transactions {
+            num_removed += mempool.advance_nonce(txn.from, txn.nonce + 1).len();
+        }
+        num_removed
+    }
+
+    /// Removes transactions included in a block, along with the ones their senders' nonces
+    /// moved past
+    pub async fn remove_transactions(&self, txn_hashes: Vec<H256>) -> usize {
+        let mut num_removed: usize = 0;
+        let mut mempool = self.mempool.write().await;
+        for txn_hash in txn_hashes {
+            num_removed += mempool.mined(&txn_hash).len();
+        }
+        return num_removed;
+    }
+
+    /// Records the next on-chain nonce of `sender`, evicting its stale transactions
+    pub async fn advance_nonce(&self, sender: Address, account_nonce: U256) -> usize {
+        let mut mempool = self.mempool.write().await;
+        mempool.advance_nonce(sender, account_nonce).len()
+    }
+
+    /// Evicts transactions that outlived their TTL
+    pub async fn expire(&self) -> usize {
+        self.expire_at(Instant::now()).await
+    }
+
+    pub async fn expire_at(&self, now: Instant) -> usize {
+        self.mempool.write().await.expire(now).len()
+    }
+
+    pub async fn expire_periodically(self: Arc<TxPool<M>>, interval: Duration) {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            let num_expired = self.expire().await;
+            if num_expired > 0 {
+                debug!("Expired {} mempool txns", num_expired);
+            }
+        }
+    }
+
+    /// Checks the on-chain nonce of every sender in the pool, evicting transactions it
+    /// passed, for when mined blocks may have been missed
+    pub async fn refresh_account_nonces(&self) -> usize {
+        let senders: Vec<Address> = self.mempool.read().await.senders().copied().collect();
+        let mut account_nonces = futures_util::stream::iter(senders)
+            .map(|sender| async move {
+                let account_nonce = self.provider.get_transaction_count(sender, None).await;
+                (sender, account_nonce)
+            })
+            .buffer_unordered(16);
+
+        let mut num_removed: usize = 0;
+        while let Some((sender, account_nonce)) = account_nonces.next().await {
+            match account_nonce {
+                Ok(account_nonce) => {
+                    num_removed += self.advance_nonce(sender, account_nonce).await;
+                }
+                Err(e) => warn!("Failed to fetch nonce of {:?}: {:?}", sender, e),
+            }
+        }
+        num_removed
+    }
+
+    pub async fn refresh_account_nonces_periodically(self: Arc<TxPool<M>>, interval: Duration) {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            let num_removed = self.refresh_account_nonces().await;
+            if num_removed > 0 {
+                debug!(
+                    "Removed {} mempool txns passed by their sender's nonce",
+                    num_removed
+                );
+            }
+        }
+    }
+
+    /// Quarantines the sender behind a failed block simulation, dropping its transactions.
+    /// None if the error is not one a sender is blamed for, or the sender can't be told.
+    pub async fn quarantine_simulation_failure(
+        &self,
+        error_msg: &str,
+    ) -> Option<QuarantinedSender> {
+        let error = SimulationError::classify(error_msg)?;
+        let mut mempool = self.mempool.write().await;
+        let sender = match (error.sender, error.tx_hash) {
+            (Some(sender), _) => sender,
+            (None, Some(tx_hash)) => mempool.get(&tx_hash)?.tx.from,
+            (None, None) => return None,
+        };
+        let quarantined = self
+            .quarantine
+            .write()
+            .await
+            .quarantine(sender, error.failure);
+        let removed = mempool.quarantine(&sender);
+        warn!(
+            "Quarantined {:?} until block #{} ({}), dropped {} txns",
+            sender,
+            quarantined.until_block,
+            quarantined.reason,
+            removed.len()
+        );
+        Some(quarantined)
+    }
+
+    /// Senders currently excluded, with why
+    pub async fn quarantined(&self) -> Vec<QuarantinedSender> {
+        self.quarantine.read().await.senders().copied().collect()
+    }
+
+    /// Arrival rates, inclusion latency and how much of each block we saw coming
+    pub async fn metrics(&self) -> MetricsSnapshot {
+        self.metrics.write().await.snapshot(Instant::now())
+    }
+
+    /// Counts of transactions that expired, were replaced, or were passed by their
+    /// sender's nonce since startup
+    pub async fn stats(&self) -> MempoolStats {
+        self.mempool.read().await.stats()
+    }
+
+    /// Syncs with the node's pool via `txpool_content`. Seeds the pool at startup, and
+    /// repairs missed or stale entries when run again.
+    pub async fn reconcile(&self) -> Result<Reconciliation, ProviderError> {
+        let taken_at = Instant::now();
+        let mut content: TxpoolContent = self
+            .provider
+            .provider()
+            .request("txpool_content", ())
+            .await?;
+        {
+            let mut metrics = self.metrics.write().await;
+            for txs in content.pending.values().chain(content.queued.values()) {
+                for tx in txs.values() {
+                    metrics.on_seen(tx.hash, taken_at);
+                }
+            }
+        }
+        if !self.filter.is_empty() {
+            for txs in content
+                .pending
+                .values_mut()
+                .chain(content.queued.values_mut())
+            {
+                txs.retain(|_, tx| self.filter.matches(tx));
+            }
+        }
+        {
+            let quarantine = self.quarantine.read().await;
+            content
+                .pending
+                .retain(|sender, _| !quarantine.contains(sender));
+            content
+                .queued
+                .retain(|sender, _| !quarantine.contains(sender));
+        }
+        let now = Instant::now();
+        let mut mempool = self.mempool.write().await;
+        let reconciliation = mempool.reconcile(content, taken_at, now);
+        if let Some(recorder) = &self.recorder {
+            // what the stream missed, so replays see it arrive too
+            for pool_tx in mempool.iter().filter(|pool_tx| pool_tx.first_seen == now) {
+                recorder.record_pending(&pool_tx.tx, now);
+            }
+        }
+        Ok(reconciliation)
+    }
+
+    pub async fn reconcile_periodically(self: Arc<TxPool<M>>, interval: Duration) {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            match self.reconcile().await {
+                Ok(reconciliation) => debug!("Reconciled mempool: {:?}", reconciliation),
+                Err(e) => warn!("Failed to reconcile mempool: {:?}", e),
+            }
+        }
+    }
+
+    /// Inserts pending transactions matching the filter as they arrive, over the configured
+    /// transport, or the cheapest one the node accepts a subscription for
+    pub async fn stream_mempool(self: Arc<TxPool<M>>)
+    where
+        <M as Middleware>::Provider: PubsubClient,
+    {
+        let transports = match self.transport {
+            Some(transport) => vec![transport],
+            None => self.filter.transports(),
+        };
+        for transport in transports {
+            match self.stream_with(transport).await {
+                Ok(()) => return,
+                Err(e) => warn!(
+                    "Unable to stream pending txns with {:?}: {:?}",
+                    transport, e
+                ),
+            }
+        }
+        warn!("No pending txn transport available, mempool is only reconciled");
+    }
+
+    /// Errors if the subscription is refused, returns once the stream closes
+    async fn stream_with(&self, transport: PendingTxTransport) -> Result<(), ProviderError>
+    where
+        <M as Middleware>::Provider: PubsubClient,
+    {
+        let provider = self.provider.provider();
+        if transport == PendingTxTransport::HashAndFetch {
+            let mut pending_tx_stream = provider
+                .subscribe_pending_txs()
+                .await?
+                .transactions_unordered(16); // TODO: what n is ideal?
+            info!("Streaming pending txns with {:?}", transport);
+            while let Some(pending_txn) = pending_tx_stream.next().await {
+                match pending_txn {
+                    Ok(pending_txn) => self.insert_matching(pending_txn).await,
+                    // usually mined or dropped before it could be fetched
+                    Err(e) => debug!("Failed to fetch pending txn: {:?}", e),
+                }
+            }
+            return Ok(());
+        }
+
+        let mut pending_tx_stream: SubscriptionStream<_, Box<RawValue>> = provider
+            .subscribe(self.filter.subscription_params(transport))
+            .await?;
+        info!("Streaming pending txns with {:?}", transport);
+        while let Some(item) = pending_tx_stream.next().await {
+            if let Ok(pending_txn) = serde_json::from_str::<Transaction>(item.get()) {
+                self.insert_matching(pending_txn).await;
+                continue;
+            }
+            // nodes that ignore the full transactions flag send hashes instead
+            match serde_json::from_str::<H256>(item.get()) {
+                Ok(txn_hash) => match provider.get_transaction(txn_hash).await {
+                    Ok(Some(pending_txn)) => self.insert_matching(pending_txn).await,
+                    Ok(None) => {}
+                    Err(e) => debug!("Failed to fetch pending txn {:?}: {:?}", txn_hash, e),
+                },
+                Err(e) => warn!("Unexpected pending txn notification: {:?}", e),
+            }
+        }
+        Ok(())
+    }
+
+    async fn insert_matching(&self, txn: Transaction) {
+        if self.filter.matches(&txn) {
+            self.insert(txn).await;
+        } else {
+            self.metrics.write().await.on_seen(txn.hash, Instant::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use ethers::{
+        providers::{Middleware, Provider, Ws},
+        types::U256,
+    };
+    use futures_util::StreamExt;
+
+    use super::TxPool;
+
+    #[tokio::test]
+    async fn test_mempool_stream_alchemy() {
+        dotenv::dotenv().ok();
+        let rpc_node_ws_url = std::env::var("ALCHEMY_POLYGON_RPC_WS_URL").unwrap();
+        let provider_ws = Provider::<Ws>::connect(&rpc_node_ws_url).await.unwrap();
+        let provider_ws = Arc::new(provider_ws);
+
+        let txpool = TxPool::init(provider_ws.clone(), 1000);
+        let txpool = Arc::new(txpool);
+        tokio::spawn(txpool.clone().stream_mempool());
+
+        let mut stream = provider_ws.subscribe_blocks().await.unwrap();
+        while let Some(_) = stream.next().await {
+            // println!("Pending txn count: {:?}", txpool.data.len());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mempool_stream_ipc() {
+        let provider_ipc = Provider::connect_ipc("/home/user/.bor/data/bor.ipc")
+            .await
+            .unwrap();
+        let provider_ipc = Arc::new(provider_ipc);
+
+        let txpool = TxPool::init(provider_ipc.clone(), 1000);
+        let txpool = Arc::new(txpool);
+        tokio::spawn(txpool.clone().stream_mempool());
+
+        let mut stream = provider_ipc.subscribe_blocks().await.unwrap();
+        while let Some(_) = stream.next().await {
+            println!("Pending txn count: {:?}", txpool.size().await);
+            println!(
+                "Suggested fees: {:?}",
+                txpool.suggest_fees(U256::from(30_000_000_000_u64)).await
+            );
+        }
+    }
+}
@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+
+use ethers::{
+    types::{Address, H256},
+    utils::hex,
+};
+use thiserror::Error;
+
+/// Blocks a sender stays excluded for, about a minute and a half on polygon
+pub const DEFAULT_QUARANTINE_BLOCKS: u64 = 50;
+
+/// Why a pending transaction made a block simulation fail, from the node's error message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum SimulationFailure {
+    #[error("insufficient funds for gas * price + value")]
+    InsufficientFunds,
+    /// ahead of the sender's account nonce, waiting on a gap we do not know about
+    #[error("nonce too high")]
+    NonceTooHigh,
+    #[error("nonce too low")]
+    NonceTooLow,
+    #[error("intrinsic gas too low")]
+    IntrinsicGasTooLow,
+    #[error("invalid signature")]
+    InvalidSignature,
+}
+
+/// A classified simulation error and who caused it. Geth names the sender for balance and
+/// nonce errors, and the transaction hash when the block processor wraps the error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SimulationError {
+    pub failure: SimulationFailure,
+    pub sender: Option<Address>,
+    pub tx_hash: Option<H256>,
+}
+
+impl SimulationError {
+    /// None if the message is not one of the failures a sender is quarantined for
+    pub fn classify(error_msg: &str) -> Option<Self> {
+        let failure = if error_msg.contains("insufficient funds for") {
+            SimulationFailure::InsufficientFunds
+        } else if error_msg.contains("nonce too high") {
+            SimulationFailure::NonceTooHigh
+        } else if error_msg.contains("nonce too low") {
+            SimulationFailure::NonceTooLow
+        } else if error_msg.contains("intrinsic gas too low") {
+            SimulationFailure::IntrinsicGasTooLow
+        } else if error_msg.contains("invalid transaction v, r, s values")
+            || error_msg.contains("invalid sender")
+            || error_msg.contains("invalid chain id for signer")
+        {
+            SimulationFailure::InvalidSignature
+        } else {
+            return None;
+        };
+
+        Some(Self {
+            failure: failure,
+            sender: hex_after(error_msg, "address 0x", 20).map(|bytes| Address::from_slice(&bytes)),
+            tx_hash: hex_after(error_msg, "[0x", 32).map(|bytes| H256::from_slice(&bytes)),
+        })
+    }
+}
+
+/// A sender excluded from the pool, and until when
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuarantinedSender {
+    pub sender: Address,
+    pub reason: SimulationFailure,
+    /// first block the sender's transactions are accepted again
+    pub until_block: u64,
+    /// times the sender has been quarantined since startup
+    pub strikes: u32,
+}
+
+/// Senders whose transactions broke block simulation, excluded for a number of blocks
+pub struct Quarantine {
+    blocks: u64,
+    current_block: u64,
+    senders: HashMap<Address, QuarantinedSender>,
+    strikes: HashMap<Address, u32>,
+}
+
+impl Quarantine {
+    pub fn new(blocks: u64) -> Self {
+        Self {
+            blocks: blocks,
+            current_block: 0,
+            senders: HashMap::new(),
+            strikes: HashMap::new(),
+        }
+    }
+
+    /// Excludes `sender` for the configured number of blocks from the latest one, extending
+    /// an earlier quarantine
+    pub fn quarantine(&mut self, sender: Address, reason: SimulationFailure) -> QuarantinedSender {
+        let strikes = self.strikes.entry(sender).or_default();
+        *strikes += 1;
+        let quarantined = QuarantinedSender {
+            sender: sender,
+            reason: reason,
+            until_block: self.current_block + self.blocks,
+            strikes: *strikes,
+        };
+        self.senders.insert(sender, quarantined);
+        quarantined
+    }
+
+    pub fn get(&self, sender: &Address) -> Option<&QuarantinedSender> {
+        self.senders.get(sender)
+    }
+
+    pub fn contains(&self, sender: &Address) -> bool {
+        self.senders.contains_key(sender)
+    }
+
+    /// Releases the senders whose quarantine ends at `block_number`
+    pub fn on_block(&mut self, block_number: u64) -> Vec<QuarantinedSender> {
+        self.current_block = u64::max(self.current_block, block_number);
+        let released: Vec<QuarantinedSender> = self
+            .senders
+            .values()
+            .filter(|quarantined| quarantined.until_block <= self.current_block)
+            .copied()
+            .collect();
+        for quarantined in &released {
+            self.senders.remove(&quarantined.sender);
+        }
+        released
+    }
+
+    /// Everyone currently excluded, with the reason why
+    pub fn senders(&self) -> impl Iterator<Item = &QuarantinedSender> {
+        self.senders.values()
+    }
+}
+
+/// Decodes the `len` bytes of hex right after `prefix`
+fn hex_after(msg: &str, prefix: &str, len: usize) -> Option<Vec<u8>> {
+    let start = msg.find(prefix)? + prefix.len();
+    hex::decode(msg.get(start..start + 2 * len)?).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use ethers::types::{Address, H256};
+
+    use super::{Quarantine, SimulationError, SimulationFailure};
+
+    #[test]
+    fn test_classify() {
+        let sender = Address::from_str("0x7ceb23fd6bc0add59e62ac25578270cff1b9f619").unwrap();
+        let error = SimulationError::classify(
+            "(code: -32000, message: insufficient funds for gas * price + value: address 0x7CEB23FD6BC0ADD59E62AC25578270CFF1B9F619 have 1 want 2, data: None)",
+        )
+        .unwrap();
+        assert_eq!(error.failure, SimulationFailure::InsufficientFunds);
+        assert_eq!(error.sender, Some(sender));
+        assert_eq!(error.tx_hash, None);
+
+        let tx_hash = H256::repeat_byte(0xab);
+        let error = SimulationError::classify(&format!(
+            "could not apply tx 3 [{:?}]: intrinsic gas too low: have 21000, want 53000",
+            tx_hash
+        ))
+        .unwrap();
+        assert_eq!(error.failure, SimulationFailure::IntrinsicGasTooLow);
+        assert_eq!(error.sender, None);
+        assert_eq!(error.tx_hash, Some(tx_hash));
+
+        // garbage after the prefix is ignored rather than split mid character
+        let error = SimulationError::classify(&format!(
+            "could not apply tx 3 [0xa{}b]: intrinsic gas too low",
+            "é".repeat(31)
+        ))
+        .unwrap();
+        assert_eq!(error.tx_hash, None);
+
+        assert_eq!(
+            SimulationError::classify(
+                "nonce too high: address 0x7ceb23fd6bc0add59e62ac25578270cff1b9f619, tx: 5 state: 3"
+            )
+            .unwrap()
+            .failure,
+            SimulationFailure::NonceTooHigh
+        );
+        assert_eq!(
+            SimulationError::classify("invalid transaction v, r, s values")
+                .unwrap()
+                .failure,
+            SimulationFailure::InvalidSignature
+        );
+        assert_eq!(SimulationError::classify("execution reverted"), None);
+    }
+
+    #[test]
+    fn test_quarantine() {
+        let sender = Address::repeat_byte(1);
+        let mut quarantine = Quarantine::new(10);
+        quarantine.on_block(100);
+
+        let quarantined = quarantine.quarantine(sender, SimulationFailure::InsufficientFunds);
+        assert_eq!(quarantined.until_block, 110);
+        assert_eq!(quarantined.strikes, 1);
+        assert!(quarantine.on_block(109).is_empty());
+        assert!(quarantine.contains(&sender));
+        assert_eq!(
+            quarantine.get(&sender).unwrap().reason.to_string(),
+            "insufficient funds for gas * price + value"
+        );
+
+        let released = quarantine.on_block(110);
+        assert_eq!(released, vec![quarantined]);
+        assert!(!quarantine.contains(&sender));
+
+        // repeat offenders are counted
+        let quarantined = quarantine.quarantine(sender, SimulationFailure::NonceTooHigh);
+        assert_eq!(quarantined.strikes, 2);
+        assert_eq!(quarantine.senders().count(), 1);
+    }
+}
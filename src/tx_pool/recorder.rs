@@ -0,0 +1,467 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Lines, Write},
+    path::Path,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use ethers::{
+    providers::{Middleware, PubsubClient},
+    types::{Address, Block, Bytes, Transaction, H256, U256, U64},
+    utils::rlp,
+};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use super::TxPool;
+use crate::{utils::txstructs::filter_mempool, world::WorldState};
+
+/// A transaction as its signed RLP encoding, the hash and sender are kept so they need not
+/// be recomputed, and because bor's state sync transactions are not signed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedTx {
+    pub hash: H256,
+    pub from: Address,
+    pub raw: Bytes,
+}
+
+impl RecordedTx {
+    pub fn new(tx: &Transaction) -> Self {
+        Self {
+            hash: tx.hash,
+            from: tx.from,
+            raw: tx.rlp(),
+        }
+    }
+
+    pub fn decode(&self) -> Result<Transaction, rlp::DecoderError> {
+        let mut tx: Transaction = rlp::decode(&self.raw)?;
+        tx.hash = self.hash;
+        tx.from = self.from;
+        // nodes report the fee cap of dynamic fee transactions as their gas price
+        if tx.gas_price.is_none() {
+            tx.gas_price = tx.max_fee_per_gas;
+        }
+        Ok(tx)
+    }
+}
+
+/// One line of a recording, `ms` is the time since the recording started
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "e", rename_all = "snake_case")]
+pub enum RecordedEvent {
+    Pending {
+        ms: u64,
+        tx: RecordedTx,
+    },
+    Block {
+        ms: u64,
+        number: u64,
+        hash: H256,
+        timestamp: U256,
+        base_fee: Option<U256>,
+        gas_used: U256,
+        gas_limit: U256,
+        txs: Vec<RecordedTx>,
+    },
+    /// reserves of a uniswap v2 pair, from a `Sync` log or a resync
+    Sync {
+        ms: u64,
+        pair: Address,
+        reserve0: U256,
+        reserve1: U256,
+    },
+    /// `filter_mempool` run on the pool for block `number` with these on-chain account
+    /// nonces, after which the transactions it rejected were dropped from the pool
+    Prediction {
+        ms: u64,
+        number: u64,
+        base_fee: U256,
+        account_nonces: Vec<(Address, U256)>,
+    },
+}
+
+impl RecordedEvent {
+    pub fn ms(&self) -> u64 {
+        match self {
+            RecordedEvent::Pending { ms, .. }
+            | RecordedEvent::Block { ms, .. }
+            | RecordedEvent::Sync { ms, .. }
+            | RecordedEvent::Prediction { ms, .. } => *ms,
+        }
+    }
+}
+
+/// Appends pending transactions, blocks and pair reserve updates to a file, one JSON event
+/// per line. Shared by `TxPool::with_recorder` and `WorldState::with_recorder` so both
+/// write to one timeline.
+pub struct Recorder {
+    start: Instant,
+    writer: Mutex<BufWriter<File>>,
+}
+
+impl Recorder {
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self {
+            start: Instant::now(),
+            writer: Mutex::new(BufWriter::new(File::create(path)?)),
+        })
+    }
+
+    pub fn record_pending(&self, tx: &Transaction, now: Instant) {
+        self.write(&RecordedEvent::Pending {
+            ms: self.ms(now),
+            tx: RecordedTx::new(tx),
+        });
+    }
+
+    pub fn record_block(&self, block: &Block<Transaction>, now: Instant) {
+        self.write(&RecordedEvent::Block {
+            ms: self.ms(now),
+            number: block.number.unwrap_or_default().as_u64(),
+            hash: block.hash.unwrap_or_default(),
+            timestamp: block.timestamp,
+            base_fee: block.base_fee_per_gas,
+            gas_used: block.gas_used,
+            gas_limit: block.gas_limit,
+            txs: block.transactions.iter().map(RecordedTx::new).collect(),
+        });
+        // blocks are rare enough to flush on, so a crash loses at most one block's worth
+        if let Err(e) = self.writer.lock().unwrap().flush() {
+            warn!("Failed to flush mempool recording: {:?}", e);
+        }
+    }
+
+    pub fn record_sync(&self, pair: Address, reserve0: U256, reserve1: U256, now: Instant) {
+        self.write(&RecordedEvent::Sync {
+            ms: self.ms(now),
+            pair: pair,
+            reserve0: reserve0,
+            reserve1: reserve1,
+        });
+    }
+
+    pub fn record_prediction(
+        &self,
+        number: u64,
+        base_fee: U256,
+        account_nonces: &HashMap<Address, U256>,
+        now: Instant,
+    ) {
+        let mut account_nonces: Vec<(Address, U256)> = account_nonces
+            .iter()
+            .map(|(address, nonce)| (*address, *nonce))
+            .collect();
+        account_nonces.sort();
+        self.write(&RecordedEvent::Prediction {
+            ms: self.ms(now),
+            number: number,
+            base_fee: base_fee,
+            account_nonces: account_nonces,
+        });
+    }
+
+    fn ms(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.start).as_millis() as u64
+    }
+
+    fn write(&self, event: &RecordedEvent) {
+        let mut writer = self.writer.lock().unwrap();
+        let written = serde_json::to_writer(&mut *writer, event)
+            .map_err(io::Error::from)
+            .and_then(|_| writer.write_all(b"\n"));
+        if let Err(e) = written {
+            warn!("Failed to record mempool event: {:?}", e);
+        }
+    }
+}
+
+/// A recorded block, with the prediction `filter_mempool` made for it. That is the recorded
+/// prediction replayed if there is one, otherwise one from the pool as it was right before
+/// the block arrived.
+#[derive(Debug, Clone)]
+pub struct ReplayedBlock {
+    pub block: Block<Transaction>,
+    pub predicted: Vec<Transaction>,
+    pub rejected: Vec<Transaction>,
+}
+
+struct Prediction {
+    number: u64,
+    predicted: Vec<Transaction>,
+    rejected: Vec<Transaction>,
+}
+
+/// Plays a recording back. Every event is applied at the recording start plus its recorded
+/// offset, so pool arrival times, TTLs and fee sample weights come out the same on every run.
+pub struct Replay {
+    events: Lines<BufReader<File>>,
+    start: Instant,
+    prediction: Option<Prediction>,
+}
+
+impl Replay {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self {
+            events: BufReader::new(File::open(path)?).lines(),
+            start: Instant::now(),
+            prediction: None,
+        })
+    }
+
+    /// Instant the recording's offsets are replayed from
+    pub fn start(&self) -> Instant {
+        self.start
+    }
+
+    pub fn next_event(&mut self) -> io::Result<Option<RecordedEvent>> {
+        match self.events.next() {
+            Some(line) => Ok(Some(serde_json::from_str(&line?)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Inserts pending transactions into `txpool` and applies reserve updates to `world` up
+    /// to the next recorded block, predicts that block, then applies the block. None once
+    /// the recording ends.
+    ///
+    /// Recorded predictions are rerun at the point they were made, with the account nonces
+    /// fetched then. Blocks without one are predicted from the pool's lowest nonces.
+    ///
+    /// `world` should be restored from a snapshot taken when the recording started. Expiry
+    /// runs at every block rather than on a timer.
+    pub async fn next_block<M: Middleware + Clone, P: PubsubClient>(
+        &mut self,
+        txpool: &TxPool<M>,
+        world: Option<&WorldState<M, P>>,
+    ) -> io::Result<Option<ReplayedBlock>> {
+        while let Some(event) = self.next_event()? {
+            let now = self.start + Duration::from_millis(event.ms());
+            match event {
+                RecordedEvent::Pending { tx, .. } => {
+                    txpool.insert_at(decode(&tx)?, now).await;
+                }
+                RecordedEvent::Sync {
+                    pair,
+                    reserve0,
+                    reserve1,
+                    ..
+                } => {
+                    if let Some(world) = world {
+                        world.update_pair_reserves(pair, reserve0, reserve1).await;
+                    }
+                }
+                RecordedEvent::Prediction {
+                    number,
+                    base_fee,
+                    account_nonces,
+                    ..
+                } => {
+                    // senders the live pool did not have fall back to their lowest nonce
+                    let mut nonces = txpool.account_nonces().await;
+                    nonces.extend(account_nonces);
+                    let (predicted, rejected) =
+                        filter_mempool(sorted_mempool(txpool).await, nonces, base_fee);
+                    txpool
+                        .remove_transactions(rejected.iter().map(|tx| tx.hash).collect())
+                        .await;
+                    self.prediction = Some(Prediction {
+                        number: number,
+                        predicted: predicted,
+                        rejected: rejected,
+                    });
+                }
+                RecordedEvent::Block {
+                    number,
+                    hash,
+                    timestamp,
+                    base_fee,
+                    gas_used,
+                    gas_limit,
+                    txs,
+                    ..
+                } => {
+                    let block = Block {
+                        number: Some(U64::from(number)),
+                        hash: Some(hash),
+                        timestamp: timestamp,
+                        base_fee_per_gas: base_fee,
+                        gas_used: gas_used,
+                        gas_limit: gas_limit,
+                        transactions: txs.iter().map(decode).collect::<io::Result<_>>()?,
+                        ..Default::default()
+                    };
+
+                    txpool.expire_at(now).await;
+                    let (predicted, rejected) = match self.prediction.take() {
+                        Some(prediction) if prediction.number == number => {
+                            (prediction.predicted, prediction.rejected)
+                        }
+                        _ => filter_mempool(
+                            sorted_mempool(txpool).await,
+                            txpool.account_nonces().await,
+                            base_fee.unwrap_or_default(),
+                        ),
+                    };
+                    txpool.on_block_at(&block, now).await;
+                    if let Some(world) = world {
+                        world.set_last_block(number);
+                    }
+                    return Ok(Some(ReplayedBlock {
+                        block: block,
+                        predicted: predicted,
+                        rejected: rejected,
+                    }));
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// The pool in a fixed order, as the pool's own iteration order changes from run to run
+async fn sorted_mempool<M: Middleware + Clone>(txpool: &TxPool<M>) -> Vec<Transaction> {
+    let mut mempool = txpool.get_mempool().await;
+    mempool.sort_by_key(|tx| (tx.from, tx.nonce));
+    mempool
+}
+
+fn decode(tx: &RecordedTx) -> io::Result<Transaction> {
+    tx.decode()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc, time::Duration};
+
+    use ethers::{
+        providers::{Provider, Ws},
+        signers::{LocalWallet, Signer},
+        types::{
+            transaction::eip2718::TypedTransaction, Address, Block, Eip1559TransactionRequest,
+            Transaction, U256, U64,
+        },
+    };
+
+    use super::{RecordedEvent, RecordedTx, Recorder, Replay};
+    use crate::{tx_pool::TxPool, world::WorldState};
+
+    const GWEI: u64 = 1_000_000_000;
+
+    async fn signed_tx(wallet: &LocalWallet, nonce: u64, max_fee: u64) -> Transaction {
+        let request: TypedTransaction = Eip1559TransactionRequest::new()
+            .to(Address::repeat_byte(1))
+            .nonce(nonce)
+            .gas(21_000)
+            .max_fee_per_gas(max_fee * GWEI)
+            .max_priority_fee_per_gas(30 * GWEI)
+            .chain_id(137u64)
+            .into();
+        let signature = wallet.sign_transaction(&request).await.unwrap();
+        let raw = request.rlp_signed(&signature);
+        let mut tx: Transaction = ethers::utils::rlp::decode(&raw).unwrap();
+        tx.hash = ethers::utils::keccak256(&raw).into();
+        tx.from = wallet.address();
+        tx.gas_price = tx.max_fee_per_gas;
+        tx
+    }
+
+    #[tokio::test]
+    async fn test_record_and_replay() {
+        let wallet = "4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318"
+            .parse::<LocalWallet>()
+            .unwrap()
+            .with_chain_id(137u64);
+        let first = signed_tx(&wallet, 0, 60).await;
+        let second = signed_tx(&wallet, 1, 60).await;
+        // another sender paying the same, so predictions have a gas price tie to break
+        let other_wallet = "8f2a55949038a9610f50fb23b5883af3b4ecb3c3bb792cbcefbd1542c692be63"
+            .parse::<LocalWallet>()
+            .unwrap()
+            .with_chain_id(137u64);
+        let other = signed_tx(&other_wallet, 0, 60).await;
+
+        let recorded = RecordedTx::new(&first);
+        assert_eq!(recorded.decode().unwrap(), first);
+
+        let path =
+            std::env::temp_dir().join(format!("tsuki_recording_{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let recorder = Recorder::create(&path).unwrap();
+        let start = recorder.start;
+        recorder.record_pending(&first, start + Duration::from_millis(5));
+        recorder.record_pending(&other, start + Duration::from_millis(7));
+        recorder.record_pending(&second, start + Duration::from_millis(10));
+        recorder.record_sync(Address::zero(), U256::one(), U256::one(), start);
+        let block = Block {
+            number: Some(U64::from(1)),
+            base_fee_per_gas: Some(U256::from(50 * GWEI)),
+            transactions: vec![first.clone()],
+            ..Default::default()
+        };
+        recorder.record_block(&block, start + Duration::from_millis(2000));
+        // the next block is predicted with on-chain nonces fetched live
+        let third = signed_tx(&wallet, 2, 60).await;
+        recorder.record_pending(&third, start + Duration::from_millis(2100));
+        recorder.record_prediction(
+            2,
+            U256::from(50 * GWEI),
+            &HashMap::from([(wallet.address(), U256::from(2))]),
+            start + Duration::from_millis(2500),
+        );
+        let block = Block {
+            number: Some(U64::from(2)),
+            base_fee_per_gas: Some(U256::from(50 * GWEI)),
+            transactions: vec![third.clone()],
+            ..Default::default()
+        };
+        recorder.record_block(&block, start + Duration::from_millis(4000));
+        drop(recorder);
+
+        let mut replay = Replay::open(&path).unwrap();
+        let events: Vec<RecordedEvent> =
+            std::iter::from_fn(|| replay.next_event().unwrap()).collect();
+        assert_eq!(events.len(), 8);
+        assert_eq!(events[4].ms(), 2000);
+
+        let (provider, _mock) = Provider::mocked();
+        let txpool = TxPool::init(Arc::new(provider), 100);
+        let mut replay = Replay::open(&path).unwrap();
+        let replayed = replay
+            .next_block(&txpool, None::<&WorldState<_, Ws>>)
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(replayed.block.number, Some(U64::from(1)));
+        assert_eq!(replayed.block.transactions, vec![first.clone()]);
+        // the tie between senders breaks the same way on every run
+        assert_eq!(
+            replayed.predicted,
+            vec![first, other.clone(), second.clone()]
+        );
+        assert!(replayed.rejected.is_empty());
+        // the mined transaction left the pool
+        assert_eq!(txpool.get_mempool().await.len(), 2);
+
+        // the nonces fetched live had moved past the second transaction, so replay rejects
+        // and drops it too, rather than predicting from the pool's own nonces
+        let replayed = replay
+            .next_block(&txpool, None::<&WorldState<_, Ws>>)
+            .await
+            .unwrap()
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(replayed.predicted, vec![other.clone(), third]);
+        assert_eq!(replayed.rejected, vec![second]);
+        assert_eq!(txpool.get_mempool().await, vec![other]);
+        assert!(replay
+            .next_block(&txpool, None::<&WorldState<_, Ws>>)
+            .await
+            .unwrap()
+            .is_none());
+    }
+}
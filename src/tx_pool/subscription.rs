@@ -0,0 +1,173 @@
+use ethers::{
+    types::{Address, Selector, Transaction},
+    utils,
+};
+use serde_json::Value;
+
+use crate::utils::serialize_structs::PendingTransactionOptions;
+
+/// How pending transactions are received from the node
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PendingTxTransport {
+    /// `alchemy_pendingTransactions`, full transactions filtered by the node on from/to
+    Alchemy,
+    /// `newPendingTransactions` with full transactions, supported by geth and bor 1.11+
+    FullTransactions,
+    /// `newPendingTransactions` hashes, each fetched with `eth_getTransactionByHash`
+    HashAndFetch,
+}
+
+/// Which pending transactions the pool keeps. Each list left empty matches anything, a
+/// transaction has to match all non-empty lists.
+///
+/// Only matching transactions enter the pool, so a sender's lane can have gaps where its
+/// other transactions were filtered out, and fee suggestions only sample what matched.
+#[derive(Debug, Clone, Default)]
+pub struct PendingTxFilter {
+    pub from: Vec<Address>,
+    pub to: Vec<Address>,
+    /// function selectors of the calldata
+    pub selectors: Vec<Selector>,
+}
+
+impl PendingTxFilter {
+    pub fn from(mut self, sender: Address) -> Self {
+        self.from.push(sender);
+        self
+    }
+
+    pub fn to(mut self, recipient: Address) -> Self {
+        self.to.push(recipient);
+        self
+    }
+
+    pub fn selector(mut self, selector: Selector) -> Self {
+        self.selectors.push(selector);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.from.is_empty() && self.to.is_empty() && self.selectors.is_empty()
+    }
+
+    pub fn matches(&self, tx: &Transaction) -> bool {
+        (self.from.is_empty() || self.from.contains(&tx.from))
+            && (self.to.is_empty() || matches!(tx.to, Some(to) if self.to.contains(&to)))
+            && (self.selectors.is_empty()
+                || (tx.input.len() >= 4
+                    && self
+                        .selectors
+                        .iter()
+                        .any(|selector| tx.input[..4] == selector[..])))
+    }
+
+    /// Transports to try, cheapest first. Filtering on the node only pays off when there
+    /// are addresses to filter on, otherwise full transactions save the per-hash fetches.
+    pub fn transports(&self) -> Vec<PendingTxTransport> {
+        if self.from.is_empty() && self.to.is_empty() {
+            vec![
+                PendingTxTransport::FullTransactions,
+                PendingTxTransport::Alchemy,
+                PendingTxTransport::HashAndFetch,
+            ]
+        } else {
+            vec![
+                PendingTxTransport::Alchemy,
+                PendingTxTransport::FullTransactions,
+                PendingTxTransport::HashAndFetch,
+            ]
+        }
+    }
+
+    /// `eth_subscribe` params for `transport`. Selectors are always matched locally.
+    pub fn subscription_params(&self, transport: PendingTxTransport) -> Vec<Value> {
+        match transport {
+            PendingTxTransport::Alchemy => {
+                let addresses = |addresses: &Vec<Address>| {
+                    if addresses.is_empty() {
+                        return None;
+                    }
+                    Some(addresses.iter().map(|a| format!("{:?}", a)).collect())
+                };
+                vec![
+                    utils::serialize(&"alchemy_pendingTransactions"),
+                    utils::serialize(&PendingTransactionOptions {
+                        from_address: addresses(&self.from),
+                        to_address: addresses(&self.to),
+                        hashes_only: Some(false),
+                    }),
+                ]
+            }
+            PendingTxTransport::FullTransactions => vec![
+                utils::serialize(&"newPendingTransactions"),
+                utils::serialize(&true),
+            ],
+            PendingTxTransport::HashAndFetch => vec![utils::serialize(&"newPendingTransactions")],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::types::{Address, Bytes, Transaction};
+    use serde_json::json;
+
+    use super::{PendingTxFilter, PendingTxTransport};
+
+    #[test]
+    fn test_pending_tx_filter() {
+        let router = Address::repeat_byte(1);
+        let tx = Transaction {
+            from: Address::repeat_byte(2),
+            to: Some(router),
+            input: Bytes::from(vec![0x38, 0xed, 0x17, 0x39, 0xff]),
+            ..Default::default()
+        };
+
+        assert!(PendingTxFilter::default().matches(&tx));
+        assert!(PendingTxFilter::default().to(router).matches(&tx));
+        assert!(PendingTxFilter::default()
+            .to(Address::repeat_byte(3))
+            .to(router)
+            .selector([0x38, 0xed, 0x17, 0x39])
+            .matches(&tx));
+        // every non-empty list has to match
+        assert!(!PendingTxFilter::default()
+            .to(router)
+            .from(Address::repeat_byte(3))
+            .matches(&tx));
+        assert!(!PendingTxFilter::default()
+            .selector([0x7f, 0xf3, 0x6a, 0xb5])
+            .matches(&tx));
+        let transfer = Transaction {
+            to: Some(router),
+            ..Default::default()
+        };
+        assert!(!PendingTxFilter::default()
+            .selector([0x38, 0xed, 0x17, 0x39])
+            .matches(&transfer));
+    }
+
+    #[test]
+    fn test_subscription_params() {
+        let filter = PendingTxFilter::default().to(Address::repeat_byte(0xab));
+        assert_eq!(filter.transports()[0], PendingTxTransport::Alchemy);
+        assert_eq!(
+            filter.subscription_params(PendingTxTransport::Alchemy),
+            vec![
+                json!("alchemy_pendingTransactions"),
+                json!({
+                    "toAddress": [format!("{:?}", Address::repeat_byte(0xab))],
+                    "hashesOnly": false,
+                }),
+            ]
+        );
+
+        let filter = PendingTxFilter::default().selector([0x38, 0xed, 0x17, 0x39]);
+        assert_eq!(filter.transports()[0], PendingTxTransport::FullTransactions);
+        assert_eq!(
+            filter.subscription_params(PendingTxTransport::FullTransactions),
+            vec![json!("newPendingTransactions"), json!(true)]
+        );
+    }
+}
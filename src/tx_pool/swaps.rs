@@ -0,0 +1,530 @@
+use ethers::{
+    abi::AbiDecode,
+    contract::EthCall,
+    prelude::abigen,
+    types::{Address, Transaction, H256, U256},
+};
+
+use crate::{
+    constants::protocol::{UniswapV2, ONEINCH_ROUTER, UNISWAP_V3},
+    uniswapV2::IUniswapV2Router02Calls,
+    world::Protocol,
+};
+
+abigen!(
+    ISwapRouter,
+    r#"[
+        struct ExactInputSingleParams { address tokenIn; address tokenOut; uint24 fee; address recipient; uint256 deadline; uint256 amountIn; uint256 amountOutMinimum; uint160 sqrtPriceLimitX96; }
+        struct ExactInputParams { bytes path; address recipient; uint256 deadline; uint256 amountIn; uint256 amountOutMinimum; }
+        struct ExactOutputSingleParams { address tokenIn; address tokenOut; uint24 fee; address recipient; uint256 deadline; uint256 amountOut; uint256 amountInMaximum; uint160 sqrtPriceLimitX96; }
+        struct ExactOutputParams { bytes path; address recipient; uint256 deadline; uint256 amountOut; uint256 amountInMaximum; }
+        function exactInputSingle(ExactInputSingleParams params) external payable returns (uint256 amountOut)
+        function exactInput(ExactInputParams params) external payable returns (uint256 amountOut)
+        function exactOutputSingle(ExactOutputSingleParams params) external payable returns (uint256 amountIn)
+        function exactOutput(ExactOutputParams params) external payable returns (uint256 amountIn)
+        function multicall(bytes[] data) external payable returns (bytes[] results)
+    ]"#,
+);
+
+abigen!(
+    AggregationRouterV5,
+    r#"[
+        struct SwapDescription { address srcToken; address dstToken; address srcReceiver; address dstReceiver; uint256 amount; uint256 minReturnAmount; uint256 flags; }
+        function swap(address executor, SwapDescription desc, bytes permit, bytes data) external payable returns (uint256 returnAmount, uint256 spentAmount)
+    ]"#,
+);
+
+// uniswap v3 paths pack (token, fee, token, fee, ..., token)
+const V3_PATH_ADDRESS_SIZE: usize = 20;
+const V3_PATH_FEE_SIZE: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SwapAmounts {
+    /// sells exactly `amount_in`, reverting if it gets less than `min_amount_out`
+    ExactIn {
+        amount_in: U256,
+        min_amount_out: U256,
+    },
+    /// buys exactly `amount_out`, reverting if it costs more than `max_amount_in`
+    ExactOut {
+        amount_out: U256,
+        max_amount_in: U256,
+    },
+}
+
+/// What a pending router call will swap, decoded from its calldata
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SwapIntent {
+    pub tx_hash: H256,
+    pub sender: Address,
+    pub router: Address,
+    /// tokens from the one sold to the one bought
+    pub token_path: Vec<Address>,
+    /// pool of each hop, empty when an aggregator picks the route
+    pub protocols: Vec<Protocol>,
+    pub amounts: SwapAmounts,
+    pub recipient: Address,
+    /// unix timestamp after which the router reverts
+    pub deadline: Option<U256>,
+}
+
+/// Swaps made by `tx` if it calls a known router, several for a multicall
+pub fn decode_swaps(tx: &Transaction) -> Vec<SwapIntent> {
+    let mut intents = Vec::new();
+    let router = match tx.to {
+        Some(router) => router,
+        None => return intents,
+    };
+
+    if let Some(protocol) = UniswapV2::get_all_protoccols()
+        .into_iter()
+        .find(|protocol| protocol.get_router_address() == router)
+    {
+        intents.extend(decode_uniswap_v2(tx, protocol));
+    } else if router == UNISWAP_V3.router_address {
+        decode_uniswap_v3(tx, &tx.input, &mut intents);
+    } else if router == *ONEINCH_ROUTER {
+        intents.extend(decode_oneinch(tx));
+    }
+    intents
+}
+
+fn decode_uniswap_v2(tx: &Transaction, protocol: UniswapV2) -> Option<SwapIntent> {
+    use IUniswapV2Router02Calls::*;
+
+    let exact_in = |amount_in: U256, min_amount_out: U256| SwapAmounts::ExactIn {
+        amount_in: amount_in,
+        min_amount_out: min_amount_out,
+    };
+    let exact_out = |amount_out: U256, max_amount_in: U256| SwapAmounts::ExactOut {
+        amount_out: amount_out,
+        max_amount_in: max_amount_in,
+    };
+    let (path, amounts, recipient, deadline) =
+        match IUniswapV2Router02Calls::decode(&tx.input).ok()? {
+            SwapExactTokensForTokens(call) => (
+                call.path,
+                exact_in(call.amount_in, call.amount_out_min),
+                call.to,
+                call.deadline,
+            ),
+            SwapExactTokensForTokensSupportingFeeOnTransferTokens(call) => (
+                call.path,
+                exact_in(call.amount_in, call.amount_out_min),
+                call.to,
+                call.deadline,
+            ),
+            SwapExactTokensForETH(call) => (
+                call.path,
+                exact_in(call.amount_in, call.amount_out_min),
+                call.to,
+                call.deadline,
+            ),
+            SwapExactTokensForETHSupportingFeeOnTransferTokens(call) => (
+                call.path,
+                exact_in(call.amount_in, call.amount_out_min),
+                call.to,
+                call.deadline,
+            ),
+            SwapExactETHForTokens(call) => (
+                call.path,
+                exact_in(tx.value, call.amount_out_min),
+                call.to,
+                call.deadline,
+            ),
+            SwapExactETHForTokensSupportingFeeOnTransferTokens(call) => (
+                call.path,
+                exact_in(tx.value, call.amount_out_min),
+                call.to,
+                call.deadline,
+            ),
+            SwapTokensForExactTokens(call) => (
+                call.path,
+                exact_out(call.amount_out, call.amount_in_max),
+                call.to,
+                call.deadline,
+            ),
+            SwapTokensForExactETH(call) => (
+                call.path,
+                exact_out(call.amount_out, call.amount_in_max),
+                call.to,
+                call.deadline,
+            ),
+            SwapETHForExactTokens(call) => (
+                call.path,
+                exact_out(call.amount_out, tx.value),
+                call.to,
+                call.deadline,
+            ),
+            _ => return None,
+        };
+    if path.len() < 2 {
+        return None;
+    }
+
+    Some(SwapIntent {
+        tx_hash: tx.hash,
+        sender: tx.from,
+        router: protocol.get_router_address(),
+        protocols: vec![Protocol::UniswapV2(protocol); path.len() - 1],
+        token_path: path,
+        amounts: amounts,
+        recipient: recipient,
+        deadline: Some(deadline),
+    })
+}
+
+fn decode_uniswap_v3(tx: &Transaction, input: &[u8], intents: &mut Vec<SwapIntent>) {
+    let call = match ISwapRouterCalls::decode(input) {
+        Ok(call) => call,
+        Err(_) => return,
+    };
+    let intent = |token_path: Vec<Address>,
+                  fees: Vec<u32>,
+                  amounts: SwapAmounts,
+                  recipient: Address,
+                  deadline: U256| SwapIntent {
+        tx_hash: tx.hash,
+        sender: tx.from,
+        router: UNISWAP_V3.router_address,
+        token_path: token_path,
+        protocols: fees
+            .into_iter()
+            .map(|fee| Protocol::UniswapV3 { fee: fee })
+            .collect(),
+        amounts: amounts,
+        recipient: recipient,
+        deadline: Some(deadline),
+    };
+
+    match call {
+        ISwapRouterCalls::ExactInputSingle(ExactInputSingleCall { params }) => {
+            intents.push(intent(
+                vec![params.token_in, params.token_out],
+                vec![params.fee],
+                SwapAmounts::ExactIn {
+                    amount_in: params.amount_in,
+                    min_amount_out: params.amount_out_minimum,
+                },
+                params.recipient,
+                params.deadline,
+            ));
+        }
+        ISwapRouterCalls::ExactInput(ExactInputCall { params }) => {
+            if let Some((token_path, fees)) = decode_v3_path(&params.path) {
+                intents.push(intent(
+                    token_path,
+                    fees,
+                    SwapAmounts::ExactIn {
+                        amount_in: params.amount_in,
+                        min_amount_out: params.amount_out_minimum,
+                    },
+                    params.recipient,
+                    params.deadline,
+                ));
+            }
+        }
+        ISwapRouterCalls::ExactOutputSingle(ExactOutputSingleCall { params }) => {
+            intents.push(intent(
+                vec![params.token_in, params.token_out],
+                vec![params.fee],
+                SwapAmounts::ExactOut {
+                    amount_out: params.amount_out,
+                    max_amount_in: params.amount_in_maximum,
+                },
+                params.recipient,
+                params.deadline,
+            ));
+        }
+        ISwapRouterCalls::ExactOutput(ExactOutputCall { params }) => {
+            // exact output paths run from the token bought to the token sold
+            if let Some((mut token_path, mut fees)) = decode_v3_path(&params.path) {
+                token_path.reverse();
+                fees.reverse();
+                intents.push(intent(
+                    token_path,
+                    fees,
+                    SwapAmounts::ExactOut {
+                        amount_out: params.amount_out,
+                        max_amount_in: params.amount_in_maximum,
+                    },
+                    params.recipient,
+                    params.deadline,
+                ));
+            }
+        }
+        ISwapRouterCalls::Multicall(MulticallCall { data }) => {
+            for input in data {
+                decode_uniswap_v3(tx, &input, intents);
+            }
+        }
+    }
+}
+
+fn decode_oneinch(tx: &Transaction) -> Option<SwapIntent> {
+    if tx.input.len() < 4 || tx.input[..4] != SwapCall::selector() {
+        return None;
+    }
+    let desc = SwapCall::decode(&tx.input).ok()?.desc;
+    Some(SwapIntent {
+        tx_hash: tx.hash,
+        sender: tx.from,
+        router: *ONEINCH_ROUTER,
+        token_path: vec![desc.src_token, desc.dst_token],
+        protocols: Vec::new(),
+        amounts: SwapAmounts::ExactIn {
+            amount_in: desc.amount,
+            min_amount_out: desc.min_return_amount,
+        },
+        recipient: desc.dst_receiver,
+        deadline: None,
+    })
+}
+
+/// Tokens and pool fees of a packed uniswap v3 path
+fn decode_v3_path(path: &[u8]) -> Option<(Vec<Address>, Vec<u32>)> {
+    let hop_size = V3_PATH_ADDRESS_SIZE + V3_PATH_FEE_SIZE;
+    if path.len() < V3_PATH_ADDRESS_SIZE + hop_size
+        || (path.len() - V3_PATH_ADDRESS_SIZE) % hop_size != 0
+    {
+        return None;
+    }
+
+    let mut tokens = Vec::new();
+    let mut fees = Vec::new();
+    let mut offset = 0;
+    loop {
+        tokens.push(Address::from_slice(
+            &path[offset..offset + V3_PATH_ADDRESS_SIZE],
+        ));
+        offset += V3_PATH_ADDRESS_SIZE;
+        if offset == path.len() {
+            return Some((tokens, fees));
+        }
+        let fee = &path[offset..offset + V3_PATH_FEE_SIZE];
+        fees.push(u32::from_be_bytes([0, fee[0], fee[1], fee[2]]));
+        offset += V3_PATH_FEE_SIZE;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::{
+        abi::AbiEncode,
+        types::{Address, Bytes, Transaction, U256},
+    };
+
+    use super::{
+        decode_swaps, ExactInputCall, ExactInputParams, ExactInputSingleCall,
+        ExactInputSingleParams, ExactOutputCall, ExactOutputParams, ISwapRouterCalls,
+        MulticallCall, SwapAmounts, SwapCall, SwapDescription,
+    };
+    use crate::{
+        constants::{
+            protocol::{UniswapV2, ONEINCH_ROUTER, UNISWAP_V3},
+            token::ERC20Token::*,
+        },
+        uniswapV2::{SwapETHForExactTokensCall, SwapExactTokensForTokensCall},
+        world::Protocol,
+    };
+
+    fn v3_path(tokens: &[Address], fees: &[u32]) -> Bytes {
+        let mut path = tokens[0].as_bytes().to_vec();
+        for (token, fee) in tokens[1..].iter().zip(fees) {
+            path.extend_from_slice(&fee.to_be_bytes()[1..]);
+            path.extend_from_slice(token.as_bytes());
+        }
+        Bytes::from(path)
+    }
+
+    #[test]
+    fn test_decode_uniswap_v2() {
+        let sender = Address::from_low_u64_be(1);
+        let path = vec![USDC.get_address(), WETH.get_address(), WBTC.get_address()];
+        let tx = Transaction {
+            from: sender,
+            to: Some(UniswapV2::QUICKSWAP.get_router_address()),
+            input: SwapExactTokensForTokensCall {
+                amount_in: U256::from(1_000_000),
+                amount_out_min: U256::from(40),
+                path: path.clone(),
+                to: sender,
+                deadline: U256::from(1_700_000_000),
+            }
+            .encode()
+            .into(),
+            ..Default::default()
+        };
+        let intents = decode_swaps(&tx);
+        assert_eq!(intents.len(), 1);
+        assert_eq!(intents[0].token_path, path);
+        assert_eq!(
+            intents[0].protocols,
+            vec![Protocol::UniswapV2(UniswapV2::QUICKSWAP); 2]
+        );
+        assert_eq!(
+            intents[0].amounts,
+            SwapAmounts::ExactIn {
+                amount_in: U256::from(1_000_000),
+                min_amount_out: U256::from(40),
+            }
+        );
+
+        // the amount in of ETH swaps is the value sent
+        let tx = Transaction {
+            value: U256::from(500),
+            input: SwapETHForExactTokensCall {
+                amount_out: U256::from(10),
+                path: vec![WMATIC.get_address(), USDC.get_address()],
+                to: sender,
+                deadline: U256::from(1_700_000_000),
+            }
+            .encode()
+            .into(),
+            ..tx
+        };
+        assert_eq!(
+            decode_swaps(&tx)[0].amounts,
+            SwapAmounts::ExactOut {
+                amount_out: U256::from(10),
+                max_amount_in: U256::from(500),
+            }
+        );
+
+        // not a router
+        let tx = Transaction {
+            to: Some(Address::from_low_u64_be(2)),
+            ..tx
+        };
+        assert!(decode_swaps(&tx).is_empty());
+    }
+
+    #[test]
+    fn test_decode_uniswap_v3_multicall() {
+        let recipient = Address::from_low_u64_be(1);
+        let exact_input_single = ISwapRouterCalls::ExactInputSingle(ExactInputSingleCall {
+            params: ExactInputSingleParams {
+                token_in: USDC.get_address(),
+                token_out: WETH.get_address(),
+                fee: 500,
+                recipient: recipient,
+                deadline: U256::from(1_700_000_000),
+                amount_in: U256::from(1_000_000),
+                amount_out_minimum: U256::from(1),
+                sqrt_price_limit_x96: U256::zero(),
+            },
+        });
+        let exact_output = ISwapRouterCalls::ExactOutput(ExactOutputCall {
+            params: ExactOutputParams {
+                // bought token first
+                path: v3_path(
+                    &[WBTC.get_address(), WETH.get_address(), DAI.get_address()],
+                    &[3000, 100],
+                ),
+                recipient: recipient,
+                deadline: U256::from(1_700_000_000),
+                amount_out: U256::from(5),
+                amount_in_maximum: U256::from(100),
+            },
+        });
+        let exact_input = ISwapRouterCalls::ExactInput(ExactInputCall {
+            params: ExactInputParams {
+                path: Bytes::from(vec![1, 2, 3]),
+                recipient: recipient,
+                deadline: U256::from(1_700_000_000),
+                amount_in: U256::from(1),
+                amount_out_minimum: U256::from(1),
+            },
+        });
+        let tx = Transaction {
+            to: Some(UNISWAP_V3.router_address),
+            input: MulticallCall {
+                data: vec![
+                    exact_input_single.encode().into(),
+                    exact_output.encode().into(),
+                    exact_input.encode().into(),
+                ],
+            }
+            .encode()
+            .into(),
+            ..Default::default()
+        };
+
+        // the malformed path is skipped
+        let intents = decode_swaps(&tx);
+        assert_eq!(intents.len(), 2);
+        assert_eq!(intents[0].protocols, vec![Protocol::UniswapV3 { fee: 500 }]);
+        assert_eq!(
+            intents[1].token_path,
+            vec![DAI.get_address(), WETH.get_address(), WBTC.get_address()]
+        );
+        assert_eq!(
+            intents[1].protocols,
+            vec![
+                Protocol::UniswapV3 { fee: 100 },
+                Protocol::UniswapV3 { fee: 3000 }
+            ]
+        );
+        assert_eq!(
+            intents[1].amounts,
+            SwapAmounts::ExactOut {
+                amount_out: U256::from(5),
+                max_amount_in: U256::from(100),
+            }
+        );
+    }
+
+    #[test]
+    fn test_decode_oneinch() {
+        let sender = Address::from_low_u64_be(1);
+        let receiver = Address::from_low_u64_be(2);
+        let tx = Transaction {
+            from: sender,
+            to: Some(*ONEINCH_ROUTER),
+            input: SwapCall {
+                executor: Address::from_low_u64_be(3),
+                desc: SwapDescription {
+                    src_token: USDC.get_address(),
+                    dst_token: WMATIC.get_address(),
+                    src_receiver: Address::from_low_u64_be(3),
+                    dst_receiver: receiver,
+                    amount: U256::from(2_000_000),
+                    min_return_amount: U256::from(1_500),
+                    flags: U256::zero(),
+                },
+                permit: Bytes::default(),
+                data: Bytes::from(vec![1, 2, 3]),
+            }
+            .encode()
+            .into(),
+            ..Default::default()
+        };
+
+        let intents = decode_swaps(&tx);
+        assert_eq!(intents.len(), 1);
+        assert_eq!(intents[0].sender, sender);
+        assert_eq!(intents[0].router, *ONEINCH_ROUTER);
+        assert_eq!(
+            intents[0].token_path,
+            vec![USDC.get_address(), WMATIC.get_address()]
+        );
+        // the executor's route is opaque
+        assert!(intents[0].protocols.is_empty());
+        assert_eq!(
+            intents[0].amounts,
+            SwapAmounts::ExactIn {
+                amount_in: U256::from(2_000_000),
+                min_amount_out: U256::from(1_500),
+            }
+        );
+        assert_eq!(intents[0].recipient, receiver);
+        assert_eq!(intents[0].deadline, None);
+
+        // other aggregator functions are not decoded
+        let tx = Transaction {
+            input: Bytes::from(vec![0x12, 0x34, 0x56, 0x78]),
+            ..tx
+        };
+        assert!(decode_swaps(&tx).is_empty());
+    }
+}
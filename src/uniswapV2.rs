@@ -5,13 +5,14 @@ use std::{
 
 use ethers::{
     abi::Token::{self, *},
-    contract::Contract,
+    contract::{Contract, ContractError},
     core::abi::Abi,
     prelude::{abigen, builders::ContractCall},
     providers::Middleware,
-    types::{Address, U256},
+    types::{Address, BlockNumber, U256},
 };
 use log::{debug, error, warn};
+use serde::{Deserialize, Serialize};
 
 use crate::{
     constants::{
@@ -21,6 +22,9 @@ use crate::{
     utils::multicall::Multicall,
 };
 
+// pairs store their reserves as uint112 and revert on balances that do not fit
+const MAX_RESERVE_BITS: usize = 112;
+
 abigen!(
     IUniswapV2Router02,
     "abis/uniswap/v2/IUniswapV2Router02.json"
@@ -28,7 +32,7 @@ abigen!(
 abigen!(IUniswapV2Factory, "abis/uniswap/v2/IUniswapV2Factory.json");
 abigen!(IUniswapV2Pair, "abis/uniswap/v2/IUniswapV2Pair.json");
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct UniswapV2Pair {
     protocol: UniswapV2,
     token0: ERC20Token,
@@ -68,29 +72,138 @@ impl UniswapV2Pair {
         self.reserve1 = reserve1;
     }
 
+    pub fn get_tokens(&self) -> (ERC20Token, ERC20Token) {
+        (self.token0, self.token1)
+    }
+
     fn get_amount_out(self, amount_in: U256, reserve_in: U256, reserve_out: U256) -> U256 {
         if reserve_in == U256::zero() || reserve_out == U256::zero() {
             return U256::zero();
         }
-        // account for each exchange's fees
-        let (numerator_fee_mul, denominator_fee_mul) = match self.protocol {
-            UniswapV2::MESHSWAP => (10000 - self.fees.as_u32(), 10000_u32),
-            UniswapV2::POLYCAT => (9976_u32, 10000_u32),
-            UniswapV2::APESWAP => (998_u32, 1000_u32),
-            _ => (997_u32, 1000_u32),
-        };
+        let (numerator_fee_mul, denominator_fee_mul) = self.fee_multipliers();
         let amount_in_with_fee: U256 = amount_in.mul(numerator_fee_mul);
         let numerator: U256 = amount_in_with_fee.mul(reserve_out);
         let denominator: U256 = reserve_in.mul(denominator_fee_mul).add(amount_in_with_fee);
         numerator / denominator
     }
 
+    /// `get_amount_out` for amounts taken from untrusted calldata, None if the math overflows
+    fn checked_amount_out(
+        self,
+        amount_in: U256,
+        reserve_in: U256,
+        reserve_out: U256,
+    ) -> Option<U256> {
+        if reserve_in == U256::zero() || reserve_out == U256::zero() {
+            return Some(U256::zero());
+        }
+        let (numerator_fee_mul, denominator_fee_mul) = self.fee_multipliers();
+        let amount_in_with_fee = amount_in.checked_mul(U256::from(numerator_fee_mul))?;
+        let numerator = amount_in_with_fee.checked_mul(reserve_out)?;
+        let denominator = reserve_in
+            .checked_mul(U256::from(denominator_fee_mul))?
+            .checked_add(amount_in_with_fee)?;
+        Some(numerator / denominator)
+    }
+
+    /// Input needed to receive `amount_out`, None if the pool cannot provide it
+    fn get_amount_in(self, amount_out: U256, reserve_in: U256, reserve_out: U256) -> Option<U256> {
+        if reserve_in == U256::zero() || amount_out >= reserve_out {
+            return None;
+        }
+        let (numerator_fee_mul, denominator_fee_mul) = self.fee_multipliers();
+        let numerator = reserve_in
+            .checked_mul(amount_out)?
+            .checked_mul(U256::from(denominator_fee_mul))?;
+        let denominator: U256 = (reserve_out - amount_out).mul(numerator_fee_mul);
+        Some(numerator / denominator + 1)
+    }
+
+    // account for each exchange's fees
+    fn fee_multipliers(&self) -> (u32, u32) {
+        match self.protocol {
+            UniswapV2::MESHSWAP => (10000 - self.fees.as_u32(), 10000_u32),
+            UniswapV2::POLYCAT => (9976_u32, 10000_u32),
+            UniswapV2::APESWAP => (998_u32, 1000_u32),
+            _ => (997_u32, 1000_u32),
+        }
+    }
+
     pub fn get_amounts_out(&self, amount_in: U256, token: ERC20Token) -> U256 {
         if token == self.token0 {
             return self.get_amount_out(amount_in, self.reserve0, self.reserve1);
         }
         return self.get_amount_out(amount_in, self.reserve1, self.reserve0);
     }
+
+    /// Amount of `token_in` needed to buy `amount_out` of the other token
+    pub fn get_amounts_in(&self, amount_out: U256, token_in: ERC20Token) -> Option<U256> {
+        if token_in == self.token0 {
+            return self.get_amount_in(amount_out, self.reserve0, self.reserve1);
+        }
+        self.get_amount_in(amount_out, self.reserve1, self.reserve0)
+    }
+
+    /// Moves the reserves as selling `amount_in` of `token_in` to the pool would, returns
+    /// the amount bought. None if the pair would revert, leaving the reserves alone.
+    pub fn swap(&mut self, amount_in: U256, token_in: ERC20Token) -> Option<U256> {
+        let amount_out = if token_in == self.token0 {
+            self.checked_amount_out(amount_in, self.reserve0, self.reserve1)?
+        } else {
+            self.checked_amount_out(amount_in, self.reserve1, self.reserve0)?
+        };
+        self.settle(amount_in, amount_out, token_in)?;
+        Some(amount_out)
+    }
+
+    /// Moves the reserves by exactly `amount_in` of `token_in` in and `amount_out` of the
+    /// other token out, as a router settling precomputed amounts does. None if the pair
+    /// would revert, i.e. it can't pay out `amount_out` or the new reserve overflows uint112.
+    pub fn settle(
+        &mut self,
+        amount_in: U256,
+        amount_out: U256,
+        token_in: ERC20Token,
+    ) -> Option<()> {
+        let (reserve_in, reserve_out) = if token_in == self.token0 {
+            (&mut self.reserve0, &mut self.reserve1)
+        } else {
+            (&mut self.reserve1, &mut self.reserve0)
+        };
+        let new_reserve_in = reserve_in.checked_add(amount_in)?;
+        let new_reserve_out = reserve_out.checked_sub(amount_out)?;
+        if new_reserve_in.bits() > MAX_RESERVE_BITS {
+            return None;
+        }
+        *reserve_in = new_reserve_in;
+        *reserve_out = new_reserve_out;
+        Some(())
+    }
+}
+
+fn parse_pair_reserves(return_data: Vec<Option<Vec<Token>>>) -> Vec<(U256, U256)> {
+    let mut data: Vec<(U256, U256)> = Vec::new();
+    for token in return_data {
+        match token {
+            Some(tokens) => {
+                let val1 = &tokens[0];
+                let val2 = &tokens[1];
+                let val1 = match val1 {
+                    Uint(a) => *a,
+                    _ => U256::zero(),
+                };
+                let val2 = match val2 {
+                    Uint(a) => *a,
+                    _ => U256::zero(),
+                };
+                data.push((val1, val2));
+            }
+            None => {
+                data.push((U256::zero(), U256::zero()));
+            }
+        }
+    }
+    return data;
 }
 
 pub struct UniswapV2Client<M> {
@@ -284,6 +397,23 @@ impl<M: Middleware> UniswapV2Client<M> {
         &self,
         pair_addresses: &Vec<Address>,
     ) -> Vec<(U256, U256)> {
+        let multicall = self.pair_reserves_multicall(pair_addresses);
+        let return_data: Vec<Option<Vec<Token>>> = multicall.call_raw().await;
+        parse_pair_reserves(return_data)
+    }
+
+    /// Reserves for all pairs read at a single block, so the result is a consistent snapshot
+    pub async fn get_pair_reserves_multicall_at(
+        &self,
+        pair_addresses: &Vec<Address>,
+        block: BlockNumber,
+    ) -> Result<Vec<(U256, U256)>, ContractError<M>> {
+        let multicall = self.pair_reserves_multicall(pair_addresses).block(block);
+        let return_data: Vec<Option<Vec<Token>>> = multicall.try_call_raw().await?;
+        Ok(parse_pair_reserves(return_data))
+    }
+
+    fn pair_reserves_multicall(&self, pair_addresses: &Vec<Address>) -> Multicall<M> {
         let mut multicall = Multicall::new(self.provider.clone());
 
         for pair_address in pair_addresses {
@@ -324,30 +454,7 @@ impl<M: Middleware> UniswapV2Client<M> {
 
             multicall.add_call(call);
         }
-
-        let return_data: Vec<Option<Vec<Token>>> = multicall.call_raw().await;
-        let mut data: Vec<(U256, U256)> = Vec::new();
-        for token in return_data {
-            match token {
-                Some(tokens) => {
-                    let val1 = &tokens[0];
-                    let val2 = &tokens[1];
-                    let val1 = match val1 {
-                        Uint(a) => *a,
-                        _ => U256::zero(),
-                    };
-                    let val2 = match val2 {
-                        Uint(a) => *a,
-                        _ => U256::zero(),
-                    };
-                    data.push((val1, val2));
-                }
-                None => {
-                    data.push((U256::zero(), U256::zero()));
-                }
-            }
-        }
-        return data;
+        multicall
     }
 
     pub async fn get_pair_metadata(&self, pair_address: Address) -> (ERC20Token, ERC20Token, U256) {
@@ -11,6 +11,10 @@ use ethers::{
 use crate::{constants::token::ERC20Token, utils::multicall::Multicall};
 
 abigen!(Quoter, "abis/uniswap/v3/Quoter.json");
+abigen!(
+    IUniswapV3PoolEvents,
+    "abis/uniswap/v3/IUniswapV3PoolEvents.json"
+);
 
 static QUOTE_ABI_STR: &str = r#"[{
     "inputs": [
@@ -100,52 +104,70 @@ impl<M: Middleware + Clone> UniswapV3Client<M> {
         token_out: ERC20Token,
         amount_in: U256,
     ) -> (u32, U256) {
+        self.quote_multicall_batch(token_in, token_out, &vec![amount_in])
+            .await[0]
+    }
+
+    /// Best (fee, amount out) for each of `amounts_in`, all quoted in a single multicall
+    pub async fn quote_multicall_batch(
+        &self,
+        token_in: ERC20Token,
+        token_out: ERC20Token,
+        amounts_in: &Vec<U256>,
+    ) -> Vec<(u32, U256)> {
         let fees: [u32; 2] = [500, 3000];
         let mut multicall = Multicall::new(self.provider.clone());
 
-        for fee in fees {
-            let call = self
-                .quote_contract
-                .method::<_, U256>(
-                    "quoteExactInputSingle",
-                    (
-                        token_in.get_address(),
-                        token_out.get_address(),
-                        fee,
-                        amount_in,
-                        U256::zero(),
-                    ),
-                )
-                .unwrap();
-            multicall.add_call(call);
+        for amount_in in amounts_in {
+            for fee in fees {
+                let call = self
+                    .quote_contract
+                    .method::<_, U256>(
+                        "quoteExactInputSingle",
+                        (
+                            token_in.get_address(),
+                            token_out.get_address(),
+                            fee,
+                            *amount_in,
+                            U256::zero(),
+                        ),
+                    )
+                    .unwrap();
+                multicall.add_call(call);
+            }
         }
 
         let return_data = multicall.call_raw().await;
-        let mut amount_outs: [(u32, U256); 2] = [
-            (fees[0], U256::zero()),
-            (fees[1], U256::zero()),
-            // (fees[2], U256::zero()),
-            // (fees[3], U256::zero()),
-        ];
-
-        for (i, token) in return_data.iter().enumerate() {
-            match token {
-                Some(tokens) => {
-                    let val1 = &tokens[0];
-                    let val1 = match val1 {
-                        Uint(a) => *a,
-                        _ => U256::zero(),
-                    };
-                    amount_outs[i].1 = val1;
+        return_data
+            .chunks(fees.len())
+            .map(|quotes| {
+                let mut amount_outs: [(u32, U256); 2] = [
+                    (fees[0], U256::zero()),
+                    (fees[1], U256::zero()),
+                    // (fees[2], U256::zero()),
+                    // (fees[3], U256::zero()),
+                ];
+
+                for (i, token) in quotes.iter().enumerate() {
+                    match token {
+                        Some(tokens) => {
+                            let val1 = &tokens[0];
+                            let val1 = match val1 {
+                                Uint(a) => *a,
+                                _ => U256::zero(),
+                            };
+                            amount_outs[i].1 = val1;
+                        }
+                        None => {}
+                    }
                 }
-                None => {}
-            }
-        }
 
-        *amount_outs
-            .iter()
-            .max_by(|(_, a), (_, b)| a.cmp(b))
-            .unwrap()
+                *amount_outs
+                    .iter()
+                    .max_by(|(_, a), (_, b)| a.cmp(b))
+                    .unwrap()
+            })
+            .collect()
     }
 }
 
@@ -0,0 +1,59 @@
+use ethers::types::U256;
+use lazy_static::lazy_static;
+
+lazy_static! {
+    static ref ELASTICITY_MULTIPLIER: U256 = U256::from(2);
+    static ref BASE_FEE_CHANGE_DENOMINATOR: U256 = U256::from(8);
+}
+
+// https://github.com/maticnetwork/bor/blob/ad69ccd0ba6aac4a690e6b4778987242609f4845/consensus/misc/eip1559.go#L99
+pub fn compute_next_base_fee(current_base_fee: U256, gas_used: U256, gas_limit: U256) -> U256 {
+    let gas_target = gas_limit.checked_div(*ELASTICITY_MULTIPLIER).unwrap();
+    if gas_used == gas_target {
+        return current_base_fee;
+    } else if gas_used > gas_target {
+        let gas_used_delta = gas_used - gas_target;
+        let x = current_base_fee.checked_mul(gas_used_delta).unwrap();
+        let y = x.checked_div(gas_target).unwrap();
+        let base_fee_delta = U256::max(
+            y.checked_div(*BASE_FEE_CHANGE_DENOMINATOR).unwrap(),
+            U256::one(),
+        );
+        return current_base_fee + base_fee_delta;
+    } else {
+        let gas_used_delta = gas_target - gas_used;
+        let x = current_base_fee.checked_mul(gas_used_delta).unwrap();
+        let y = x.checked_div(gas_target).unwrap();
+        let base_fee_delta = y.checked_div(*BASE_FEE_CHANGE_DENOMINATOR).unwrap();
+
+        return current_base_fee - base_fee_delta;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::types::U256;
+
+    use super::compute_next_base_fee;
+
+    #[test]
+    fn test_compute_next_base_fee() {
+        let base_fee = U256::from(100_000_000_000_u64);
+        let gas_limit = U256::from(30_000_000);
+        // at target, unchanged
+        assert_eq!(
+            compute_next_base_fee(base_fee, U256::from(15_000_000), gas_limit),
+            base_fee
+        );
+        // full block, +12.5%
+        assert_eq!(
+            compute_next_base_fee(base_fee, gas_limit, gas_limit),
+            U256::from(112_500_000_000_u64)
+        );
+        // empty block, -12.5%
+        assert_eq!(
+            compute_next_base_fee(base_fee, U256::zero(), gas_limit),
+            U256::from(87_500_000_000_u64)
+        );
+    }
+}
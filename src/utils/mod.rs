@@ -1,6 +1,7 @@
 pub mod batch;
 pub mod block;
 pub mod block_oracle;
+pub mod gas;
 pub mod matrix;
 pub mod multicall;
 pub mod serialize_structs;
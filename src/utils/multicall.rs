@@ -2,9 +2,10 @@ use std::sync::Arc;
 
 use ethers::{
     abi::{Detokenize, Function, Token},
+    contract::ContractError,
     prelude::{abigen, builders::ContractCall},
     providers::Middleware,
-    types::{Address, Bytes, NameOrAddress, U256},
+    types::{Address, BlockId, Bytes, NameOrAddress, U256},
 };
 
 abigen!(MulticallContract, "abis/Multicall.json");
@@ -23,6 +24,7 @@ pub struct Call {
 pub struct Multicall<M> {
     calls: Vec<Call>,
     contract: MulticallContract<M>,
+    block: Option<BlockId>,
 }
 
 impl<M: Middleware> Multicall<M> {
@@ -34,9 +36,16 @@ impl<M: Middleware> Multicall<M> {
         Self {
             calls: vec![],
             contract,
+            block: None,
         }
     }
 
+    /// Pins every call in the batch to the given block (defaults to latest)
+    pub fn block<T: Into<BlockId>>(mut self, block: T) -> Self {
+        self.block = Some(block.into());
+        self
+    }
+
     pub fn add_call<D: Detokenize>(&mut self, call: ContractCall<M, D>) {
         match (call.tx.to(), call.tx.data()) {
             (Some(NameOrAddress::Address(target)), Some(data)) => {
@@ -66,12 +75,22 @@ impl<M: Middleware> Multicall<M> {
 
         // Construct the ContractCall for `aggregate_3` function to broadcast the transaction
         let contract_call = self.contract.aggregate_3(calls);
-        contract_call
+        match self.block {
+            Some(block) => contract_call.block(block),
+            None => contract_call,
+        }
     }
 
     pub async fn call_raw(&self) -> Vec<Option<Vec<Token>>> {
+        self.try_call_raw().await.unwrap()
+    }
+
+    /// Same as `call_raw`, but surfaces a failed `aggregate3` call instead of panicking
+    pub async fn try_call_raw(
+        &self,
+    ) -> std::result::Result<Vec<Option<Vec<Token>>>, ContractError<M>> {
         let call: ContractCall<M, Vec<Result>> = self.as_aggregate_3();
-        let return_data: Vec<Result> = call.call().await.unwrap();
+        let return_data: Vec<Result> = call.call().await?;
 
         let output = self
             .calls
@@ -92,6 +111,6 @@ impl<M: Middleware> Multicall<M> {
             })
             .collect();
 
-        return output;
+        Ok(output)
     }
 }
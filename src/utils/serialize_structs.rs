@@ -2,6 +2,7 @@ use std::collections::HashMap;
 
 use ethers::types::Address;
 use ethers::types::Bytes;
+use ethers::types::Transaction;
 use ethers::types::H256;
 use ethers::types::U256;
 
@@ -48,18 +49,35 @@ pub struct BlockTraceResult {
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub error: Option<String>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revert_reason: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub calls: Option<Vec<BlockTraceResult>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub logs: Option<Vec<CallLog>>,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Default)]
-#[serde(rename_all = "camelCase")]
-pub struct TxpoolEntry {
-    pub hash: H256,
-    pub gas_price: U256,
+pub struct CallLog {
+    pub address: Address,
+    pub topics: Vec<H256>,
+    pub data: Bytes,
 }
 
+/// Response of `txpool_content`, sender -> decimal nonce -> transaction
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Default)]
 pub struct TxpoolContent {
-    pub pending: HashMap<Address, HashMap<U256, TxpoolEntry>>,
-    pub queued: HashMap<Address, HashMap<U256, TxpoolEntry>>,
+    pub pending: HashMap<Address, HashMap<String, Transaction>>,
+    pub queued: HashMap<Address, HashMap<String, Transaction>>,
+}
+
+/// Params of Alchemy's `alchemy_pendingTransactions` subscription
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct PendingTransactionOptions {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from_address: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to_address: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hashes_only: Option<bool>,
 }
@@ -1,6 +1,6 @@
-use std::collections::LinkedList;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, LinkedList};
 
-use ethers::types::{Transaction, U256};
+use ethers::types::{Address, Transaction, U256};
 
 #[derive(PartialEq, Eq)]
 pub struct TxLinkedList {
@@ -85,6 +85,83 @@ impl Ord for TxLinkedList {
     }
 }
 
+pub fn heapify_mempool(mut mempool_txns: Vec<Transaction>) -> BinaryHeap<TxLinkedList> {
+    mempool_txns.sort_by(|a, b| b.gas_price.unwrap().cmp(&a.gas_price.unwrap()));
+    mempool_txns.sort_by(|a, b| a.nonce.cmp(&b.nonce));
+    // ordered by sender, so that gas price ties come out of the heap the same way every run
+    let mut mapping: BTreeMap<Address, TxLinkedList> = BTreeMap::new();
+    for txn in mempool_txns {
+        let sender_address = txn.from;
+        if !mapping.contains_key(&sender_address) {
+            mapping.insert(sender_address, TxLinkedList::new());
+        }
+        mapping
+            .get_mut(&sender_address)
+            .unwrap()
+            .linked_list
+            .push_back(txn);
+    }
+    let mut heap = BinaryHeap::<TxLinkedList>::new();
+
+    for (_, lls) in mapping {
+        heap.push(lls);
+    }
+
+    return heap;
+}
+
+// https://github.com/maticnetwork/bor/blob/ad69ccd0ba6aac4a690e6b4778987242609f4845/miner/worker.go#L942
+// TODO: need to account for https://github.com/maticnetwork/bor/blob/ad69ccd0ba6aac4a690e6b4778987242609f4845/miner/worker.go#L1020
+// where if gas limit is reached it ignores the other transactions from same sender (may impact our block predict algorithm)
+pub fn filter_mempool(
+    mempool_txns: Vec<Transaction>,
+    mut account_nonces: HashMap<Address, U256>,
+    next_base_fee: U256,
+) -> (Vec<Transaction>, Vec<Transaction>) {
+    let mut heap = heapify_mempool(mempool_txns);
+    let mut rejected_txns: Vec<Transaction> = Vec::new();
+    let mut final_txns: Vec<Transaction> = Vec::new();
+    while heap.len() != 0 {
+        let mut ll = heap.pop().unwrap();
+        let txn = ll.linked_list.pop_front().unwrap();
+        let current_account_nonce = account_nonces.get(&txn.from).unwrap();
+        if txn.nonce < *current_account_nonce {
+            rejected_txns.push(txn);
+            if ll.linked_list.front().is_some() {
+                heap.push(ll);
+            }
+            continue;
+        } else if txn.nonce > *current_account_nonce {
+            continue;
+        } else {
+            account_nonces.insert(txn.from, *current_account_nonce + 1);
+        }
+
+        let gas_fee = match txn.max_fee_per_gas {
+            Some(val) => val,
+            None => txn.gas_price.unwrap(),
+        };
+
+        if gas_fee < next_base_fee || gas_fee < U256::from(22916) {
+            continue;
+        }
+
+        // https://github.com/maticnetwork/bor/blob/ad69ccd0ba6aac4a690e6b4778987242609f4845/core/types/transaction.go#L426
+        if let Some(max_priority_gas_fee) = txn.max_priority_fee_per_gas {
+            let tip = gas_fee - next_base_fee;
+            if max_priority_gas_fee < tip {
+                continue;
+            }
+        }
+
+        if ll.linked_list.front().is_some() {
+            heap.push(ll);
+        }
+        final_txns.push(txn);
+    }
+    (final_txns, rejected_txns)
+}
+
 #[cfg(test)]
 mod test {
     use std::{
@@ -1,31 +1,138 @@
 use ethers::{
-    abi::{parse_abi, Address},
-    prelude::BaseContract,
+    abi::Address,
+    contract::ContractError,
     providers::{Middleware, Provider, PubsubClient},
-    types::U256,
+    types::{Block, BlockNumber, H256, I256, U256},
 };
-use futures_util::StreamExt;
-use log::debug;
-use std::{cmp::Ordering, collections::HashMap, sync::Arc};
-use tokio::sync::RwLock;
+use futures_util::{Stream, StreamExt};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    fmt,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{self, AtomicBool, AtomicU64},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+use tokio::sync::{Mutex, RwLock};
 
 use crate::{
     constants::{
         protocol::{UniswapV2, UNISWAPV2_PROTOCOLS},
         token::ERC20Token,
     },
-    event_monitor::get_pair_sync_stream,
-    uniswapV2::{UniswapV2Client, UniswapV2Pair},
+    event_monitor::{pair_sync_subscription, EventLog},
+    tx_pool::recorder::Recorder,
+    uniswapV2::{SyncFilter, UniswapV2Client, UniswapV2Pair},
     uniswapV3::UniswapV3Client,
     utils::matrix::Matrix3D,
 };
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Protocol {
     UniswapV2(UniswapV2),
     UniswapV3 { fee: u32 },
 }
 
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Protocol::UniswapV2(protocol) => write!(f, "{}", protocol.get_name()),
+            Protocol::UniswapV3 { fee } => write!(f, "UniswapV3 {fee}"),
+        }
+    }
+}
+
+// backoff between resubscription attempts when the node connection is down
+const RESUBSCRIBE_BACKOFF_MIN: Duration = Duration::from_millis(250);
+const RESUBSCRIBE_BACKOFF_MAX: Duration = Duration::from_secs(8);
+
+/// Reasons the world state stopped tracking the chain and has to resync
+#[derive(Debug, Error)]
+pub enum StreamGap {
+    #[error("subscription stream closed")]
+    StreamClosed,
+    #[error("missed blocks, last seen #{last_seen} but received #{received}")]
+    MissedBlocks { last_seen: u64, received: u64 },
+    #[error("sync log at block #{0} was removed by a reorg")]
+    Reorg(u64),
+}
+
+/// On-disk copy of the pool state, used to warm start `WorldState` without re-running
+/// the address/metadata multicalls
+#[derive(Serialize, Deserialize)]
+pub struct WorldSnapshot {
+    pub block_number: u64,
+    pub tokens: Vec<ERC20Token>,
+    pub protocols: Vec<UniswapV2>,
+    pub pairs: Vec<(Address, UniswapV2Pair)>,
+}
+
+impl WorldSnapshot {
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = File::open(path)?;
+        Ok(serde_json::from_reader(BufReader::new(file))?)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        // write to a temporary file first so a crash mid-write never leaves a torn snapshot
+        let path = path.as_ref();
+        let tmp_path = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.flush()?;
+        fs::rename(tmp_path, path)
+    }
+}
+
+/// Every (protocol, token0, token1) market in the order the pair vectors are laid out
+fn market_keys(
+    uniswapV2_list: &Vec<UniswapV2>,
+    tokens_list: &Vec<ERC20Token>,
+) -> Vec<(UniswapV2, ERC20Token, ERC20Token)> {
+    let mut keys = Vec::new();
+    for protocol in uniswapV2_list {
+        for i in 0..tokens_list.len() {
+            for j in (i + 1)..tokens_list.len() {
+                keys.push((*protocol, tokens_list[i], tokens_list[j]));
+            }
+        }
+    }
+    keys
+}
+
+/// One row of a route's profit curve
+#[derive(Debug, Clone)]
+pub struct RouteQuote {
+    pub amount_in: U256,
+    pub amount_out: U256,
+    /// amount out minus amount in, in units of the route's first token
+    pub profit: I256,
+    /// protocol chosen for each hop at this input size
+    pub protocols: Vec<Protocol>,
+}
+
+/// Index of a uniswap v2 market in the pair matrix
+pub(crate) type MarketIndex = (usize, usize, usize);
+
+#[inline(always)]
+pub(crate) fn market_index(
+    protocol: UniswapV2,
+    token0: ERC20Token,
+    token1: ERC20Token,
+) -> MarketIndex {
+    // stored in address order, which a pair's token0/token1 don't always follow (Meshswap)
+    let (token0, token1) = order_tokens(token0, token1);
+    (protocol as usize, token0 as usize, token1 as usize)
+}
+
 #[inline(always)]
 fn order_tokens(token0: ERC20Token, token1: ERC20Token) -> (ERC20Token, ERC20Token) {
     match token0.get_address().cmp(&token1.get_address()) {
@@ -40,8 +147,17 @@ pub struct WorldState<M, P> {
     uniswapV2_markets: RwLock<Matrix3D<UniswapV2Pair>>,
     uniswapV2_pair_lookup: HashMap<Address, (UniswapV2, ERC20Token, ERC20Token)>,
     pub uniswapV2_pair_addresses: Vec<Address>,
+    uniswapV2_client: UniswapV2Client<M>,
     uniswapV3_client: UniswapV3Client<M>,
     pub gas_price: RwLock<U256>,
+    tokens_list: Vec<ERC20Token>,
+    uniswapV2_list: Vec<UniswapV2>,
+    last_block: AtomicU64,
+    healthy: AtomicBool,
+    /// block to backfill `Sync` logs from instead of the first resync, set when restored
+    /// from a snapshot
+    backfill_from: Mutex<Option<u64>>,
+    recorder: Option<Arc<Recorder>>,
 }
 
 impl<M: Middleware + Clone, P: PubsubClient> WorldState<M, P> {
@@ -58,19 +174,9 @@ impl<M: Middleware + Clone, P: PubsubClient> WorldState<M, P> {
         tokens_list.sort_by(|x, y| x.get_address().cmp(&y.get_address()));
 
         // grab all pair addresses across all pairs, protocols
-        let mut pair_address_multicall_input: Vec<(UniswapV2, ERC20Token, ERC20Token)> = Vec::new();
-        for protocol in &uniswapV2_list {
-            for i in 0..tokens_list.len() {
-                let token0 = tokens_list[i];
-                for j in i + 1..tokens_list.len() {
-                    let token1 = tokens_list[j];
-                    pair_address_multicall_input.push((*protocol, token0, token1));
-                }
-            }
-        }
-
+        let market_keys = market_keys(&uniswapV2_list, &tokens_list);
         let pair_addresses = uniswapV2_client
-            .get_pair_address_multicall(pair_address_multicall_input)
+            .get_pair_address_multicall(market_keys.to_vec())
             .await;
 
         let pair_metadatas = uniswapV2_client
@@ -82,6 +188,81 @@ impl<M: Middleware + Clone, P: PubsubClient> WorldState<M, P> {
             .get_pair_reserves_multicall(&pair_addresses)
             .await;
 
+        let mut pairs: Vec<(Address, UniswapV2Pair)> = Vec::with_capacity(market_keys.len());
+        for (i, (protocol, _, _)) in market_keys.iter().enumerate() {
+            let (token0, token1, fees) = pair_metadatas[i];
+            let (reserve0, reserve1) = pair_reserves[i];
+            let mut pair = UniswapV2Pair::default();
+            pair.update_metadata(*protocol, token0, token1, fees);
+            pair.update_reserves(reserve0, reserve1);
+            pairs.push((pair_addresses[i], pair));
+        }
+
+        Self::from_pairs(
+            provider,
+            stream_provider,
+            tokens_list,
+            uniswapV2_list,
+            pairs,
+        )
+        .await
+    }
+
+    /// Restores from the snapshot at `path`. `stream_data` then backfills the `Sync` logs
+    /// emitted since it was taken, instead of refetching every pair's reserves. Falls back to
+    /// a full `init` if the snapshot is missing or was taken for a different set of
+    /// tokens/protocols.
+    pub async fn init_from_snapshot(
+        provider: Arc<M>,
+        stream_provider: Provider<P>,
+        mut tokens_list: Vec<ERC20Token>,
+        uniswapV2_list: Vec<UniswapV2>,
+        path: impl AsRef<Path>,
+    ) -> Self {
+        tokens_list.sort_by(|x, y| x.get_address().cmp(&y.get_address()));
+
+        let snapshot = match WorldSnapshot::load(&path) {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                warn!("Unable to load world snapshot, running full init: {:?}", e);
+                return Self::init(provider, stream_provider, tokens_list, uniswapV2_list).await;
+            }
+        };
+        if snapshot.tokens != tokens_list
+            || snapshot.protocols != uniswapV2_list
+            || snapshot.pairs.len() != market_keys(&uniswapV2_list, &tokens_list).len()
+        {
+            warn!("World snapshot does not match requested markets, running full init");
+            return Self::init(provider, stream_provider, tokens_list, uniswapV2_list).await;
+        }
+
+        let mut ws = Self::from_pairs(
+            provider,
+            stream_provider,
+            tokens_list,
+            uniswapV2_list,
+            snapshot.pairs,
+        )
+        .await;
+        // the snapshot block comes from block headers, which can arrive before that block's
+        // `Sync` logs, so it is backfilled again. Logs carry absolute reserves, so reapplying
+        // ones the snapshot already has is harmless.
+        *ws.backfill_from.get_mut() = Some(snapshot.block_number);
+        ws.set_last_block(snapshot.block_number);
+        info!(
+            "Restored world snapshot from block #{}",
+            snapshot.block_number
+        );
+        ws
+    }
+
+    async fn from_pairs(
+        provider: Arc<M>,
+        stream_provider: Provider<P>,
+        tokens_list: Vec<ERC20Token>,
+        uniswapV2_list: Vec<UniswapV2>,
+        pairs: Vec<(Address, UniswapV2Pair)>,
+    ) -> Self {
         // populate UniswapV2Pair matrix and reverse lookup table
         let mut matrix = Matrix3D::new(
             uniswapV2_list.len(),
@@ -92,24 +273,16 @@ impl<M: Middleware + Clone, P: PubsubClient> WorldState<M, P> {
 
         // create pair addresses to information mapping
         let mut pair_lookup: HashMap<Address, (UniswapV2, ERC20Token, ERC20Token)> = HashMap::new();
+        let mut pair_addresses: Vec<Address> = Vec::with_capacity(pairs.len());
 
-        let mut curr_idx = 0;
-        for protocol in &uniswapV2_list {
-            for i in 0..tokens_list.len() {
-                let token0_ord = tokens_list[i];
-                for j in (i + 1)..tokens_list.len() {
-                    let token1_ord = tokens_list[j];
-                    let reserve0 = pair_reserves[curr_idx].0;
-                    let reserve1 = pair_reserves[curr_idx].1;
-                    let (token0, token1, fees) = pair_metadatas[curr_idx];
-                    matrix[(*protocol as usize, token0_ord as usize, token1_ord as usize)]
-                        .update_metadata(*protocol, token0, token1, fees);
-                    matrix[(*protocol as usize, token0_ord as usize, token1_ord as usize)]
-                        .update_reserves(reserve0, reserve1);
-                    pair_lookup.insert(pair_addresses[curr_idx], (*protocol, token0, token1));
-                    curr_idx += 1;
-                }
-            }
+        let market_keys = market_keys(&uniswapV2_list, &tokens_list);
+        for ((protocol, token0_ord, token1_ord), (pair_address, pair)) in
+            market_keys.into_iter().zip(pairs)
+        {
+            let (token0, token1) = pair.get_tokens();
+            matrix[(protocol as usize, token0_ord as usize, token1_ord as usize)] = pair;
+            pair_lookup.insert(pair_address, (protocol, token0, token1));
+            pair_addresses.push(pair_address);
         }
 
         WorldState {
@@ -118,42 +291,284 @@ impl<M: Middleware + Clone, P: PubsubClient> WorldState<M, P> {
             uniswapV2_markets: RwLock::new(matrix),
             uniswapV2_pair_lookup: pair_lookup,
             uniswapV2_pair_addresses: pair_addresses,
+            uniswapV2_client: UniswapV2Client::new(provider.clone()),
             uniswapV3_client: UniswapV3Client::new(provider.clone()),
             gas_price: RwLock::new(provider.get_gas_price().await.unwrap()),
+            tokens_list: tokens_list,
+            uniswapV2_list: uniswapV2_list,
+            last_block: AtomicU64::new(0),
+            healthy: AtomicBool::new(false),
+            backfill_from: Mutex::new(None),
+            recorder: None,
+        }
+    }
+
+    /// Records every reserve update, alongside a `TxPool` sharing the same recorder
+    pub fn with_recorder(mut self, recorder: Arc<Recorder>) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// Copies the current markets, tagged with the block they are up to date with
+    pub async fn snapshot(&self) -> WorldSnapshot {
+        let markets = self.uniswapV2_markets.read().await;
+        let pairs = market_keys(&self.uniswapV2_list, &self.tokens_list)
+            .into_iter()
+            .zip(&self.uniswapV2_pair_addresses)
+            .map(|((protocol, token0_ord, token1_ord), pair_address)| {
+                (
+                    *pair_address,
+                    markets[(protocol as usize, token0_ord as usize, token1_ord as usize)],
+                )
+            })
+            .collect();
+
+        WorldSnapshot {
+            block_number: self.last_block(),
+            tokens: self.tokens_list.to_vec(),
+            protocols: self.uniswapV2_list.to_vec(),
+            pairs: pairs,
+        }
+    }
+
+    /// Writes a snapshot to `path`. Skipped while unhealthy, since reserves may be stale.
+    pub async fn save_snapshot(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        if !self.is_healthy() {
+            debug!("World state unhealthy, not writing snapshot");
+            return Ok(());
+        }
+        self.snapshot().await.save(path)
+    }
+
+    /// Periodically writes snapshots to `path`, meant to be spawned alongside `stream_data`
+    pub async fn persist_snapshots(self: Arc<Self>, path: PathBuf, period: Duration) {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.save_snapshot(&path).await {
+                error!("Failed to write world snapshot to {:?}: {:?}", path, e);
+            }
         }
     }
 
+    /// Brings the reserves up to the latest block, returns that block. Backfills `Sync` logs
+    /// the first time after a snapshot restore, otherwise refetches every pair's reserves.
+    async fn catch_up(&self) -> Result<u64, ContractError<M>> {
+        if let Some(from_block) = self.backfill_from.lock().await.take() {
+            match self.backfill_sync_logs(from_block).await {
+                Ok(block_number) => {
+                    info!(
+                        "Backfilled sync logs from block #{} to #{}",
+                        from_block, block_number
+                    );
+                    return Ok(block_number);
+                }
+                Err(e) => warn!("Failed to backfill sync logs, resyncing: {:?}", e),
+            }
+        }
+        self.resync().await
+    }
+
+    /// Applies all `Sync` logs from `from_block` up to the latest block, returns the latest block
+    async fn backfill_sync_logs(&self, from_block: u64) -> Result<u64, M::Error> {
+        let latest_block = self.provider.get_block_number().await?.as_u64();
+        if from_block <= latest_block {
+            let sync_logs = pair_sync_subscription(self.uniswapV2_pair_addresses.to_vec())
+                .backfill(self.provider.as_ref(), from_block, latest_block)
+                .await?;
+            for sync_log in sync_logs {
+                self.apply_sync_log(sync_log).await;
+            }
+        }
+        self.last_block
+            .store(latest_block, atomic::Ordering::Release);
+        Ok(latest_block)
+    }
+
+    /// False while the streams are down or reserves are being resynced, prices should not be trusted
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(atomic::Ordering::Acquire)
+    }
+
+    /// Latest block number the reserves are known to be up to date with
+    pub fn last_block(&self) -> u64 {
+        self.last_block.load(atomic::Ordering::Acquire)
+    }
+
+    /// Follows pair `Sync` logs and new heads. If a stream closes or a block is skipped,
+    /// resubscribes and catches up before reporting healthy again: the first time after a
+    /// snapshot restore by backfilling `Sync` logs from the snapshot block, otherwise by
+    /// refetching every pair's reserves at the latest block.
     pub async fn stream_data(self: Arc<Self>)
     where
         <M as Middleware>::Provider: PubsubClient,
     {
-        let mut pair_stream = get_pair_sync_stream(
-            &self.stream_provider,
-            self.uniswapV2_pair_addresses.to_vec(),
+        let pair_subscription = pair_sync_subscription(self.uniswapV2_pair_addresses.to_vec());
+        let mut backoff = RESUBSCRIBE_BACKOFF_MIN;
+        loop {
+            let streams = tokio::try_join!(
+                pair_subscription.subscribe(&self.stream_provider),
+                self.stream_provider.subscribe_blocks()
+            );
+            let (mut pair_stream, mut block_stream) = match streams {
+                Ok(streams) => streams,
+                Err(e) => {
+                    error!("Failed to subscribe to world state streams: {:?}", e);
+                    tokio::time::sleep(backoff).await;
+                    backoff = Duration::min(backoff * 2, RESUBSCRIBE_BACKOFF_MAX);
+                    continue;
+                }
+            };
+
+            // streams are live, so anything emitted after the synced block will be buffered in them
+            let synced_block = match self.catch_up().await {
+                Ok(block_number) => block_number,
+                Err(e) => {
+                    error!("Failed to resync pair reserves: {:?}", e);
+                    tokio::time::sleep(backoff).await;
+                    backoff = Duration::min(backoff * 2, RESUBSCRIBE_BACKOFF_MAX);
+                    continue;
+                }
+            };
+            backoff = RESUBSCRIBE_BACKOFF_MIN;
+            self.healthy.store(true, atomic::Ordering::Release);
+            info!("World state synced at block #{}", synced_block);
+
+            let gap = self
+                .follow_streams(&mut pair_stream, &mut block_stream, synced_block)
+                .await;
+            self.healthy.store(false, atomic::Ordering::Release);
+            warn!("World state out of sync ({}), resyncing", gap);
+        }
+    }
+
+    async fn follow_streams<L, B>(
+        &self,
+        pair_stream: &mut L,
+        block_stream: &mut B,
+        synced_block: u64,
+    ) -> StreamGap
+    where
+        L: Stream<Item = EventLog<SyncFilter>> + Unpin,
+        B: Stream<Item = Block<H256>> + Unpin,
+    {
+        let mut last_seen = synced_block;
+        loop {
+            tokio::select! {
+                log = pair_stream.next() => {
+                    let log = match log {
+                        Some(log) => log,
+                        None => return StreamGap::StreamClosed,
+                    };
+                    let block_number = log.meta.block_number.as_u64();
+                    if log.removed {
+                        return StreamGap::Reorg(block_number);
+                    }
+                    // already reflected in the resynced reserves
+                    if block_number <= synced_block {
+                        continue;
+                    }
+                    self.apply_sync_log(log).await;
+                }
+                block = block_stream.next() => {
+                    let block = match block {
+                        Some(block) => block,
+                        None => return StreamGap::StreamClosed,
+                    };
+                    let block_number = block.number.unwrap_or_default().as_u64();
+                    if block_number > last_seen + 1 {
+                        return StreamGap::MissedBlocks {
+                            last_seen: last_seen,
+                            received: block_number,
+                        };
+                    }
+                    last_seen = u64::max(last_seen, block_number);
+                    self.last_block.store(last_seen, atomic::Ordering::Release);
+                }
+            }
+        }
+    }
+
+    async fn apply_sync_log(&self, log: EventLog<SyncFilter>) {
+        let (protocol, token0, token1) = self.uniswapV2_pair_lookup[&log.meta.address];
+        self.update_pair_reserves(
+            log.meta.address,
+            log.event.reserve_0.into(),
+            log.event.reserve_1.into(),
         )
         .await;
-        let pair_sync_abi = BaseContract::from(
-            parse_abi(&["event Sync(uint112 reserve0, uint112 reserve1)"]).unwrap(),
+        debug!(
+            "Block#:{}, Pair reserves updated on {:?} protocol, pair {}-{}",
+            log.meta.block_number,
+            protocol.get_name(),
+            token0.get_symbol(),
+            token1.get_symbol()
         );
+    }
 
-        while let Some(log) = pair_stream.next().await {
-            let (reserve0, reserve1): (U256, U256) = pair_sync_abi
-                .decode_event("Sync", log.topics, log.data)
-                .unwrap();
-            let (protocol, token0, token1) = self.uniswapV2_pair_lookup[&log.address];
-            // need to sort tokens here (for proper indexing, since token0<=token1 not guarenteed for Meshswap)
-            let (token0, token1) = order_tokens(token0, token1);
-            self.uniswapV2_markets.write().await
-                [(protocol as usize, token0 as usize, token1 as usize)]
-                .update_reserves(reserve0, reserve1);
-            debug!(
-                "Block#:{}, Pair reserves updated on {:?} protocol, pair {}-{}",
-                log.block_number.unwrap(),
-                protocol.get_name(),
-                token0.get_symbol(),
-                token1.get_symbol()
-            );
+    /// Sets the reserves of the pair at `pair_address`, ignoring untracked pairs
+    pub(crate) async fn update_pair_reserves(
+        &self,
+        pair_address: Address,
+        reserve0: U256,
+        reserve1: U256,
+    ) {
+        let (protocol, token0, token1) = match self.uniswapV2_pair_lookup.get(&pair_address) {
+            Some(market) => *market,
+            None => return,
+        };
+        if let Some(recorder) = &self.recorder {
+            recorder.record_sync(pair_address, reserve0, reserve1, Instant::now());
         }
+        self.uniswapV2_markets.write().await[market_index(protocol, token0, token1)]
+            .update_reserves(reserve0, reserve1);
+    }
+
+    pub(crate) fn set_last_block(&self, block_number: u64) {
+        self.last_block
+            .store(block_number, atomic::Ordering::Release);
+    }
+
+    /// Refetches every pair's reserves pinned to the latest block, returns that block number
+    async fn resync(&self) -> Result<u64, ContractError<M>> {
+        let block_number = self
+            .provider
+            .get_block_number()
+            .await
+            .map_err(ContractError::MiddlewareError)?;
+        let pair_reserves = self
+            .uniswapV2_client
+            .get_pair_reserves_multicall_at(
+                &self.uniswapV2_pair_addresses,
+                BlockNumber::Number(block_number),
+            )
+            .await?;
+
+        let mut markets = self.uniswapV2_markets.write().await;
+        for (pair_address, (reserve0, reserve1)) in
+            self.uniswapV2_pair_addresses.iter().zip(pair_reserves)
+        {
+            let (protocol, token0, token1) = self.uniswapV2_pair_lookup[pair_address];
+            if let Some(recorder) = &self.recorder {
+                recorder.record_sync(*pair_address, reserve0, reserve1, Instant::now());
+            }
+            markets[market_index(protocol, token0, token1)].update_reserves(reserve0, reserve1);
+        }
+        self.last_block
+            .store(block_number.as_u64(), atomic::Ordering::Release);
+        Ok(block_number.as_u64())
+    }
+
+    /// Tracked token at `address`, None for tokens outside the tokens list
+    pub(crate) fn token(&self, address: Address) -> Option<ERC20Token> {
+        self.tokens_list
+            .iter()
+            .find(|token| token.get_address() == address)
+            .copied()
+    }
+
+    pub(crate) async fn uniswapV2_pair(&self, index: MarketIndex) -> UniswapV2Pair {
+        self.uniswapV2_markets.read().await[index]
     }
 
     pub async fn compute_best_route(
@@ -161,70 +576,174 @@ impl<M: Middleware + Clone, P: PubsubClient> WorldState<M, P> {
         token_path: Vec<ERC20Token>,
         amount_in: U256,
     ) -> (U256, Vec<Protocol>) {
-        let mut protocols: Vec<Protocol> = Vec::with_capacity(token_path.len() - 1);
-
-        let mut token_in = token_path[0];
-        let mut token_out;
-        let mut current_amt = amount_in;
-        for i in 1..token_path.len() {
-            token_out = token_path[i];
-            let (best_amount_out_v3, best_pool_fee) =
-                self.best_uniswapV3(token_in, token_out, current_amt).await;
-
-            let (best_amount_out, uniswapV2_protocol) =
-                self.best_uniswapV2(token_in, token_out, current_amt).await;
-
-            if best_amount_out > best_amount_out_v3 {
-                current_amt = best_amount_out;
-                protocols.push(Protocol::UniswapV2(uniswapV2_protocol));
-            } else {
-                current_amt = best_amount_out_v3;
-                protocols.push(Protocol::UniswapV3 { fee: best_pool_fee });
-            }
-            token_in = token_out;
-        }
-        (current_amt, protocols)
+        let quote = self
+            .sweep_route(token_path, vec![amount_in])
+            .await
+            .remove(0);
+        (quote.amount_out, quote.protocols)
+    }
+
+    /// Evaluates `token_path` at every size in `amounts_in` against one snapshot of the
+    /// uniswap v2 reserves, quoting uniswap v3 with one multicall per hop for all sizes.
+    /// Returns a row per input size, in the same order.
+    pub async fn sweep_route(
+        self: Arc<Self>,
+        token_path: Vec<ERC20Token>,
+        amounts_in: Vec<U256>,
+    ) -> Vec<RouteQuote> {
+        self.sweep_route_with(&HashMap::new(), token_path, amounts_in)
+            .await
     }
 
-    async fn best_uniswapV2(
+    /// `sweep_route`, with the pairs in `overrides` quoted in place of the tracked ones
+    pub(crate) async fn sweep_route_with(
         &self,
-        token_in: ERC20Token,
-        token_out: ERC20Token,
-        amount_in: U256,
-    ) -> (U256, UniswapV2) {
-        let (token0, token1) = order_tokens(token_in, token_out);
-
-        let mut best_protocol = UNISWAPV2_PROTOCOLS[0];
-        let mut best_amount_out = self.uniswapV2_markets.read().await
-            [(best_protocol as usize, token0 as usize, token1 as usize)]
-            .get_amounts_out(amount_in, token_in);
-
-        for i in 1..UNISWAPV2_PROTOCOLS.len() {
-            let protocol = UNISWAPV2_PROTOCOLS[i];
-            let amount_out = self.uniswapV2_markets.read().await
-                [(protocol as usize, token0 as usize, token1 as usize)]
-                .get_amounts_out(amount_in, token_in);
-
-            if amount_out > best_amount_out {
-                best_protocol = protocol;
-                best_amount_out = amount_out;
+        overrides: &HashMap<MarketIndex, UniswapV2Pair>,
+        token_path: Vec<ERC20Token>,
+        amounts_in: Vec<U256>,
+    ) -> Vec<RouteQuote> {
+        // copy every hop's pairs under a single lock so all sizes see the same reserves
+        let hop_pairs: Vec<Vec<UniswapV2Pair>> = {
+            let markets = self.uniswapV2_markets.read().await;
+            token_path
+                .windows(2)
+                .map(|hop| {
+                    UNISWAPV2_PROTOCOLS
+                        .iter()
+                        .map(|protocol| {
+                            let index = market_index(*protocol, hop[0], hop[1]);
+                            overrides.get(&index).copied().unwrap_or(markets[index])
+                        })
+                        .collect()
+                })
+                .collect()
+        };
+
+        let mut current_amts = amounts_in.to_vec();
+        let mut protocols: Vec<Vec<Protocol>> =
+            vec![Vec::with_capacity(token_path.len() - 1); amounts_in.len()];
+        for (i, pairs) in hop_pairs.iter().enumerate() {
+            let (token_in, token_out) = (token_path[i], token_path[i + 1]);
+            let v3_quotes = self
+                .uniswapV3_client
+                .quote_multicall_batch(token_in, token_out, &current_amts)
+                .await;
+
+            for (j, (best_pool_fee, best_amount_out_v3)) in v3_quotes.into_iter().enumerate() {
+                let (best_amount_out, uniswapV2_protocol) =
+                    best_uniswapV2(pairs, token_in, current_amts[j]);
+
+                if best_amount_out > best_amount_out_v3 {
+                    current_amts[j] = best_amount_out;
+                    protocols[j].push(Protocol::UniswapV2(uniswapV2_protocol));
+                } else {
+                    current_amts[j] = best_amount_out_v3;
+                    protocols[j].push(Protocol::UniswapV3 { fee: best_pool_fee });
+                }
             }
         }
 
-        (best_amount_out, best_protocol)
+        amounts_in
+            .into_iter()
+            .zip(current_amts)
+            .zip(protocols)
+            .map(|((amount_in, amount_out), protocols)| RouteQuote {
+                amount_in: amount_in,
+                amount_out: amount_out,
+                profit: I256::from_raw(amount_out) - I256::from_raw(amount_in),
+                protocols: protocols,
+            })
+            .collect()
     }
+}
 
-    async fn best_uniswapV3(
-        &self,
-        token_in: ERC20Token,
-        token_out: ERC20Token,
-        amount_in: U256,
-    ) -> (U256, u32) {
-        let return_data = self
-            .uniswapV3_client
-            .quote_multicall(token_in, token_out, amount_in)
-            .await;
+/// Best uniswap v2 fork for a hop, `pairs` indexed in `UNISWAPV2_PROTOCOLS` order
+fn best_uniswapV2(
+    pairs: &Vec<UniswapV2Pair>,
+    token_in: ERC20Token,
+    amount_in: U256,
+) -> (U256, UniswapV2) {
+    let mut best_protocol = UNISWAPV2_PROTOCOLS[0];
+    let mut best_amount_out = pairs[0].get_amounts_out(amount_in, token_in);
+
+    for i in 1..UNISWAPV2_PROTOCOLS.len() {
+        let amount_out = pairs[i].get_amounts_out(amount_in, token_in);
+        if amount_out > best_amount_out {
+            best_protocol = UNISWAPV2_PROTOCOLS[i];
+            best_amount_out = amount_out;
+        }
+    }
+
+    (best_amount_out, best_protocol)
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::types::{Address, U256};
+
+    use super::{best_uniswapV2, WorldSnapshot};
+    use crate::{
+        constants::{
+            protocol::{UniswapV2::*, UNISWAPV2_PROTOCOLS},
+            token::ERC20Token::*,
+        },
+        uniswapV2::UniswapV2Pair,
+    };
+
+    #[test]
+    fn test_best_uniswapV2() {
+        let mut pairs = vec![UniswapV2Pair::default(); UNISWAPV2_PROTOCOLS.len()];
+        for (i, protocol) in UNISWAPV2_PROTOCOLS.iter().enumerate() {
+            pairs[i].update_metadata(*protocol, USDC, WETH, U256::zero());
+            pairs[i].update_reserves(U256::from(1_000_000_000), U256::from(1_000_000_000));
+        }
+        // deeper WETH side on apeswap, enough to beat meshswap charging no fee at zero `fees`
+        pairs[APESWAP as usize]
+            .update_reserves(U256::from(1_000_000_000), U256::from(1_100_000_000));
+
+        let (amount_out, protocol) = best_uniswapV2(&pairs, USDC, U256::from(1_000_000));
+        assert_eq!(protocol, APESWAP);
+        assert_eq!(
+            amount_out,
+            pairs[APESWAP as usize].get_amounts_out(U256::from(1_000_000), USDC)
+        );
 
-        (return_data.1, return_data.0)
+        // empty pools quote nothing, first protocol is kept on ties
+        let empty = vec![UniswapV2Pair::default(); UNISWAPV2_PROTOCOLS.len()];
+        assert_eq!(
+            best_uniswapV2(&empty, USDC, U256::from(1_000_000)),
+            (U256::zero(), UNISWAPV2_PROTOCOLS[0])
+        );
+    }
+
+    #[test]
+    fn test_snapshot_roundtrip() {
+        let mut pair = UniswapV2Pair::default();
+        pair.update_metadata(QUICKSWAP, USDC, WETH, U256::zero());
+        pair.update_reserves(U256::from(1_000_000), U256::from(2_000_000));
+        let snapshot = WorldSnapshot {
+            block_number: 38_000_000,
+            tokens: vec![USDC, WETH],
+            protocols: vec![SUSHISWAP, QUICKSWAP],
+            pairs: vec![
+                (Address::zero(), UniswapV2Pair::default()),
+                (Address::repeat_byte(1), pair),
+            ],
+        };
+
+        let path = std::env::temp_dir().join(format!("tsuki_snapshot_{}.json", std::process::id()));
+        snapshot.save(&path).unwrap();
+        let loaded = WorldSnapshot::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.block_number, snapshot.block_number);
+        assert_eq!(loaded.tokens, snapshot.tokens);
+        assert_eq!(loaded.protocols, snapshot.protocols);
+        assert_eq!(loaded.pairs[1].0, Address::repeat_byte(1));
+        assert_eq!(loaded.pairs[1].1.get_tokens(), (USDC, WETH));
+        assert_eq!(
+            loaded.pairs[1].1.get_amounts_out(U256::from(1000), USDC),
+            pair.get_amounts_out(U256::from(1000), USDC)
+        );
     }
 }